@@ -0,0 +1,82 @@
+use std::fmt::Debug;
+
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
+
+use super::single::SingleInstruction;
+
+/// Eight consecutive outputs of a bit reversal permutation. Output
+/// lane `i` comes from `first_in + reverse(i) * stride`, where
+/// `reverse` reverses the three bits of the lane, so each window of
+/// the output is a single gather with a constant index vector.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BitReverseInstruction {
+    pub first_in: u32,
+    pub stride: u32,
+    pub first_out: u32,
+}
+
+impl BitReverseInstruction {
+    pub const fn new(first_in: u32, stride: u32, first_out: u32) -> Self {
+        Self {
+            first_in,
+            stride,
+            first_out,
+        }
+    }
+
+    /// The offsets from `first_in` of the value for each output lane.
+    pub const fn get_gather_offsets(&self) -> [u32; 8] {
+        let mut offsets = [0; 8];
+
+        let mut lane = 0;
+        while lane < 8 {
+            offsets[lane] = (lane as u32).reverse_bits() >> 29;
+            offsets[lane] *= self.stride;
+            lane += 1;
+        }
+
+        offsets
+    }
+}
+
+impl Debug for BitReverseInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "bitreverse({}+{}n, {})",
+            self.first_in, self.stride, self.first_out
+        )
+    }
+}
+
+impl LowerIR for BitReverseInstruction {
+    /// The positions are absolute, so that the gather can be based on
+    /// the start of the input.
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        let positions = self
+            .get_gather_offsets()
+            .iter()
+            .map(|offset| self.first_in + offset)
+            .collect();
+        vec![program.gather(positions)]
+    }
+
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        program.store(loaded[0], self.first_out);
+    }
+}
+
+impl From<BitReverseInstruction> for Vec<SingleInstruction> {
+    fn from(val: BitReverseInstruction) -> Self {
+        val.get_gather_offsets()
+            .iter()
+            .enumerate()
+            .map(|(lane, offset)| {
+                SingleInstruction::new(val.first_in + offset, val.first_out + lane as u32)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,57 @@
+use std::fmt::Debug;
+
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
+
+use super::single::SingleInstruction;
+
+/// A run of `len` consecutive values of the input that stay in order in
+/// the output, moved `first_out - first_in` places along, which is just
+/// a `memcpy`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CopyInstruction {
+    pub first_in: u32,
+    pub first_out: u32,
+    pub len: u32,
+}
+
+impl CopyInstruction {
+    pub const fn new(first_in: u32, first_out: u32, len: u32) -> Self {
+        Self {
+            first_in,
+            first_out,
+            len,
+        }
+    }
+}
+
+impl Debug for CopyInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "copy({}, {}, {})",
+            self.first_in, self.first_out, self.len
+        )
+    }
+}
+
+/// A copy goes from memory to memory, so nothing is loaded up front.
+impl LowerIR for CopyInstruction {
+    fn lower_ir_load(&self, _program: &mut Program) -> Vec<VReg> {
+        vec![]
+    }
+
+    fn lower_ir_store(&self, program: &mut Program, _loaded: &[VReg]) {
+        program.copy(self.first_in, self.first_out, self.len);
+    }
+}
+
+impl From<CopyInstruction> for Vec<SingleInstruction> {
+    fn from(val: CopyInstruction) -> Self {
+        (0..val.len)
+            .map(|i| SingleInstruction::new(val.first_in + i, val.first_out + i))
+            .collect()
+    }
+}
@@ -1,6 +1,9 @@
 use std::fmt::Debug;
 
-use crate::encodings::{Architecture, CEncoder, SerializeAMD64MachineCode};
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
 
 use super::{four::FourInstruction, single::SingleInstruction, InstructionBlock};
 
@@ -19,7 +22,7 @@ impl EightInstruction {
         let mut full_vec = vec![];
 
         for blk in instrs.iter() {
-            let mut cloned: Vec<SingleInstruction> = blk.clone().into();
+            let mut cloned: Vec<SingleInstruction> = (*blk).into();
             full_vec.append(&mut cloned);
         }
 
@@ -33,7 +36,7 @@ impl EightInstruction {
         ))
     }
 
-    const fn get_first_output_index(&self) -> u32 {
+    pub const fn get_first_output_index(&self) -> u32 {
         let mut smallest = u32::MAX;
 
         if self.value1.value1.value < smallest {
@@ -71,20 +74,41 @@ impl EightInstruction {
         smallest
     }
 
-    const fn get_permute_mask(&self) -> (i64, i64, i64, i64) {
+    pub const fn get_first_input_index(&self) -> u32 {
+        let first = self.value1.get_first_input_index();
+        let second = self.value2.get_first_input_index();
+
+        if first < second {
+            first
+        } else {
+            second
+        }
+    }
+
+    /// Returns the index vector for this block, ie for each output lane
+    /// the input lane that should be stored there.
+    pub const fn get_permute_lanes(&self) -> [u32; 8] {
+        let first_in = self.get_first_input_index();
         let first_out = self.get_first_output_index();
-        let mut i: (i64, i64, i64, i64) = (0, 0, 0, 0);
-
-        i.1 |= ((self.value1.value4.value - first_out) as i64) << 32;
-        i.1 |= (self.value1.value3.value - first_out) as i64;
-        i.0 |= ((self.value1.value2.value - first_out) as i64) << 32;
-        i.0 |= (self.value1.value1.value - first_out) as i64;
-        i.3 |= ((self.value2.value4.value - first_out) as i64) << 32;
-        i.3 |= (self.value2.value3.value - first_out) as i64;
-        i.2 |= ((self.value2.value2.value - first_out) as i64) << 32;
-        i.2 |= (self.value2.value1.value - first_out) as i64;
-
-        i
+        let lanes = [
+            self.value1.value1,
+            self.value1.value2,
+            self.value1.value3,
+            self.value1.value4,
+            self.value2.value1,
+            self.value2.value2,
+            self.value2.value3,
+            self.value2.value4,
+        ];
+        let mut mask = [0; 8];
+
+        let mut lane = 0;
+        while lane < 8 {
+            mask[(lanes[lane].value - first_out) as usize] = lanes[lane].index - first_in;
+            lane += 1;
+        }
+
+        mask
     }
 }
 
@@ -94,52 +118,39 @@ impl Debug for EightInstruction {
     }
 }
 
-impl CEncoder for EightInstruction {
-    fn encode_to_c(&self, index: u32, arch: Architecture) -> String {
-        match &arch {
-            Architecture::Amd64 => {
-                let smallest = self.get_first_output_index();
-                let mask = self.get_permute_mask();
-
-                format!(
-                    "  __m256 valin{} = {{in[{}], in[{}], in[{}], in[{}], in[{}], in[{}], in[{}], in[{}]}};
-  static const __m256i mask{} = {{{}, {}, {}, {}}};
-  _mm256_storeu_ps(&out[{}], _mm256_permutevar8x32_ps(valin{}, mask{}));
-",
-                    index,
-                    self.value1.value1.index,
-                    self.value1.value2.index,
-                    self.value1.value3.index,
-                    self.value1.value4.index,
-                    self.value2.value1.index,
-                    self.value2.value2.index,
-                    self.value2.value3.index,
-                    self.value2.value4.index,
-                    index,
-                    mask.0,
-                    mask.1,
-                    mask.2,
-                    mask.3,
-                    smallest,
-                    index,
-                    index,
-                )
-            }
-            Architecture::Arm => format!(""),
-        }
+impl LowerIR for EightInstruction {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        vec![program.load(self.get_first_input_index(), 8)]
     }
-}
 
-impl SerializeAMD64MachineCode for EightInstruction {
-    fn write_amd64_bytes(&self, _bytes: &mut Vec<u8>) {}
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        let permuted = program.permute(loaded[0], &self.get_permute_lanes());
+        program.store(permuted, self.get_first_output_index());
+    }
 }
 
-impl Into<Vec<SingleInstruction>> for EightInstruction {
-    fn into(self) -> Vec<SingleInstruction> {
-        let mut vec1: Vec<SingleInstruction> = self.value1.into();
-        let mut vec2: Vec<SingleInstruction> = self.value2.into();
+impl From<EightInstruction> for Vec<SingleInstruction> {
+    fn from(val: EightInstruction) -> Self {
+        let mut vec1: Vec<SingleInstruction> = val.value1.into();
+        let mut vec2: Vec<SingleInstruction> = val.value2.into();
         vec1.append(&mut vec2);
 
-        return vec1;
+        vec1
     }
 }
+
+#[test]
+fn test_permute_lanes() {
+    // Each value moves one lane up from the window at 8 into the one at
+    // 16, so output lane `i` reads input lane `i - 1`. The index vector
+    // is by output lane, which a rotation tells apart from the lanes
+    // each input is stored to.
+    let singles: Vec<SingleInstruction> = (0..8)
+        .map(|i| SingleInstruction::new(8 + i, 16 + (i + 1) % 8))
+        .collect();
+    let blk = EightInstruction::new(
+        FourInstruction::new(singles[0], singles[1], singles[2], singles[3]),
+        FourInstruction::new(singles[4], singles[5], singles[6], singles[7]),
+    );
+    assert_eq!(blk.get_permute_lanes(), [7, 0, 1, 2, 3, 4, 5, 6]);
+}
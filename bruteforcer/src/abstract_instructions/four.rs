@@ -2,7 +2,8 @@ use std::fmt::Debug;
 
 use crate::{
     abstract_instructions::InstructionBlock,
-    encodings::{Architecture, CEncoder, SerializeAMD64MachineCode},
+    encodings::LowerIR,
+    ir::{Program, VReg},
 };
 
 use super::single::SingleInstruction;
@@ -60,7 +61,7 @@ impl FourInstruction {
         Some(Self::new(f1, f2, f3, f4))
     }
 
-    const fn get_first_output_index(&self) -> u32 {
+    pub const fn get_first_output_index(&self) -> u32 {
         let mut smallest = u32::MAX;
 
         if self.value1.value < smallest {
@@ -82,7 +83,7 @@ impl FourInstruction {
         smallest
     }
 
-    const fn get_first_input_index(&self) -> u32 {
+    pub const fn get_first_input_index(&self) -> u32 {
         let mut smallest = u32::MAX;
 
         if self.value1.index < smallest {
@@ -104,48 +105,21 @@ impl FourInstruction {
         smallest
     }
 
-    const fn get_permute_mask(&self) -> u8 {
+    /// Returns for each output lane the input lane that should be
+    /// stored there.
+    pub const fn get_permute_lanes(&self) -> [u32; 4] {
+        let first_in = self.get_first_input_index();
         let first_out = self.get_first_output_index();
-        let mut i: u8 = 0;
-
-        let v1 = (self.value1.value - first_out) as i32;
-        let v2 = (self.value2.value - first_out) as i32;
-        let v3 = (self.value3.value - first_out) as i32;
-        let v4 = (self.value4.value - first_out) as i32;
-
-        match v1 {
-            0 => i |= 0,
-            1 => i |= 1,
-            2 => i |= 2,
-            3 => i |= 3,
-            _ => {}
-        }
-
-        match v2 {
-            0 => i |= 0 << 2,
-            1 => i |= 1 << 2,
-            2 => i |= 2 << 2,
-            3 => i |= 3 << 2,
-            _ => {}
-        }
-
-        match v3 {
-            0 => i |= 0 << 4,
-            1 => i |= 1 << 4,
-            2 => i |= 2 << 4,
-            3 => i |= 3 << 4,
-            _ => {}
-        }
+        let lanes = [self.value1, self.value2, self.value3, self.value4];
+        let mut mask = [0; 4];
 
-        match v4 {
-            0 => i |= 0 << 6,
-            1 => i |= 1 << 6,
-            2 => i |= 2 << 6,
-            3 => i |= 3 << 6,
-            _ => {}
+        let mut lane = 0;
+        while lane < 4 {
+            mask[(lanes[lane].value - first_out) as usize] = lanes[lane].index - first_in;
+            lane += 1;
         }
 
-        i
+        mask
     }
 }
 
@@ -159,34 +133,20 @@ impl Debug for FourInstruction {
     }
 }
 
-impl CEncoder for FourInstruction {
-    fn encode_to_c(&self, index: u32, arch: Architecture) -> String {
-        match &arch {
-            Architecture::Amd64 => {
-                let smallest_in: u32 = self.get_first_input_index();
-                let smallest_out: u32 = self.get_first_output_index();
-                let mask: u8 = self.get_permute_mask();
-
-                format!(
-                    "  __m128 valin{} = _mm_maskload_ps(&in[{}], quadmask);
-  __m128 valout{} = _mm_permute_ps(valin{}, {});
-  _mm_maskstore_ps(&out[{}], quadmask, valout{});
-",
-                    index, smallest_in, index, index, mask, smallest_out, index
-                )
-            }
-            Architecture::Arm => format!(""),
-        }
+impl LowerIR for FourInstruction {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        vec![program.load(self.get_first_input_index(), 4)]
     }
-}
 
-impl SerializeAMD64MachineCode for FourInstruction {
-    fn write_amd64_bytes(&self, _bytes: &mut Vec<u8>) {}
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        let permuted = program.permute(loaded[0], &self.get_permute_lanes());
+        program.store(permuted, self.get_first_output_index());
+    }
 }
 
 // Returns the pairing of index to mapped value for each index.
-impl Into<Vec<SingleInstruction>> for FourInstruction {
-    fn into(self) -> Vec<SingleInstruction> {
-        return vec![self.value1, self.value2, self.value3, self.value4];
+impl From<FourInstruction> for Vec<SingleInstruction> {
+    fn from(val: FourInstruction) -> Self {
+        vec![val.value1, val.value2, val.value3, val.value4]
     }
 }
@@ -0,0 +1,52 @@
+use std::fmt::Debug;
+
+use crate::{
+    encodings::Target, instructions_x86_64::Instruction, ir::Program, regalloc::lower_amd64_program,
+};
+
+use super::InstructionBlock;
+
+/// A single step of a program that permutes one buffer in place. The
+/// generated code is called with the same pointer for `in` and `out`.
+/// Each step carries the id of the block it came from in the plan.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum InPlaceStep {
+    /// Load, permute and store a block in one go, nothing that is
+    /// still to be read lives in its destination window.
+    Block(u32, InstructionBlock),
+    /// Load all lanes of a block into a temporary slot ahead of
+    /// time, which breaks a cycle in the permutation.
+    Save(u32, InstructionBlock, usize),
+    /// Permute and store a block that was previously saved.
+    Restore(u32, InstructionBlock, usize),
+}
+
+impl Debug for InPlaceStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            InPlaceStep::Block(_, blk) => write!(f, "{:?}", blk),
+            InPlaceStep::Save(_, blk, slot) => write!(f, "save{}{:?}", slot, blk),
+            InPlaceStep::Restore(_, blk, slot) => write!(f, "restore{}{:?}", slot, blk),
+        }
+    }
+}
+
+impl InPlaceStep {
+    /// The block this step loads or stores.
+    pub const fn block(&self) -> InstructionBlock {
+        match self {
+            InPlaceStep::Block(_, blk)
+            | InPlaceStep::Save(_, blk, _)
+            | InPlaceStep::Restore(_, blk, _) => *blk,
+        }
+    }
+}
+
+/// Lower a whole in place program. Saved blocks stay in registers
+/// until they are restored, unless they have to be spilled to the
+/// stack.
+pub fn lower_in_place_amd64(steps: &[InPlaceStep], target: Target) -> Vec<Instruction> {
+    let mut program = Program::from_in_place_steps(steps);
+    program.optimize();
+    lower_amd64_program(&program, target)
+}
@@ -1,16 +1,32 @@
 use std::fmt::Debug;
 
-use crate::encodings::{Architecture, CEncoder, SerializeAMD64MachineCode};
+use crate::{
+    args::Feature,
+    encodings::{LowerIR, Target},
+    features::FeatureSet,
+    ir::{Program, VReg},
+    targets::IsaFamily,
+};
 
 use self::{
-    eight::EightInstruction, four::FourInstruction, single::SingleInstruction,
-    sixteen::SixteenInstruction,
+    bit_reverse::BitReverseInstruction, copy::CopyInstruction, eight::EightInstruction,
+    four::FourInstruction, partial::PartialInstruction, reverse::ReverseInstruction,
+    rotate::RotateInstruction, single::SingleInstruction, sixteen::SixteenInstruction,
+    transpose::TransposeInstruction, two_source::TwoSourceInstruction,
 };
 
+pub mod bit_reverse;
+pub mod copy;
 pub mod eight;
 pub mod four;
+pub mod in_place;
+pub mod partial;
+pub mod reverse;
+pub mod rotate;
 pub mod single;
 pub mod sixteen;
+pub mod transpose;
+pub mod two_source;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum InstructionBlock {
@@ -18,6 +34,13 @@ pub enum InstructionBlock {
     Four(FourInstruction),
     Eight(EightInstruction),
     Sixteen(SixteenInstruction),
+    Reverse(ReverseInstruction),
+    Rotate(RotateInstruction),
+    Transpose(TransposeInstruction),
+    BitReverse(BitReverseInstruction),
+    TwoSource(TwoSourceInstruction),
+    Partial(PartialInstruction),
+    Copy(CopyInstruction),
 }
 
 impl InstructionBlock {
@@ -27,7 +50,107 @@ impl InstructionBlock {
             InstructionBlock::Four(_) => 4,
             InstructionBlock::Eight(_) => 8,
             InstructionBlock::Sixteen(_) => 16,
+            InstructionBlock::Reverse(i) => i.width as usize,
+            InstructionBlock::Rotate(i) => i.width as usize,
+            InstructionBlock::Transpose(i) => (i.size * i.size) as usize,
+            InstructionBlock::BitReverse(_) => 8,
+            InstructionBlock::TwoSource(i) => i.width as usize,
+            InstructionBlock::Partial(i) => i.stored.count_ones() as usize,
+            InstructionBlock::Copy(i) => i.len as usize,
+        }
+    }
+
+    /// The amd64 extensions the lowering of this block needs. Blocks of
+    /// four lanes get by with SSE: permutes of one register only need
+    /// `pshufd` and transposes `unpcklps` and `shufps`, while other
+    /// shuffles of two may need `palignr` or `blendps`.
+    /// Masked stores and anything wider are only encoded with VEX.
+    pub fn required_features(&self) -> FeatureSet {
+        let feature = match self {
+            InstructionBlock::Single(_) => return FeatureSet::empty(),
+            InstructionBlock::Four(_) | InstructionBlock::Copy(_) => Feature::Sse2,
+            InstructionBlock::Reverse(i) if i.width == 4 => Feature::Sse2,
+            InstructionBlock::Rotate(i) if i.width == 4 => Feature::Sse2,
+            InstructionBlock::Partial(i) if i.width == 4 && i.overlapping => Feature::Sse2,
+            InstructionBlock::Transpose(i) if i.size == 4 => Feature::Sse2,
+            InstructionBlock::TwoSource(i) if i.width == 4 => Feature::Sse41,
+            InstructionBlock::Partial(i) if i.width == 4 => Feature::Avx,
+            InstructionBlock::Reverse(_) | InstructionBlock::Transpose(_) => Feature::Avx,
+            InstructionBlock::Eight(_)
+            | InstructionBlock::Rotate(_)
+            | InstructionBlock::BitReverse(_)
+            | InstructionBlock::TwoSource(_)
+            | InstructionBlock::Partial(_) => Feature::Avx2,
+            InstructionBlock::Sixteen(_) => Feature::Avx512f,
+        };
+
+        FeatureSet::from(&[feature][..])
+    }
+
+    /// Whether this block can be lowered for the target as is.
+    pub fn fits(&self, target: Target) -> bool {
+        match target.family() {
+            IsaFamily::X86_64 => target.features.contains(self.required_features()),
+            // There is no gather or masked store, and no use in sixteen
+            // lanes with 128 bit registers.
+            IsaFamily::Aarch64 | IsaFamily::Wasm32 => match self {
+                InstructionBlock::BitReverse(_) | InstructionBlock::Sixteen(_) => false,
+                InstructionBlock::Partial(i) => i.overlapping,
+                _ => true,
+            },
+            IsaFamily::Riscv64 => true,
+        }
+    }
+
+    /// This block when it fits the target, otherwise its windows of four
+    /// outputs when those fit, or else the scalar moves it is made up of.
+    pub fn fit_to(self, target: Target) -> Vec<InstructionBlock> {
+        if self.fits(target) {
+            return vec![self];
+        }
+        if let Some(quarters) = self.split_to_quarters(target) {
+            return quarters;
+        }
+
+        let singles: Vec<SingleInstruction> = self.into();
+        singles.into_iter().map(InstructionBlock::Single).collect()
+    }
+
+    /// Each window of four outputs of a wider block as a two source block,
+    /// if all of them fit the target. The windows they read stay within
+    /// the inputs of the block.
+    fn split_to_quarters(&self, target: Target) -> Option<Vec<InstructionBlock>> {
+        if matches!(self, InstructionBlock::Partial(_)) {
+            return None;
+        }
+        let mut singles: Vec<SingleInstruction> = (*self).into();
+        singles.sort_by_key(|single| single.value);
+        let end = singles.iter().map(|single| single.index + 1).max()?;
+        if singles.len() <= 4 || !singles.len().is_multiple_of(4) || end < 4 {
+            return None;
         }
+
+        singles
+            .chunks(4)
+            .map(|quarter| {
+                let blk = InstructionBlock::TwoSource(TwoSourceInstruction::new_from_singles(
+                    quarter, end,
+                )?);
+                blk.fits(target).then_some(blk)
+            })
+            .collect()
+    }
+
+    /// The smallest index this block reads from.
+    pub fn get_first_input_index(&self) -> u32 {
+        let singles: Vec<SingleInstruction> = (*self).into();
+        singles.iter().map(|s| s.index).min().unwrap_or(0)
+    }
+
+    /// The smallest index this block writes to.
+    pub fn get_first_output_index(&self) -> u32 {
+        let singles: Vec<SingleInstruction> = (*self).into();
+        singles.iter().map(|s| s.value).min().unwrap_or(0)
     }
 }
 
@@ -38,39 +161,97 @@ impl Debug for InstructionBlock {
             InstructionBlock::Four(i) => write!(f, "{:?}", i),
             InstructionBlock::Eight(i) => write!(f, "{:?}", i),
             InstructionBlock::Sixteen(i) => write!(f, "{:?}", i),
+            InstructionBlock::Reverse(i) => write!(f, "{:?}", i),
+            InstructionBlock::Rotate(i) => write!(f, "{:?}", i),
+            InstructionBlock::Transpose(i) => write!(f, "{:?}", i),
+            InstructionBlock::BitReverse(i) => write!(f, "{:?}", i),
+            InstructionBlock::TwoSource(i) => write!(f, "{:?}", i),
+            InstructionBlock::Partial(i) => write!(f, "{:?}", i),
+            InstructionBlock::Copy(i) => write!(f, "{:?}", i),
         }
     }
 }
 
-impl CEncoder for InstructionBlock {
-    fn encode_to_c(&self, index: u32, arch: Architecture) -> String {
+impl LowerIR for InstructionBlock {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
         match &self {
-            InstructionBlock::Single(i) => i.encode_to_c(index, arch),
-            InstructionBlock::Four(i) => i.encode_to_c(index, arch),
-            InstructionBlock::Eight(i) => i.encode_to_c(index, arch),
-            InstructionBlock::Sixteen(i) => i.encode_to_c(index, arch),
+            InstructionBlock::Single(i) => i.lower_ir_load(program),
+            InstructionBlock::Four(i) => i.lower_ir_load(program),
+            InstructionBlock::Eight(i) => i.lower_ir_load(program),
+            InstructionBlock::Sixteen(i) => i.lower_ir_load(program),
+            InstructionBlock::Reverse(i) => i.lower_ir_load(program),
+            InstructionBlock::Rotate(i) => i.lower_ir_load(program),
+            InstructionBlock::Transpose(i) => i.lower_ir_load(program),
+            InstructionBlock::BitReverse(i) => i.lower_ir_load(program),
+            InstructionBlock::TwoSource(i) => i.lower_ir_load(program),
+            InstructionBlock::Partial(i) => i.lower_ir_load(program),
+            InstructionBlock::Copy(i) => i.lower_ir_load(program),
         }
     }
-}
 
-impl SerializeAMD64MachineCode for InstructionBlock {
-    fn write_amd64_bytes(&self, bytes: &mut Vec<u8>) {
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
         match &self {
-            InstructionBlock::Single(i) => i.write_amd64_bytes(bytes),
-            InstructionBlock::Four(i) => i.write_amd64_bytes(bytes),
-            InstructionBlock::Eight(i) => i.write_amd64_bytes(bytes),
-            InstructionBlock::Sixteen(i) => i.write_amd64_bytes(bytes),
+            InstructionBlock::Single(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::Four(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::Eight(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::Sixteen(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::Reverse(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::Rotate(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::Transpose(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::BitReverse(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::TwoSource(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::Partial(i) => i.lower_ir_store(program, loaded),
+            InstructionBlock::Copy(i) => i.lower_ir_store(program, loaded),
         }
     }
 }
 
-impl Into<Vec<SingleInstruction>> for InstructionBlock {
-    fn into(self) -> Vec<SingleInstruction> {
-        match self {
-            InstructionBlock::Single(i) => return vec![i],
-            InstructionBlock::Four(i) => return i.into(),
-            InstructionBlock::Eight(i) => return i.into(),
-            InstructionBlock::Sixteen(i) => return i.into(),
+impl From<InstructionBlock> for Vec<SingleInstruction> {
+    fn from(val: InstructionBlock) -> Self {
+        match val {
+            InstructionBlock::Single(i) => vec![i],
+            InstructionBlock::Four(i) => i.into(),
+            InstructionBlock::Eight(i) => i.into(),
+            InstructionBlock::Sixteen(i) => i.into(),
+            InstructionBlock::Reverse(i) => i.into(),
+            InstructionBlock::Rotate(i) => i.into(),
+            InstructionBlock::Transpose(i) => i.into(),
+            InstructionBlock::BitReverse(i) => i.into(),
+            InstructionBlock::TwoSource(i) => i.into(),
+            InstructionBlock::Partial(i) => i.into(),
+            InstructionBlock::Copy(i) => i.into(),
         }
     }
 }
+
+#[test]
+fn test_fits_wasm() {
+    use crate::args::TargetModel;
+
+    let singles: Vec<SingleInstruction> = (0..16)
+        .map(|i| SingleInstruction::new(i, (i + 4) % 16))
+        .collect();
+    let four =
+        |i: usize| FourInstruction::new(singles[i], singles[i + 1], singles[i + 2], singles[i + 3]);
+    let sixteen = InstructionBlock::Sixteen(SixteenInstruction::new(
+        EightInstruction::new(four(0), four(4)),
+        EightInstruction::new(four(8), four(12)),
+    ));
+    let bit_reverse = InstructionBlock::BitReverse(BitReverseInstruction::new(0, 2, 0));
+
+    let wasm = Target::new(
+        TargetModel::Wasm32Simd128,
+        TargetModel::Wasm32Simd128.features(),
+    );
+    let avx512 = Target::new(TargetModel::X86_64V4, TargetModel::X86_64V4.features());
+    for blk in [sixteen, bit_reverse] {
+        assert!(blk.fits(avx512));
+        assert!(!blk.fits(wasm));
+        // What is left over is at most four wide and lowers as is.
+        assert!(blk
+            .fit_to(wasm)
+            .iter()
+            .all(|part| part.len() <= 4 && part.fits(wasm)));
+    }
+    assert!(InstructionBlock::Four(four(0)).fits(wasm));
+}
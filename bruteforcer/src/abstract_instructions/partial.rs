@@ -0,0 +1,107 @@
+use std::fmt::Debug;
+
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
+
+use super::single::SingleInstruction;
+
+/// A window of 4 or 8 values of which only some lanes self permute, the
+/// rest of the output window is left to other blocks. Lane `i` of the
+/// output window is lane `lanes[i]` of the input window when bit `i` of
+/// `stored` is set. Windows are moved back at the end of the pattern,
+/// so that loads never need a mask, only stores do. An overlapping
+/// block stores its whole output window instead, and has to come before
+/// the blocks writing the lanes it doesn't store.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PartialInstruction {
+    pub first_in: u32,
+    pub first_out: u32,
+    pub width: u32,
+    pub lanes: [u32; 8],
+    pub stored: u32,
+    pub overlapping: bool,
+}
+
+impl PartialInstruction {
+    /// The block moving `singles`, whose indices and values each have to
+    /// fit in a window of `width` values of a pattern of `len` values.
+    pub fn new_from_singles(singles: &[SingleInstruction], width: u32, len: u32) -> Self {
+        let first_in = singles
+            .iter()
+            .map(|s| s.index)
+            .min()
+            .unwrap()
+            .min(len - width);
+        let first_out = singles
+            .iter()
+            .map(|s| s.value)
+            .min()
+            .unwrap()
+            .min(len - width);
+
+        // Lanes that aren't stored keep their place, so that a window
+        // whose lanes all stay in place isn't permuted at all.
+        let mut lanes: [u32; 8] = std::array::from_fn(|i| i as u32);
+        let mut stored = 0;
+        for single in singles.iter() {
+            let lane = single.value - first_out;
+            lanes[lane as usize] = single.index - first_in;
+            stored |= 1 << lane;
+        }
+
+        Self {
+            first_in,
+            first_out,
+            width,
+            lanes,
+            stored,
+            overlapping: false,
+        }
+    }
+}
+
+impl Debug for PartialInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}({}, {}, {:#04x})",
+            if self.overlapping {
+                "overlapping"
+            } else {
+                "partial"
+            },
+            self.width,
+            self.first_in,
+            self.first_out,
+            self.stored
+        )
+    }
+}
+
+impl LowerIR for PartialInstruction {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        vec![program.load(self.first_in, self.width)]
+    }
+
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        let permuted = program.permute(loaded[0], &self.lanes[..self.width as usize]);
+        if self.overlapping {
+            program.store(permuted, self.first_out);
+        } else {
+            program.masked_store(permuted, self.first_out, self.stored);
+        }
+    }
+}
+
+impl From<PartialInstruction> for Vec<SingleInstruction> {
+    fn from(val: PartialInstruction) -> Self {
+        (0..val.width)
+            .filter(|i| val.stored & (1 << i) != 0)
+            .map(|i| {
+                SingleInstruction::new(val.first_in + val.lanes[i as usize], val.first_out + i)
+            })
+            .collect()
+    }
+}
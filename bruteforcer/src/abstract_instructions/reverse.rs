@@ -0,0 +1,57 @@
+use std::fmt::Debug;
+
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
+
+use super::single::SingleInstruction;
+
+/// A window of 4 or 8 consecutive values that is stored in reverse
+/// order, which needs no index vector unlike a general permute.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ReverseInstruction {
+    pub first_in: u32,
+    pub first_out: u32,
+    pub width: u32,
+}
+
+impl ReverseInstruction {
+    pub const fn new(first_in: u32, first_out: u32, width: u32) -> Self {
+        Self {
+            first_in,
+            first_out,
+            width,
+        }
+    }
+}
+
+impl Debug for ReverseInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "reverse{}({}, {})",
+            self.width, self.first_in, self.first_out
+        )
+    }
+}
+
+impl LowerIR for ReverseInstruction {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        vec![program.load(self.first_in, self.width)]
+    }
+
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        let lanes: Vec<u32> = (0..self.width).rev().collect();
+        let reversed = program.permute(loaded[0], &lanes);
+        program.store(reversed, self.first_out);
+    }
+}
+
+impl From<ReverseInstruction> for Vec<SingleInstruction> {
+    fn from(val: ReverseInstruction) -> Self {
+        (0..val.width)
+            .map(|i| SingleInstruction::new(val.first_in + i, val.first_out + val.width - 1 - i))
+            .collect()
+    }
+}
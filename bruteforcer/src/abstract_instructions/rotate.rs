@@ -0,0 +1,68 @@
+use std::fmt::Debug;
+
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
+
+use super::single::SingleInstruction;
+
+/// A window of 4 or 8 consecutive values that is rotated right by
+/// `amount` lanes, ie the value at lane `i` is stored to lane
+/// `(i + amount) % width`. Rotations are byte aligns of the window
+/// with itself, so need no index vector.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RotateInstruction {
+    pub first_in: u32,
+    pub first_out: u32,
+    pub width: u32,
+    pub amount: u32,
+}
+
+impl RotateInstruction {
+    pub const fn new(first_in: u32, first_out: u32, width: u32, amount: u32) -> Self {
+        Self {
+            first_in,
+            first_out,
+            width,
+            amount,
+        }
+    }
+}
+
+impl Debug for RotateInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rotate{}by{}({}, {})",
+            self.width, self.amount, self.first_in, self.first_out
+        )
+    }
+}
+
+impl LowerIR for RotateInstruction {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        vec![program.load(self.first_in, self.width)]
+    }
+
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        let lanes: Vec<u32> = (0..self.width)
+            .map(|lane| (lane + self.width - self.amount) % self.width)
+            .collect();
+        let rotated = program.permute(loaded[0], &lanes);
+        program.store(rotated, self.first_out);
+    }
+}
+
+impl From<RotateInstruction> for Vec<SingleInstruction> {
+    fn from(val: RotateInstruction) -> Self {
+        (0..val.width)
+            .map(|i| {
+                SingleInstruction::new(
+                    val.first_in + i,
+                    val.first_out + (i + val.amount) % val.width,
+                )
+            })
+            .collect()
+    }
+}
@@ -1,6 +1,9 @@
 use std::fmt::Debug;
 
-use crate::encodings::{Architecture, CEncoder, SerializeAMD64MachineCode};
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct SingleInstruction {
@@ -24,14 +27,14 @@ impl Debug for SingleInstruction {
     }
 }
 
-impl CEncoder for SingleInstruction {
-    fn encode_to_c(&self, _index: u32, _arch: Architecture) -> String {
-        format!("  out[{}] = in[{}];\n", self.value, self.index)
+impl LowerIR for SingleInstruction {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        vec![program.load(self.index, 1)]
     }
-}
 
-impl SerializeAMD64MachineCode for SingleInstruction {
-    fn write_amd64_bytes(&self, _bytes: &mut Vec<u8>) {}
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        program.store(loaded[0], self.value);
+    }
 }
 
 impl From<(u32, u32)> for SingleInstruction {
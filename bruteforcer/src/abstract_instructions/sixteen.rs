@@ -1,6 +1,9 @@
 use std::fmt::Debug;
 
-use crate::encodings::{Architecture, CEncoder, SerializeAMD64MachineCode};
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
 
 use super::{
     eight::EightInstruction, four::FourInstruction, single::SingleInstruction, InstructionBlock,
@@ -21,7 +24,7 @@ impl SixteenInstruction {
         let mut full_vec = vec![];
 
         for blk in instrs.iter() {
-            let mut cloned: Vec<SingleInstruction> = blk.clone().into();
+            let mut cloned: Vec<SingleInstruction> = (*blk).into();
             full_vec.append(&mut cloned);
         }
 
@@ -48,22 +51,31 @@ impl Debug for SixteenInstruction {
     }
 }
 
-impl CEncoder for SixteenInstruction {
-    fn encode_to_c(&self, _index: u32, _arch: Architecture) -> String {
-        format!("")
+/// No target permutes sixteen lanes yet, so the values are moved one at
+/// a time.
+impl LowerIR for SixteenInstruction {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        let singles: Vec<SingleInstruction> = (*self).into();
+        singles
+            .iter()
+            .flat_map(|single| single.lower_ir_load(program))
+            .collect()
     }
-}
 
-impl SerializeAMD64MachineCode for SixteenInstruction {
-    fn write_amd64_bytes(&self, _bytes: &mut Vec<u8>) {}
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        let singles: Vec<SingleInstruction> = (*self).into();
+        for (single, reg) in singles.iter().zip(loaded.iter()) {
+            single.lower_ir_store(program, &[*reg]);
+        }
+    }
 }
 
-impl Into<Vec<SingleInstruction>> for SixteenInstruction {
-    fn into(self) -> Vec<SingleInstruction> {
-        let mut vec1: Vec<SingleInstruction> = self.value1.into();
-        let mut vec2: Vec<SingleInstruction> = self.value2.into();
+impl From<SixteenInstruction> for Vec<SingleInstruction> {
+    fn from(val: SixteenInstruction) -> Self {
+        let mut vec1: Vec<SingleInstruction> = val.value1.into();
+        let mut vec2: Vec<SingleInstruction> = val.value2.into();
         vec1.append(&mut vec2);
 
-        return vec1;
+        vec1
     }
 }
@@ -0,0 +1,143 @@
+use std::fmt::Debug;
+
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
+
+use super::single::SingleInstruction;
+
+/// A 4x4 or 8x8 tile of a matrix transpose. Row `a` of the tile is
+/// read from `first_in + a * in_stride` and stored as column `a` of
+/// the tile at `first_out`, whose rows are `out_stride` apart. The
+/// rows are shuffled with an unpack network, rather than permuting
+/// single lanes across windows.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TransposeInstruction {
+    pub first_in: u32,
+    pub in_stride: u32,
+    pub first_out: u32,
+    pub out_stride: u32,
+    pub size: u32,
+}
+
+impl TransposeInstruction {
+    pub const fn new(
+        first_in: u32,
+        in_stride: u32,
+        first_out: u32,
+        out_stride: u32,
+        size: u32,
+    ) -> Self {
+        Self {
+            first_in,
+            in_stride,
+            first_out,
+            out_stride,
+            size,
+        }
+    }
+
+    const fn input_row(&self, row: u32) -> u32 {
+        self.first_in + row * self.in_stride
+    }
+
+    const fn output_row(&self, row: u32) -> u32 {
+        self.first_out + row * self.out_stride
+    }
+
+    /// The 4x4 transpose with the unpack and move halves network of
+    /// `_MM_TRANSPOSE4_PS`, returns the transposed rows.
+    fn transpose4(program: &mut Program, rows: &[VReg]) -> Vec<VReg> {
+        let low = program.shuffle(rows[0], rows[1], &[0, 4, 1, 5]);
+        let high = program.shuffle(rows[0], rows[1], &[2, 6, 3, 7]);
+        let low2 = program.shuffle(rows[2], rows[3], &[0, 4, 1, 5]);
+        let high2 = program.shuffle(rows[2], rows[3], &[2, 6, 3, 7]);
+
+        vec![
+            program.shuffle(low, low2, &[0, 1, 4, 5]),
+            program.shuffle(low, low2, &[2, 3, 6, 7]),
+            program.shuffle(high, high2, &[0, 1, 4, 5]),
+            program.shuffle(high, high2, &[2, 3, 6, 7]),
+        ]
+    }
+
+    /// The 8x8 transpose, which does the 4x4 network within each 128 bit
+    /// half and then combines the halves. Returns the transposed rows.
+    fn transpose8(program: &mut Program, rows: &[VReg]) -> Vec<VReg> {
+        // Interleave pairs of rows.
+        let mut low = vec![];
+        let mut high = vec![];
+        for pair in rows.chunks(2) {
+            low.push(program.shuffle(pair[0], pair[1], &[0, 8, 1, 9, 4, 12, 5, 13]));
+            high.push(program.shuffle(pair[0], pair[1], &[2, 10, 3, 11, 6, 14, 7, 15]));
+        }
+
+        // Gather columns of four rows within each 128 bit half.
+        let mut quads = vec![];
+        for quad in 0..2 {
+            for unpacked in [&low, &high] {
+                let (first, second) = (unpacked[2 * quad], unpacked[2 * quad + 1]);
+                quads.push(program.shuffle(first, second, &[0, 1, 8, 9, 4, 5, 12, 13]));
+                quads.push(program.shuffle(first, second, &[2, 3, 10, 11, 6, 7, 14, 15]));
+            }
+        }
+
+        // Then combine the halves of both quads.
+        (0..8)
+            .map(|row| {
+                let halves = if row < 4 {
+                    [0, 1, 2, 3, 8, 9, 10, 11]
+                } else {
+                    [4, 5, 6, 7, 12, 13, 14, 15]
+                };
+                program.shuffle(quads[row % 4], quads[row % 4 + 4], &halves)
+            })
+            .collect()
+    }
+}
+
+impl Debug for TransposeInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "transpose{}({}+{}n, {}+{}n)",
+            self.size, self.first_in, self.in_stride, self.first_out, self.out_stride
+        )
+    }
+}
+
+impl LowerIR for TransposeInstruction {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        (0..self.size)
+            .map(|row| program.load(self.input_row(row), self.size))
+            .collect()
+    }
+
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        let rows = match self.size {
+            4 => Self::transpose4(program, loaded),
+            _ => Self::transpose8(program, loaded),
+        };
+
+        for (row, reg) in rows.iter().enumerate() {
+            program.store(*reg, self.output_row(row as u32));
+        }
+    }
+}
+
+impl From<TransposeInstruction> for Vec<SingleInstruction> {
+    fn from(val: TransposeInstruction) -> Self {
+        let mut singles = vec![];
+        for row in 0..val.size {
+            for col in 0..val.size {
+                singles.push(SingleInstruction::new(
+                    val.input_row(row) + col,
+                    val.output_row(col) + row,
+                ));
+            }
+        }
+
+        singles
+    }
+}
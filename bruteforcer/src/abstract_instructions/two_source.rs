@@ -0,0 +1,100 @@
+use std::fmt::Debug;
+
+use crate::{
+    encodings::LowerIR,
+    ir::{Program, VReg},
+};
+
+use super::single::SingleInstruction;
+
+/// A window of 4 or 8 consecutive outputs whose values are read from
+/// two windows of the input of the same width. Lane `i` of the output
+/// is lane `lanes[i]` of the first window followed by the second, the
+/// windows can overlap and are the same when one is enough.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TwoSourceInstruction {
+    pub first_in: [u32; 2],
+    pub first_out: u32,
+    pub width: u32,
+    pub lanes: [u32; 8],
+}
+
+impl TwoSourceInstruction {
+    /// The block moving `singles`, which have to store to consecutive
+    /// outputs in order, if their values are read from at most two
+    /// windows of an input of `len` values.
+    pub fn new_from_singles(singles: &[SingleInstruction], len: u32) -> Option<Self> {
+        let width = singles.len() as u32;
+        let window = |first: u32| first.min(len - width);
+
+        let first = window(singles.iter().map(|s| s.index).min()?);
+        let second = singles
+            .iter()
+            .map(|s| s.index)
+            .filter(|index| *index >= first + width)
+            .min()
+            .map_or(first, window);
+        if singles.iter().any(|s| s.index >= second + width) {
+            return None;
+        }
+
+        let mut lanes = [0; 8];
+        for (i, single) in singles.iter().enumerate() {
+            if single.value != singles[0].value + i as u32 {
+                return None;
+            }
+            lanes[i] = if single.index < first + width {
+                single.index - first
+            } else {
+                width + single.index - second
+            };
+        }
+
+        Some(Self {
+            first_in: [first, second],
+            first_out: singles[0].value,
+            width,
+            lanes,
+        })
+    }
+}
+
+impl Debug for TwoSourceInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "two_source{}({}, {}, {})",
+            self.width, self.first_in[0], self.first_in[1], self.first_out
+        )
+    }
+}
+
+impl LowerIR for TwoSourceInstruction {
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg> {
+        let mut loaded = vec![program.load(self.first_in[0], self.width)];
+        if self.first_in[1] != self.first_in[0] {
+            loaded.push(program.load(self.first_in[1], self.width));
+        }
+        loaded
+    }
+
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]) {
+        let lanes = &self.lanes[..self.width as usize];
+        let shuffled = program.shuffle(loaded[0], *loaded.last().unwrap(), lanes);
+        program.store(shuffled, self.first_out);
+    }
+}
+
+impl From<TwoSourceInstruction> for Vec<SingleInstruction> {
+    fn from(val: TwoSourceInstruction) -> Self {
+        (0..val.width)
+            .map(|i| {
+                let lane = val.lanes[i as usize];
+                SingleInstruction::new(
+                    val.first_in[(lane / val.width) as usize] + lane % val.width,
+                    val.first_out + i,
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt::Display;
+
+use crate::{
+    abstract_instructions::{single::SingleInstruction, InstructionBlock},
+    optimize::ShiftMask,
+};
+
+/// Statistics about a pattern that help explain how well it can be
+/// SIMDized, and why it benchmarks the way it does.
+pub struct PatternReport {
+    /// Number of elements in the pattern.
+    pub len: usize,
+    /// Lengths of every cycle longer than one, in decreasing order.
+    pub cycle_lengths: Vec<usize>,
+    /// Number of elements that map onto themselves.
+    pub fixed_points: usize,
+    /// Longest run of consecutive elements with `dst == src`.
+    pub longest_identity_run: usize,
+    /// Longest run of consecutive elements with `dst == src + shift`
+    /// for some nonzero shift, along with that shift.
+    pub longest_shift_run: (usize, i64),
+    /// Counts of `|dst - src|` distances, bucketed by powers of two so
+    /// that bucket `i` holds distances in `[2^(i-1), 2^i)` and bucket
+    /// zero holds the fixed points.
+    pub distance_buckets: Vec<usize>,
+    /// Fraction of lanes that can be covered by in-window blocks of each
+    /// of 4, 8 and 16 lanes on their own.
+    pub coverable: Vec<(u8, f64)>,
+    /// Fraction of lanes covered by blocks wider than one after
+    /// `optimize_to_blocks`.
+    pub covered: f64,
+}
+
+impl ShiftMask {
+    pub fn analyze(&self) -> PatternReport {
+        let values = self.values();
+        let len = values.len();
+
+        let cycles = self.cycles();
+        let fixed_points = cycles.iter().filter(|c| c.len() == 1).count();
+        let mut cycle_lengths: Vec<usize> =
+            cycles.iter().map(|c| c.len()).filter(|l| *l > 1).collect();
+        cycle_lengths.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut longest_identity_run = 0;
+        let mut longest_shift_run = (0, 0);
+        let mut run = 0;
+        for (i, val) in values.iter().enumerate() {
+            let shift = *val as i64 - i as i64;
+            if i > 0 && values[i - 1] as i64 - (i - 1) as i64 == shift {
+                run += 1;
+            } else {
+                run = 1;
+            }
+
+            if shift == 0 {
+                longest_identity_run = longest_identity_run.max(run);
+            } else if run > longest_shift_run.0 {
+                longest_shift_run = (run, shift);
+            }
+        }
+
+        let mut distance_buckets = vec![];
+        for (i, val) in values.iter().enumerate() {
+            let distance = (*val as i64 - i as i64).unsigned_abs();
+            let bucket = (u64::BITS - distance.leading_zeros()) as usize;
+            if distance_buckets.len() <= bucket {
+                distance_buckets.resize(bucket + 1, 0);
+            }
+            distance_buckets[bucket] += 1;
+        }
+
+        let singles: Vec<InstructionBlock> = values
+            .iter()
+            .enumerate()
+            .map(|(i, val)| InstructionBlock::Single(SingleInstruction::new(i as u32, *val)))
+            .collect();
+        let mut tmp_vec = vec![];
+        let mut coverable = vec![];
+        for width in [4_u8, 8, 16] {
+            let mut lanes = 0;
+            let mut i = 0;
+            while i + width as usize <= len {
+                let chunk = &singles[i..i + width as usize];
+                if Self::chunk_self_permutes(&mut tmp_vec, chunk, width) {
+                    lanes += width as usize;
+                    i += width as usize;
+                } else {
+                    i += 1;
+                }
+            }
+            coverable.push((width, Self::fraction(lanes, len)));
+        }
+
+        let covered_lanes: usize = self
+            .optimize_to_blocks(255)
+            .iter()
+            .map(|blk| blk.len())
+            .filter(|l| *l > 1)
+            .sum();
+
+        PatternReport {
+            len,
+            cycle_lengths,
+            fixed_points,
+            longest_identity_run,
+            longest_shift_run,
+            distance_buckets,
+            coverable,
+            covered: Self::fraction(covered_lanes, len),
+        }
+    }
+
+    fn fraction(lanes: usize, len: usize) -> f64 {
+        if len == 0 {
+            0.0
+        } else {
+            lanes as f64 / len as f64
+        }
+    }
+}
+
+impl Display for PatternReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "length: {}", self.len)?;
+        writeln!(f, "cycles: {}", self.cycle_lengths.len())?;
+        if !self.cycle_lengths.is_empty() {
+            let longest = self.cycle_lengths[0];
+            let mean =
+                self.cycle_lengths.iter().sum::<usize>() as f64 / self.cycle_lengths.len() as f64;
+            writeln!(f, "  longest: {}, mean length: {:.2}", longest, mean)?;
+
+            write!(f, "  lengths:")?;
+            let mut i = 0;
+            while i < self.cycle_lengths.len() {
+                let length = self.cycle_lengths[i];
+                let count = self.cycle_lengths[i..]
+                    .iter()
+                    .take_while(|l| **l == length)
+                    .count();
+                write!(f, " {}x{}", count, length)?;
+                i += count;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "fixed points: {}", self.fixed_points)?;
+        writeln!(f, "longest identity run: {}", self.longest_identity_run)?;
+        writeln!(
+            f,
+            "longest shift run: {} (shift {})",
+            self.longest_shift_run.0, self.longest_shift_run.1
+        )?;
+
+        writeln!(f, "|dst - src| distances:")?;
+        for (bucket, count) in self.distance_buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            match bucket {
+                0 => writeln!(f, "  0: {}", count)?,
+                1 => writeln!(f, "  1: {}", count)?,
+                _ => writeln!(
+                    f,
+                    "  {}-{}: {}",
+                    1_u64 << (bucket - 1),
+                    (1_u64 << bucket) - 1,
+                    count
+                )?,
+            }
+        }
+
+        writeln!(f, "coverable by in-window blocks:")?;
+        for (width, fraction) in self.coverable.iter() {
+            writeln!(f, "  {:>2} lanes: {:.1}%", width, fraction * 100.0)?;
+        }
+        writeln!(
+            f,
+            "covered by optimize_to_blocks: {:.1}%",
+            self.covered * 100.0
+        )
+    }
+}
+
+#[test]
+fn test_analyze() {
+    let report = ShiftMask::from(vec![1, 2, 3, 0, 4, 5, 7, 6, 9, 10, 11, 8, 13, 12]).analyze();
+
+    assert_eq!(report.len, 14);
+    assert_eq!(report.cycle_lengths, vec![4, 4, 2, 2]);
+    assert_eq!(report.fixed_points, 2);
+    assert_eq!(report.longest_identity_run, 2);
+    assert_eq!(report.longest_shift_run, (3, 1));
+    assert_eq!(report.distance_buckets, vec![2, 10, 2]);
+    assert_eq!(
+        report.coverable,
+        vec![(4, 12.0 / 14.0), (8, 8.0 / 14.0), (16, 0.0)]
+    );
+    assert_eq!(report.covered, 12.0 / 14.0);
+
+    // A partial window only covers the lanes it stores.
+    let report = ShiftMask::from(vec![1, 0, 2, 3, 6, 4, 5]).analyze();
+    assert_eq!(report.covered, 1.0);
+}
@@ -1,4 +1,6 @@
-use clap::{Parser, Subcommand};
+use std::fmt::Display;
+
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -9,7 +11,7 @@ use clap::{Parser, Subcommand};
 pub struct BruteforcerArgs {
     /// Specify the subcommand you wish to run.
     #[command(subcommand)]
-    pub cmd: BruteforcerCmds,
+    pub cmd: Option<BruteforcerCmds>,
 
     /// Specify the pattern to generate output code for.
     #[arg(long, short, value_delimiter = ',')]
@@ -19,8 +21,45 @@ pub struct BruteforcerArgs {
     #[arg(short, long, default_value_t = 50)]
     pub len: u32,
 
-    #[arg(long, short, default_value_t = String::from("amd64"))]
-    pub arch: String,
+    /// Target model to generate code for, defaults to the best x86-64
+    /// model the host implements, or the model of the host's family on
+    /// other hosts.
+    #[arg(long, short)]
+    pub target: Option<TargetModel>,
+
+    /// Instruction set extensions generated code may use, defaults to
+    /// those of the host, or those of the model given with --target.
+    /// Blocks that need anything else are split into scalar moves.
+    #[arg(long, value_delimiter = ',')]
+    pub target_features: Option<Vec<Feature>>,
+
+    /// List the target models along with their vector widths, shuffles
+    /// and latencies.
+    #[arg(long, default_value_t = false)]
+    pub list_targets: bool,
+
+    /// Generate code that permutes a single buffer in place, the
+    /// generated function must then be called with `in == out`.
+    #[arg(long, default_value_t = false)]
+    pub in_place: bool,
+
+    /// Order the generated blocks by the cache tiles of the source and
+    /// destination arrays they touch, rather than by pattern order.
+    /// Ignored for in place code, whose order follows the permutation cycles.
+    #[arg(long, default_value_t = false)]
+    pub tile: bool,
+
+    /// Tile size in bytes to use with --tile, defaults to the page size
+    /// of the target architecture.
+    #[arg(long)]
+    pub tile_size: Option<u32>,
+
+    /// Roll blocks of generated C that repeat the same moves at a
+    /// constant stride into loops, with this many blocks in each
+    /// iteration. 0, the default, leaves every block unrolled. Ignored
+    /// for in place code.
+    #[arg(long, default_value_t = 0)]
+    pub unroll: u32,
 }
 
 #[derive(Subcommand, Debug)]
@@ -38,9 +77,278 @@ pub enum BruteforcerCmds {
     /// tries to create a pattern that contains subsets that can
     /// be SIMDized.
     #[command(alias = "randpat")]
-    RandomPattern,
+    RandomPattern {
+        /// Seed for the random number generator. When omitted a seed is
+        /// picked at random and printed to stderr.
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Relative weights of drawing a single value, or a shuffled
+        /// run of 4, 8 or 16 values, at each step of the generator.
+        #[arg(long, value_delimiter = ',', default_values_t = [1, 1, 1, 1])]
+        weights: Vec<u32>,
+
+        /// Largest distance between the position a run is placed at
+        /// and the values within it.
+        #[arg(long)]
+        max_displacement: Option<u32>,
+
+        /// Generate a uniformly random (Fisher-Yates) permutation instead.
+        #[arg(long, default_value_t = false)]
+        uniform: bool,
+
+        /// Number of patterns to generate from the same seed.
+        #[arg(long, default_value_t = 1)]
+        count: u32,
+    },
+
+    /// Generate a pattern of specified length from a named family of
+    /// structured permutations.
+    #[command(alias = "structpat")]
+    StructuredPattern {
+        /// The family of permutations to generate from.
+        family: PatternFamily,
+
+        /// Rotation amount, block size, stride or butterfly stage,
+        /// depending on the family.
+        #[arg(short, default_value_t = 1)]
+        k: u32,
+
+        /// Number of rows of the matrix to transpose, the length
+        /// must be a multiple of this.
+        #[arg(long, default_value_t = 1)]
+        rows: u32,
+    },
+
+    /// Report statistics about a pattern, such as its cycles, runs,
+    /// displacement distances and how much of it can be SIMDized.
+    Analyze,
+
+    /// Generate a C program for a pattern, compile and run it, and check
+    /// its output against the pattern, reporting the blocks that moved
+    /// values to the wrong place. Honors --in-place and --tile. Code for
+    /// armv9-sve and rv64gcv is run on a lane simulator at every vector
+    /// length instead, unless a compiler is given with --cc. Code for
+    /// wasm32-simd128 needs a --cc whose output runs on the host, eg a
+    /// wrapper around wasi-sdk and wasmtime.
+    Verify {
+        /// Corpus of patterns to verify instead of --pattern, either
+        /// `patterns.py` or a file with one pattern per line.
+        #[arg(long)]
+        corpus: Option<String>,
+
+        /// C compiler to use, defaults to $CC and then to `cc`.
+        #[arg(long)]
+        cc: Option<String>,
+
+        /// Keep the generated sources and binaries around.
+        #[arg(long, default_value_t = false)]
+        keep: bool,
+    },
+
+    /// Benchmark the generated machine code of every pattern of a
+    /// corpus with each strategy, and write the time stamp counter ticks
+    /// of every iteration as CSV, in the schema of `commands.py runbench`.
+    #[command(alias = "bench")]
+    Benchmark {
+        /// Corpus of patterns, either `patterns.py` or a file with
+        /// one comma separated pattern per line.
+        #[arg(long, default_value_t = String::from("patterns.py"))]
+        corpus: String,
+
+        /// Strategies to generate code with.
+        #[arg(long, value_delimiter = ',', default_values_t = [
+            Strategy::Scalar,
+            Strategy::Simd,
+            Strategy::Structured,
+            Strategy::Tiled,
+            Strategy::InPlace,
+        ])]
+        strategies: Vec<Strategy>,
+
+        /// Number of untimed calls before timing a program.
+        #[arg(long, default_value_t = 50)]
+        warmup: u32,
+
+        /// Number of timed calls of each program.
+        #[arg(long, default_value_t = 500)]
+        iterations: u32,
+
+        /// Emit the instructions of every block in order, instead of
+        /// overlapping the loads of the next blocks with the current one.
+        #[arg(long, default_value_t = false)]
+        no_schedule: bool,
+
+        /// Where to write the results to.
+        #[arg(long, short, default_value_t = String::from("results.csv"))]
+        output: String,
+
+        /// Performance counters to read around every timed call, written
+        /// as extra columns. Counters that can't be opened are left empty.
+        #[arg(long, value_delimiter = ',')]
+        counters: Vec<Counter>,
+    },
+
+    /// Summarize one or more benchmark result CSVs, and compare two of
+    /// them to find regressions.
+    Report {
+        /// Result CSVs, when given two the second is compared against
+        /// the first.
+        #[arg(required = true)]
+        inputs: Vec<String>,
+
+        /// Template that speedups are computed against.
+        #[arg(long, default_value_t = String::from("scalar"))]
+        baseline: String,
+
+        /// Number of bootstrap resamples of each confidence interval.
+        #[arg(long, default_value_t = 1000)]
+        resamples: u32,
+
+        /// Confidence level of the intervals.
+        #[arg(long, default_value_t = 0.95)]
+        confidence: f64,
+
+        /// Significance level of the comparison between two runs.
+        #[arg(long, default_value_t = 0.05)]
+        alpha: f64,
+
+        /// Seed for the bootstrap resampling.
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Also write a standalone HTML page with charts to this path.
+        #[arg(long)]
+        html: Option<String>,
+
+        /// Exit with an error when the second run has significant
+        /// regressions.
+        #[arg(long, default_value_t = false)]
+        fail_on_regression: bool,
+    },
 
     /// Generate an input sequence of form 0,1,2,3,4 ... n - 1.
     #[command(alias = "inputseq")]
     InputSequence,
 }
+
+/// Named families of structured permutations.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PatternFamily {
+    Reverse,
+    Rotate,
+    BlockReverse,
+    Transpose,
+    BitReversal,
+    Shuffle,
+    Unshuffle,
+    StrideGather,
+    Butterfly,
+}
+
+/// Code generation strategies to benchmark.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// A scalar move per value.
+    Scalar,
+    /// Windows of 4 and 8 values that self permute.
+    Simd,
+    /// Structured regions, then windows of 4 and 8 values.
+    Structured,
+    /// Structured blocks, ordered by cache tiles.
+    Tiled,
+    /// Permute a single buffer in place.
+    InPlace,
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// Performance counters that can be read around a program.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    /// Core cycles.
+    Cycles,
+    /// Retired instructions.
+    Instructions,
+    /// L1 data cache read misses.
+    L1dMisses,
+    /// Nanoseconds the thread was running, which needs no PMU.
+    TaskClock,
+    /// Uops dispatched to port 0, on Intel only.
+    Port0,
+    /// Uops dispatched to port 1, on Intel only.
+    Port1,
+    /// Uops dispatched to port 2, on Intel only.
+    Port2,
+    /// Uops dispatched to port 3, on Intel only.
+    Port3,
+    /// Uops dispatched to port 4, on Intel only.
+    Port4,
+    /// Uops dispatched to port 5, on Intel only.
+    Port5,
+    /// Uops dispatched to port 6, on Intel only.
+    Port6,
+    /// Uops dispatched to port 7, on Intel only.
+    Port7,
+}
+
+impl Display for Counter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// Instruction set extensions of amd64 that blocks can be lowered with.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    Sse2,
+    Ssse3,
+    #[value(name = "sse4.1")]
+    Sse41,
+    Avx,
+    Avx2,
+    Avx512f,
+    Avx512vbmi,
+}
+
+impl Display for Feature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// Target models that code can be generated for.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetModel {
+    /// x86-64 with SSE up to 4.2.
+    #[value(name = "x86-64-v2")]
+    X86_64V2,
+    /// x86-64 with AVX2.
+    #[value(name = "x86-64-v3")]
+    X86_64V3,
+    /// x86-64 with AVX-512.
+    #[value(name = "x86-64-v4")]
+    X86_64V4,
+    /// Armv8-A with 128 bit NEON vectors.
+    #[value(name = "armv8-neon")]
+    Armv8Neon,
+    /// Armv9-A with vector length agnostic SVE2.
+    #[value(name = "armv9-sve")]
+    Armv9Sve,
+    /// RV64GC with the vector extension.
+    #[value(name = "rv64gcv")]
+    Rv64gcv,
+    /// WebAssembly with the 128 bit SIMD proposal.
+    #[value(name = "wasm32-simd128")]
+    Wasm32Simd128,
+}
+
+impl Display for TargetModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
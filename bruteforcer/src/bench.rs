@@ -0,0 +1,236 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use crate::{
+    abstract_instructions::{in_place::lower_in_place_amd64, InstructionBlock},
+    args::{Counter, Strategy},
+    counters::PerfCounters,
+    encodings::Target,
+    optimize::ShiftMask,
+    overlap::fit_overlapping,
+    playground::Playground,
+    regalloc::lower_amd64_blocks,
+    schedule::schedule_amd64,
+    tiling::tile_blocks,
+};
+
+/// A pattern of the benchmark corpus, numbered within the patterns of
+/// the same length like the corpus of `commands.py`.
+pub struct CorpusPattern {
+    pub pattern_number: u32,
+    pub mask: ShiftMask,
+}
+
+/// Read a corpus of patterns, which is either `patterns.py` (from which
+/// every quoted list of values is taken) or a plain file with a pattern
+/// per line.
+pub fn read_corpus(contents: &str) -> Result<Vec<CorpusPattern>, String> {
+    let mut patterns = vec![];
+    let mut numbers: HashMap<usize, u32> = HashMap::new();
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let candidates: Vec<&str> = if line.contains('"') {
+            line.split('"').skip(1).step_by(2).collect()
+        } else {
+            vec![line.trim()]
+        };
+
+        for candidate in candidates.into_iter() {
+            if candidate.is_empty() || candidate.starts_with('#') {
+                continue;
+            }
+            let Ok(values) = candidate
+                .split(',')
+                .map(|v| v.trim().parse::<u32>())
+                .collect::<Result<Vec<u32>, _>>()
+            else {
+                continue;
+            };
+
+            let mask = ShiftMask::from(values);
+            if !mask.is_permutation() {
+                return Err(format!(
+                    "pattern on line {} is not a permutation",
+                    line_number + 1
+                ));
+            }
+
+            let number = numbers.entry(mask.len()).or_insert(0);
+            patterns.push(CorpusPattern {
+                pattern_number: *number,
+                mask,
+            });
+            *number += 1;
+        }
+    }
+
+    if patterns.is_empty() {
+        return Err("corpus does not contain any patterns".to_string());
+    }
+
+    Ok(patterns)
+}
+
+/// Generate the machine code for a pattern with the given strategy,
+/// returning the program along with the number of blocks within it.
+/// Unless `schedule` is off, independent blocks are overlapped.
+pub fn compile_amd64(
+    mask: &ShiftMask,
+    strategy: Strategy,
+    target: Target,
+    schedule: bool,
+) -> (Vec<u8>, usize) {
+    let lower = |blocks: VecDeque<InstructionBlock>| {
+        let blocks = fit_overlapping(&Vec::from(blocks), target);
+        (lower_amd64_blocks(&blocks, target), blocks.len())
+    };
+
+    let (instrs, blocks) = match strategy {
+        Strategy::Scalar => lower(mask.optimize_to_window_blocks(1)),
+        Strategy::Simd => lower(mask.optimize_to_window_blocks(255)),
+        Strategy::Structured => lower(mask.optimize_to_blocks(255)),
+        Strategy::Tiled => lower(tile_blocks(
+            mask.optimize_to_blocks(255),
+            &target.description().tile,
+        )),
+        Strategy::InPlace => {
+            let steps = mask.optimize_to_in_place_blocks(255, target);
+            (lower_in_place_amd64(&steps, target), steps.len())
+        }
+    };
+
+    let instrs = if schedule {
+        let in_place = strategy == Strategy::InPlace;
+        schedule_amd64(&instrs, &target.description().costs, in_place)
+    } else {
+        instrs
+    };
+
+    let mut program = vec![];
+    target.write_amd64_function(&instrs, &mut program);
+
+    (program, blocks)
+}
+
+/// Benchmark every pattern of the corpus with every strategy, and write
+/// one CSV row per timed iteration. Programs that compute the wrong
+/// permutation are reported and skipped.
+#[allow(clippy::too_many_arguments)]
+pub fn run_bench(
+    corpus: &[CorpusPattern],
+    strategies: &[Strategy],
+    target: Target,
+    schedule: bool,
+    warmup: u32,
+    iterations: u32,
+    counters: &[Counter],
+    output: &str,
+) -> Result<(), String> {
+    let file = File::create(output).map_err(|e| format!("unable to create {}: {}", output, e))?;
+    let mut results = BufWriter::new(file);
+    let write_err = |e: std::io::Error| format!("unable to write {}: {}", output, e);
+
+    let counters = PerfCounters::open(counters);
+    for counter in counters.unavailable() {
+        eprintln!("counter {} is unavailable, leaving it empty", counter);
+    }
+
+    write!(
+        results,
+        "compute_time,template_name,arg_count,pattern_number,iteration_number,blocks,program_bytes"
+    )
+    .map_err(write_err)?;
+    for counter in counters.counters() {
+        write!(results, ",{}", counter).map_err(write_err)?;
+    }
+    writeln!(results).map_err(write_err)?;
+
+    for strategy in strategies.iter() {
+        for pattern in corpus.iter() {
+            let mask = &pattern.mask;
+            let (program, blocks) = compile_amd64(mask, *strategy, target, schedule);
+            let pg = unsafe { Playground::new(program.len().next_multiple_of(4096) as u32) };
+
+            let correct = match strategy {
+                Strategy::InPlace => pg.run_is_correct_in_place(&program, mask),
+                _ => pg.run_is_correct(&program, mask),
+            };
+            if !correct {
+                eprintln!(
+                    "skipping {} on pattern {} of length {}, it is incorrect",
+                    strategy,
+                    pattern.pattern_number,
+                    mask.len()
+                );
+                continue;
+            }
+
+            eprintln!(
+                "running {} arg_count {} pattern {}...",
+                strategy,
+                mask.len(),
+                pattern.pattern_number
+            );
+            let measurements = pg.time(
+                &program,
+                mask.len(),
+                *strategy == Strategy::InPlace,
+                warmup,
+                iterations,
+                &counters,
+            );
+            for (iteration, measurement) in measurements.iter().enumerate() {
+                write!(
+                    results,
+                    "{},{},{},{},{},{},{}",
+                    measurement.ticks,
+                    strategy,
+                    mask.len(),
+                    pattern.pattern_number,
+                    iteration,
+                    blocks,
+                    program.len()
+                )
+                .map_err(write_err)?;
+                for reading in measurement.counters.iter() {
+                    match reading {
+                        Some(reading) => write!(results, ",{}", reading),
+                        None => write!(results, ","),
+                    }
+                    .map_err(write_err)?;
+                }
+                writeln!(results).map_err(write_err)?;
+            }
+        }
+    }
+
+    results.flush().map_err(write_err)
+}
+
+#[test]
+fn test_read_corpus() {
+    let corpus = read_corpus(
+        "patterns = {
+\t10: [\"1,2,4,9,8,6,0,5,7,3\", \"9,7,6,8,0,5,2,4,3,1\"],
+\t4: [\"3,2,1,0\"],
+}",
+    )
+    .unwrap();
+    assert_eq!(
+        corpus
+            .iter()
+            .map(|p| (p.mask.len(), p.pattern_number))
+            .collect::<Vec<_>>(),
+        vec![(10, 0), (10, 1), (4, 0)]
+    );
+
+    let corpus = read_corpus("# plain corpus\n1,0,2\n\n2,0,1\n").unwrap();
+    assert_eq!(corpus[1].mask.values(), [2, 0, 1]);
+    assert_eq!(corpus[1].pattern_number, 1);
+
+    assert!(read_corpus("1,1,0\n").is_err());
+    assert!(read_corpus("patterns = {}\n").is_err());
+}
@@ -0,0 +1,224 @@
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+use std::mem::size_of;
+
+use libc::{c_int, c_void};
+
+use crate::args::Counter;
+
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_TYPE_HW_CACHE: u32 = 3;
+const PERF_TYPE_RAW: u32 = 4;
+
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+// L1D read misses, ie cache id 0, op 0 and result 1.
+const PERF_COUNT_HW_CACHE_L1D_READ_MISS: u64 = 1 << 16;
+
+const PERF_ATTR_FLAG_DISABLED: u64 = 1 << 0;
+const PERF_ATTR_FLAG_EXCLUDE_KERNEL: u64 = 1 << 5;
+const PERF_ATTR_FLAG_EXCLUDE_HV: u64 = 1 << 6;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+const PERF_EVENT_IOC_RESET: libc::c_ulong = 0x2403;
+
+/// `struct perf_event_attr` up to `sig_data`, the kernel accepts any of
+/// its published sizes and zero fills the rest.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved_2: u16,
+    aux_sample_size: u32,
+    reserved_3: u32,
+    sig_data: u64,
+}
+
+impl Counter {
+    /// The event type and config of this counter, or `None` when the CPU
+    /// has no such event that we know of.
+    fn event(&self) -> Option<(u32, u64)> {
+        match self {
+            Counter::Cycles => Some((PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES)),
+            Counter::Instructions => Some((PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS)),
+            Counter::L1dMisses => Some((PERF_TYPE_HW_CACHE, PERF_COUNT_HW_CACHE_L1D_READ_MISS)),
+            Counter::TaskClock => Some((PERF_TYPE_SOFTWARE, PERF_COUNT_SW_TASK_CLOCK)),
+            Counter::Port0
+            | Counter::Port1
+            | Counter::Port2
+            | Counter::Port3
+            | Counter::Port4
+            | Counter::Port5
+            | Counter::Port6
+            | Counter::Port7 => {
+                let port = *self as u64 - Counter::Port0 as u64;
+                uops_dispatched_event(port).map(|config| (PERF_TYPE_RAW, config))
+            }
+        }
+    }
+}
+
+/// The raw config counting uops dispatched to `port` on Intel cores,
+/// whose unit masks select the port. AMD has no per port counts.
+#[cfg(target_arch = "x86_64")]
+fn uops_dispatched_event(port: u64) -> Option<u64> {
+    let vendor = __cpuid(0);
+    // "GenuineIntel", split over ebx, edx and ecx.
+    if (vendor.ebx, vendor.edx, vendor.ecx) != (0x756e6547, 0x49656e69, 0x6c65746e) {
+        return None;
+    }
+
+    let signature = __cpuid(1).eax;
+    let family = (signature >> 8) & 0xf;
+    let model = ((signature >> 12) & 0xf0) | ((signature >> 4) & 0xf);
+    match (family, model) {
+        // Golden Cove and later renamed the event, and merged the unit
+        // masks of some of their 12 ports: port 2 counts ports 2, 3 and
+        // 10, so port 3 has none of its own.
+        (6, 0x8f | 0x97 | 0x9a | 0xaa | 0xac | 0xad | 0xae | 0xb7 | 0xba | 0xbf | 0xcf) => {
+            (port != 3).then_some(((1 << port) << 8) | 0xb2)
+        }
+        (6, _) => Some(((1 << port) << 8) | 0xa1),
+        _ => None,
+    }
+}
+
+/// Other architectures have no per port counts that we know of.
+#[cfg(not(target_arch = "x86_64"))]
+fn uops_dispatched_event(_port: u64) -> Option<u64> {
+    None
+}
+
+/// A set of performance counters of the calling thread, counting user
+/// space only so that they work with `perf_event_paranoid` up to 2.
+/// Counters that can't be opened, say within a container or a VM
+/// without a PMU, are left out and read as `None`.
+pub struct PerfCounters {
+    counters: Vec<(Counter, Option<c_int>)>,
+}
+
+impl PerfCounters {
+    pub fn open(counters: &[Counter]) -> Self {
+        let counters = counters
+            .iter()
+            .map(|counter| (*counter, counter.event().and_then(Self::open_event)))
+            .collect();
+
+        Self { counters }
+    }
+
+    fn open_event((kind, config): (u32, u64)) -> Option<c_int> {
+        let attr = PerfEventAttr {
+            kind,
+            size: size_of::<PerfEventAttr>() as u32,
+            config,
+            flags: PERF_ATTR_FLAG_DISABLED
+                | PERF_ATTR_FLAG_EXCLUDE_KERNEL
+                | PERF_ATTR_FLAG_EXCLUDE_HV,
+            ..Default::default()
+        };
+
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                0,
+                -1,
+                -1,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+
+        (fd >= 0).then_some(fd as c_int)
+    }
+
+    pub fn counters(&self) -> impl Iterator<Item = Counter> + '_ {
+        self.counters.iter().map(|(counter, _)| *counter)
+    }
+
+    pub fn unavailable(&self) -> impl Iterator<Item = Counter> + '_ {
+        self.counters
+            .iter()
+            .filter(|(_, fd)| fd.is_none())
+            .map(|(counter, _)| *counter)
+    }
+
+    /// Reset and start all of the counters.
+    pub fn start(&self) {
+        for fd in self.counters.iter().filter_map(|(_, fd)| *fd) {
+            unsafe {
+                libc::ioctl(fd, PERF_EVENT_IOC_RESET, 0);
+                libc::ioctl(fd, PERF_EVENT_IOC_ENABLE, 0);
+            }
+        }
+    }
+
+    /// Stop all of the counters, and read them in the order they were
+    /// opened in.
+    pub fn stop(&self) -> Vec<Option<u64>> {
+        for fd in self.counters.iter().filter_map(|(_, fd)| *fd) {
+            unsafe {
+                libc::ioctl(fd, PERF_EVENT_IOC_DISABLE, 0);
+            }
+        }
+
+        self.counters
+            .iter()
+            .map(|(_, fd)| {
+                let fd = (*fd)?;
+                let mut value: u64 = 0;
+                let read = unsafe {
+                    libc::read(fd, &mut value as *mut u64 as *mut c_void, size_of::<u64>())
+                };
+
+                (read == size_of::<u64>() as isize).then_some(value)
+            })
+            .collect()
+    }
+}
+
+impl Drop for PerfCounters {
+    fn drop(&mut self) {
+        for fd in self.counters.iter().filter_map(|(_, fd)| *fd) {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+#[test]
+fn test_counters_fall_back() {
+    let counters = PerfCounters::open(&[Counter::Cycles, Counter::TaskClock, Counter::Port0]);
+
+    counters.start();
+    let sum: u64 = (0..100_000_u64).map(std::hint::black_box).sum();
+    let readings = counters.stop();
+    assert_eq!(sum, 4_999_950_000);
+
+    assert_eq!(readings.len(), 3);
+    let unavailable: Vec<Counter> = counters.unavailable().collect();
+    for (counter, reading) in counters.counters().zip(readings.iter()) {
+        assert_eq!(reading.is_none(), unavailable.contains(&counter));
+    }
+}
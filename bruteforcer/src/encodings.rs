@@ -1,39 +1,656 @@
-use std::fmt::Display;
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+use crate::{
+    args::{Feature, TargetModel},
+    features::FeatureSet,
+    instructions_x86_64::{Instruction, Operand, Register},
+    ir::{Op, Program, VReg},
+    isel::{self, select_vex, Selection},
+    targets::{IsaFamily, TargetDescription, VectorWidth},
+};
 
 /// Trait to encode objects to machine code.
 pub trait SerializeAMD64MachineCode {
     fn write_amd64_bytes(&self, bytes: &mut Vec<u8>);
 }
 
-/// The architectures that are supported by these encoders.
+/// The model code is generated for, along with the extensions the code
+/// may use, which can differ from those every CPU of the model has.
 #[derive(Debug, Clone, Copy)]
-pub enum Architecture {
-    Amd64,
-    Arm,
+pub struct Target {
+    pub model: TargetModel,
+    pub features: FeatureSet,
+}
+
+impl Target {
+    pub const fn new(model: TargetModel, features: FeatureSet) -> Self {
+        Self { model, features }
+    }
+
+    /// The model of the host, with all of the host's extensions.
+    pub fn host() -> Self {
+        let model = TargetModel::host().expect("no target model matches the host");
+        Self::new(model, model.host_features())
+    }
+
+    pub const fn description(&self) -> &'static TargetDescription {
+        self.model.description()
+    }
+
+    pub const fn family(&self) -> IsaFamily {
+        self.description().family
+    }
+
+    /// Whether the vector length of this target is only known at runtime,
+    /// which needs code planned by `optimize_to_vla_blocks`.
+    pub const fn is_vector_length_agnostic(&self) -> bool {
+        matches!(
+            self.description().vector_width,
+            VectorWidth::Scalable { .. }
+        )
+    }
+
+    /// Whether the code of this target can run on the host.
+    pub fn runs_on_host(&self) -> bool {
+        self.family() == IsaFamily::X86_64 && FeatureSet::host().contains(self.features)
+    }
+
+    /// Flags to pass to a C compiler for the intrinsics we generate.
+    pub fn c_flags(&self) -> Vec<&'static str> {
+        let mut flags = vec!["-O2"];
+        match self.model {
+            TargetModel::Armv8Neon => {}
+            TargetModel::Armv9Sve => flags.push("-march=armv9-a+sve2"),
+            TargetModel::Rv64gcv => flags.push("-march=rv64gcv"),
+            TargetModel::Wasm32Simd128 => flags.push("-msimd128"),
+            _ => flags.extend(self.features.iter().map(|feature| feature.c_flag())),
+        }
+        // Permutes of two ymm registers are the 256 bit forms of AVX-512
+        // instructions, which need AVX512VL as well.
+        if self.features.has(Feature::Avx512f) {
+            flags.push("-mavx512vl");
+        }
+        flags
+    }
+
+    /// Encode the return of a generated function. The upper halves of
+    /// the ymm registers are cleared first whenever they may have been
+    /// used, to keep the caller's SSE code from paying for them.
+    pub fn write_amd64_return(&self, bytes: &mut Vec<u8>) {
+        if self.features.has(Feature::Avx) {
+            Instruction::VZEROUPPER.write_amd64_bytes(bytes);
+        }
+        Instruction::RET.write_amd64_bytes(bytes);
+    }
+
+    /// Encode a lowered function along with its return, followed by the
+    /// constant pool of the index vectors it reads. The pool is aligned
+    /// to 32 bytes from the start of `bytes`, which has to be where the
+    /// code is mapped to a page.
+    pub fn write_amd64_function(&self, instrs: &[Instruction], bytes: &mut Vec<u8>) {
+        let mut pool = ConstantPool::default();
+        for instr in instrs.iter() {
+            instr.map_operands(|op| {
+                if let Operand::Constant(lanes) = op {
+                    pool.add(&lanes);
+                }
+                op
+            });
+        }
+
+        // Instructions are as long wherever the pool ends up, so it can
+        // be placed after encoding the function once.
+        let mut function = vec![0; bytes.len()];
+        let lay_out = |start: usize, function: &mut Vec<u8>| {
+            for instr in instrs.iter() {
+                instr
+                    .map_operands(|op| match op {
+                        Operand::Constant(lanes) => {
+                            Operand::RipRelative((start + 4 * pool.offset(&lanes)) as i32)
+                        }
+                        op => op,
+                    })
+                    .write_amd64_bytes(function);
+            }
+            self.write_amd64_return(function);
+        };
+        lay_out(0, &mut function);
+        let start = function.len().next_multiple_of(32);
+
+        lay_out(start, bytes);
+        // Pad with int3, nothing jumps past the return.
+        bytes.resize(start, 0xcc);
+        for lane in pool.lanes.iter() {
+            bytes.extend_from_slice(&lane.to_le_bytes());
+        }
+    }
+}
+
+/// The index vectors and masks a program reads, one after the other,
+/// each of them only once however often it is read.
+#[derive(Debug, Default)]
+pub struct ConstantPool {
+    pub lanes: Vec<u32>,
+    offsets: HashMap<Vec<u32>, usize>,
+}
+
+impl ConstantPool {
+    /// The lane the index vector `lanes` starts at within the pool,
+    /// adding it when it isn't in the pool yet.
+    pub fn add(&mut self, lanes: &[u32]) -> usize {
+        if let Some(offset) = self.offsets.get(lanes) {
+            return *offset;
+        }
+        let offset = self.lanes.len();
+        self.lanes.extend_from_slice(lanes);
+        self.offsets.insert(lanes.to_vec(), offset);
+        offset
+    }
+
+    /// The lane an index vector that was already added starts at.
+    fn offset(&self, lanes: &[u32]) -> usize {
+        self.offsets[lanes]
+    }
+
+    /// The C declaration of the pool, if there is anything in it.
+    pub fn c_declaration(&self) -> String {
+        if self.lanes.is_empty() {
+            return String::new();
+        }
+        format!(
+            "  static const int pool[] __attribute__((aligned(32))) = {{{}}};\n",
+            self.lanes.iter().map(|lane| *lane as i32).join(", ")
+        )
+    }
+}
+
+/// Trait to lower instruction blocks to the IR that every backend
+/// consumes. The loads and the stores of a block are lowered separately,
+/// so that the loads can be hoisted ahead of other stores when
+/// permuting a buffer in place.
+pub trait LowerIR {
+    /// Lower the loads of this block, returning the registers its lanes
+    /// were loaded into.
+    fn lower_ir_load(&self, program: &mut Program) -> Vec<VReg>;
+    /// Lower the permute and stores of this block, out of the registers
+    /// `lower_ir_load` returned.
+    fn lower_ir_store(&self, program: &mut Program, loaded: &[VReg]);
+}
+
+/// The C type of a register of `lanes` lanes.
+fn c_type(lanes: u32, target: Target) -> &'static str {
+    match (target.family(), lanes) {
+        (_, 1) => "float",
+        (IsaFamily::X86_64, 4) => "__m128",
+        (IsaFamily::X86_64, _) => "__m256",
+        (IsaFamily::Wasm32, _) => "v128_t",
+        _ => "float32x4_t",
+    }
 }
 
-impl Display for Architecture {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self {
-            Architecture::Amd64 => write!(f, "amd64"),
-            Architecture::Arm => write!(f, "arm"),
+/// Encode a program to C, with one string for each of the `blocks` its
+/// ops were lowered from. The program has to be legalized to the width
+/// of the target first. The index vectors and masks of x86-64 are put
+/// in `pool`, and loaded from it where they are read.
+pub fn encode_program_to_c(
+    program: &Program,
+    blocks: usize,
+    target: Target,
+    pool: &mut ConstantPool,
+) -> Vec<String> {
+    let mut code = vec![String::new(); blocks];
+    let gathered: Vec<VReg> = program
+        .ops
+        .iter()
+        .filter_map(|(_, op)| match op {
+            Op::Gather { indices, .. } => Some(*indices),
+            _ => None,
+        })
+        .collect();
+
+    for (block, op) in program.ops.iter() {
+        let c = &mut code[*block];
+        match *op {
+            Op::Load { dst, first } => {
+                let lanes = program.lanes(dst);
+                let load = match (target.family(), lanes) {
+                    (_, 1) => format!("in[{}]", first),
+                    (IsaFamily::X86_64, 4) => format!("_mm_loadu_ps(&in[{}])", first),
+                    (IsaFamily::X86_64, _) => format!("_mm256_loadu_ps(&in[{}])", first),
+                    (IsaFamily::Wasm32, _) => format!("wasm_v128_load(&in[{}])", first),
+                    _ => format!("vld1q_f32(&in[{}])", first),
+                };
+                *c += &format!("  {} {:?} = {};\n", c_type(lanes, target), dst, load);
+            }
+            Op::Constant { dst, ref lanes } if gathered.contains(&dst) => {
+                *c += &format!(
+                    "  __m256i {:?} = _mm256_load_si256((const __m256i *) &pool[{}]);\n",
+                    dst,
+                    pool.add(lanes)
+                );
+            }
+            Op::Constant { .. } => {}
+            Op::Shuffle { indices, .. } => {
+                let lanes = program.constant(indices);
+                *c += &encode_shuffle_to_c(program, op, lanes, target, pool);
+            }
+            Op::Blend { dst, select, .. } => {
+                let width = program.lanes(dst);
+                let lanes: Vec<u32> = (0..width)
+                    .map(|i| i + width * ((select >> i) & 1))
+                    .collect();
+                *c += &encode_shuffle_to_c(program, op, &lanes, target, pool);
+            }
+            Op::Gather { dst, indices } => {
+                *c += &format!(
+                    "  __m256 {:?} = _mm256_i32gather_ps(in, {:?}, 4);\n",
+                    dst, indices
+                );
+            }
+            Op::Store { src, first } => {
+                let lanes = program.lanes(src);
+                *c += &match (target.family(), lanes) {
+                    (_, 1) => format!("  out[{}] = {:?};\n", first, src),
+                    (IsaFamily::X86_64, 4) => {
+                        format!("  _mm_storeu_ps(&out[{}], {:?});\n", first, src)
+                    }
+                    (IsaFamily::X86_64, _) => {
+                        format!("  _mm256_storeu_ps(&out[{}], {:?});\n", first, src)
+                    }
+                    (IsaFamily::Wasm32, _) => {
+                        format!("  wasm_v128_store(&out[{}], {:?});\n", first, src)
+                    }
+                    _ => format!("  vst1q_f32(&out[{}], {:?});\n", first, src),
+                };
+            }
+            // Only x86-64 has masked stores. AVX-512 takes the mask as
+            // an immediate, which ends up in a mask register.
+            Op::MaskedStore { src, first, mask } if target.features.has(Feature::Avx512f) => {
+                let lanes = program.lanes(src);
+                *c += &format!(
+                    "  {}_mask_storeu_ps(&out[{}], {:#04x}, {:?});\n",
+                    if lanes == 4 { "_mm" } else { "_mm256" },
+                    first,
+                    mask,
+                    src
+                );
+            }
+            Op::MaskedStore { src, first, mask } => {
+                // Masks take up as much of the pool as index vectors do,
+                // which keeps every one of them aligned.
+                let lanes = program.lanes(src);
+                let offset = pool.add(&std::array::from_fn::<u32, 8, _>(|lane| {
+                    if mask & (1 << lane) != 0 {
+                        u32::MAX
+                    } else {
+                        0
+                    }
+                }));
+                *c += &if lanes == 4 {
+                    format!(
+                        "  _mm_maskstore_ps(&out[{}], _mm_load_si128((const __m128i *) &pool[{}]), {:?});\n",
+                        first, offset, src
+                    )
+                } else {
+                    format!(
+                        "  _mm256_maskstore_ps(&out[{}], _mm256_load_si256((const __m256i *) &pool[{}]), {:?});\n",
+                        first, offset, src
+                    )
+                };
+            }
+            // In place, the run it is copied to can overlap its own.
+            Op::Copy { from, first, len } => {
+                *c += &format!(
+                    "  {}(&out[{}], &in[{}], {} * sizeof(float));\n",
+                    if program.in_place {
+                        "memmove"
+                    } else {
+                        "memcpy"
+                    },
+                    first,
+                    from,
+                    len
+                );
+            }
         }
     }
+
+    code
 }
 
-impl TryFrom<String> for Architecture {
-    type Error = String;
+/// Encode a loop running a program `iterations` times, with the input
+/// and output moved `stride` values further along in each iteration.
+/// The program reads and writes where the first iteration does, and
+/// the pointers are moved back once the loop is done.
+pub fn encode_loop_to_c(
+    program: &Program,
+    blocks: usize,
+    iterations: u32,
+    stride: [i64; 2],
+    target: Target,
+    pool: &mut ConstantPool,
+) -> String {
+    let body: String = encode_program_to_c(program, blocks, target, pool)
+        .iter()
+        .flat_map(|code| code.lines())
+        .map(|line| format!("  {}\n", line))
+        .collect();
+    let [input, output] = stride;
+
+    format!(
+        "  for (int i = 0; i < {}; i++) {{
+{}    in += {};
+    out += {};
+  }}
+  in -= {};
+  out -= {};
+",
+        iterations,
+        body,
+        input,
+        output,
+        input * iterations as i64,
+        output * iterations as i64
+    )
+}
 
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        match value.as_str() {
-            "amd64" => return Ok(Self::Amd64),
-            "arm" => return Ok(Self::Arm),
-            _ => return Err("please provide a valid architecture".to_string()),
+/// A shuffle of two registers, where `lanes` index the lanes of the
+/// first followed by those of the second. x86-64 spells it as the
+/// intrinsics of the instructions selected for the op, NEON as a table
+/// lookup and wasm as its own shuffle intrinsic.
+fn encode_shuffle_to_c(
+    program: &Program,
+    op: &Op,
+    lanes: &[u32],
+    target: Target,
+    pool: &mut ConstantPool,
+) -> String {
+    let (Op::Shuffle {
+        dst, first, second, ..
+    }
+    | Op::Blend {
+        dst, first, second, ..
+    }) = *op
+    else {
+        unreachable!("only shuffles and blends are encoded as shuffles")
+    };
+    match target.family() {
+        IsaFamily::X86_64 => encode_selection_to_c(
+            &select_vex(op, program, target),
+            dst,
+            first,
+            second,
+            target,
+            pool,
+        ),
+        IsaFamily::Aarch64 => encode_table_lookup_to_c(dst, first, second, lanes),
+        _ => format!(
+            "  v128_t {:?} = wasm_i32x4_shuffle({:?}, {:?}, {});\n",
+            dst,
+            first,
+            second,
+            lanes.iter().join(", ")
+        ),
+    }
+}
+
+/// The instructions selected for a shuffle as one intrinsic each. The
+/// registers isel reads and writes are named after the registers of the
+/// op, its index vectors and temporaries after its result. Index vectors
+/// are loaded from `pool`.
+fn encode_selection_to_c(
+    selection: &Selection,
+    dst: VReg,
+    first: VReg,
+    second: VReg,
+    target: Target,
+    pool: &mut ConstantPool,
+) -> String {
+    let name = |op: Operand| -> String {
+        let Operand::Register(reg) = op else {
+            unreachable!("shuffles only read registers")
+        };
+        match reg.number() {
+            isel::FIRST => format!("{:?}", first),
+            isel::SECOND => format!("{:?}", second),
+            isel::RESULT => format!("{:?}", dst),
+            number => format!("{:?}_{}", dst, number),
         }
+    };
+
+    let mut c = String::new();
+    let mut declared = vec![format!("{:?}", first), format!("{:?}", second)];
+    for (number, lanes) in selection.constants.iter() {
+        let indices = name(Operand::Register(Register::ymm(*number)));
+        c += &format!(
+            "  __m256i {} = _mm256_load_si256((const __m256i *) &pool[{}]);\n",
+            indices,
+            pool.add(lanes)
+        );
+        declared.push(indices);
+    }
+
+    for instr in selection.instrs.iter() {
+        let (dst, intrinsic) = encode_intrinsic_to_c(instr, name, target);
+        let Operand::Register(reg) = dst else {
+            unreachable!("shuffles only write registers")
+        };
+        let dst = name(dst);
+        if declared.contains(&dst) {
+            c += &format!("  {} = {};\n", dst, intrinsic);
+        } else {
+            let ty = if reg.is_ymm() { "__m256" } else { "__m128" };
+            c += &format!("  {} {} = {};\n", ty, dst, intrinsic);
+            declared.push(dst);
+        }
+    }
+    c
+}
+
+/// The register an instruction writes along with the intrinsic that
+/// computes it, reading the registers named by `name`.
+fn encode_intrinsic_to_c(
+    instr: &Instruction,
+    name: impl Fn(Operand) -> String,
+    target: Target,
+) -> (Operand, String) {
+    let (prefix, bits) = match instr.registers().last() {
+        Some(reg) if reg.is_ymm() => ("_mm256", 256),
+        _ => ("_mm", 128),
+    };
+    let imm = |op: Operand| -> i32 {
+        let Operand::Immediate(imm) = op else {
+            unreachable!("{} has an immediate first", instr)
+        };
+        imm
+    };
+
+    match *instr {
+        Instruction::VMOVUPS(src, dst) => (dst, name(src)),
+        // Permutes of a single register are AVX, SSE shuffles the same
+        // register twice instead.
+        Instruction::VPERMILPS(sel, src, dst) if target.features.has(Feature::Avx) => (
+            dst,
+            format!("{}_permute_ps({}, {})", prefix, name(src), imm(sel)),
+        ),
+        Instruction::VPERMILPS(sel, src, dst) => (
+            dst,
+            format!("_mm_shuffle_ps({}, {}, {})", name(src), name(src), imm(sel)),
+        ),
+        Instruction::VSHUFPS(sel, y, x, dst) => (
+            dst,
+            format!("{}_shuffle_ps({}, {}, {})", prefix, name(x), name(y), imm(sel)),
+        ),
+        Instruction::VUNPCKLPS(y, x, dst) => (
+            dst,
+            format!("{}_unpacklo_ps({}, {})", prefix, name(x), name(y)),
+        ),
+        Instruction::VUNPCKHPS(y, x, dst) => (
+            dst,
+            format!("{}_unpackhi_ps({}, {})", prefix, name(x), name(y)),
+        ),
+        Instruction::VPALIGNR(shift, low, high, dst) => (
+            dst,
+            format!(
+                "{0}_castsi{1}_ps({0}_alignr_epi8({0}_castps_si{1}({2}), {0}_castps_si{1}({3}), {4}))",
+                prefix,
+                bits,
+                name(high),
+                name(low),
+                imm(shift)
+            ),
+        ),
+        Instruction::VBLENDPS(select, y, x, dst) => (
+            dst,
+            format!("{}_blend_ps({}, {}, {})", prefix, name(x), name(y), imm(select)),
+        ),
+        Instruction::VPERM2F128(halves, y, x, dst) => (
+            dst,
+            format!(
+                "_mm256_permute2f128_ps({}, {}, {})",
+                name(x),
+                name(y),
+                imm(halves)
+            ),
+        ),
+        Instruction::VPERMPS(src, indices, dst) => (
+            dst,
+            format!(
+                "_mm256_permutevar8x32_ps({}, {})",
+                name(src),
+                name(indices)
+            ),
+        ),
+        Instruction::VPERMT2PS(table, indices, dst) => (
+            dst,
+            format!(
+                "_mm256_permutex2var_ps({}, {}, {})",
+                name(dst),
+                name(indices),
+                name(table)
+            ),
+        ),
+        _ => unreachable!("{} is never selected for a shuffle", instr),
     }
 }
 
-/// Trait to encode instruction blocks to C code strings.
-pub trait CEncoder {
-    fn encode_to_c(&self, index: u32, arch: Architecture) -> String;
+/// A shuffle of two NEON registers as a lookup of the bytes of each
+/// lane in the table they make up together.
+fn encode_table_lookup_to_c(dst: VReg, first: VReg, second: VReg, lanes: &[u32]) -> String {
+    let bytes = lanes
+        .iter()
+        .flat_map(|lane| (0..4).map(move |byte| 4 * lane + byte))
+        .join(", ");
+    let table = format!(
+        "(uint8x16x2_t) {{{{vreinterpretq_u8_f32({:?}), vreinterpretq_u8_f32({:?})}}}}",
+        first, second
+    );
+    format!(
+        "  float32x4_t {:?} = vreinterpretq_f32_u8(vqtbl2q_u8({}, (uint8x16_t) {{{}}}));\n",
+        dst, table, bytes
+    )
+}
+
+#[test]
+fn test_constant_pool() {
+    let (reversed, rotated) = ([7, 6, 5, 4, 3, 2, 1, 0], [1, 2, 3, 4, 5, 6, 7, 0]);
+    let instrs: Vec<Instruction> = [reversed, rotated, reversed]
+        .iter()
+        .enumerate()
+        .map(|(i, lanes)| {
+            Instruction::VMOVDQU(
+                Operand::Constant(*lanes),
+                Operand::Register(Register::ymm(i as u8)),
+            )
+        })
+        .collect();
+
+    let mut bytes = vec![];
+    let model = TargetModel::X86_64V3;
+    Target::new(model, model.features()).write_amd64_function(&instrs, &mut bytes);
+    let pool: Vec<u8> = reversed
+        .iter()
+        .chain(rotated.iter())
+        .flat_map(|lane: &u32| lane.to_le_bytes())
+        .collect();
+    assert_eq!(bytes.len() % 32, 0);
+    assert!(bytes.ends_with(&pool));
+}
+
+#[test]
+fn test_x86_shuffles_to_c_intrinsics() {
+    use crate::{optimize::ShiftMask, verify::generate_c_blocks};
+
+    let mask = ShiftMask::from(vec![1, 0, 3, 2, 11, 10, 9, 8, 7, 6, 5, 4]);
+    let code = |model: TargetModel| -> String {
+        generate_c_blocks(&mask, Target::new(model, model.features()), false, None, 0)
+            .iter()
+            .map(|block| block.code.clone())
+            .collect()
+    };
+
+    let avx2 = code(TargetModel::X86_64V3);
+    assert!(avx2.contains("_mm256_permute2f128_ps("));
+    assert!(avx2.contains("_mm256_permute_ps("));
+    // Without AVX a register is shuffled with itself.
+    let sse = code(TargetModel::X86_64V2);
+    assert!(sse.contains("_mm_shuffle_ps(v0, v0, 177)"));
+    assert!(!avx2.contains("__builtin_shufflevector") && !sse.contains("__builtin_shufflevector"));
+}
+
+#[test]
+fn test_c_index_vectors_in_pool() {
+    use crate::{optimize::ShiftMask, verify::generate_c_blocks};
+
+    let mask = ShiftMask::from(vec![7, 0, 6, 1, 5, 2, 4, 3, 15, 8, 14, 9, 13, 10, 12, 11]);
+    let model = TargetModel::X86_64V3;
+    let blocks = generate_c_blocks(&mask, Target::new(model, model.features()), false, None, 0);
+    assert_eq!(
+        blocks[0].code,
+        "  static const int pool[] __attribute__((aligned(32))) = {1, 3, 5, 7, 6, 4, 2, 0};\n"
+    );
+    // Both blocks permute the same way, so they load the same lanes.
+    assert!(blocks[1..]
+        .iter()
+        .all(|block| block.code.contains("&pool[0]")));
+}
+
+#[test]
+fn test_wasm_shuffles_to_c() {
+    use crate::{optimize::ShiftMask, verify::generate_c_blocks};
+
+    let mask = ShiftMask::from(vec![1, 0, 3, 2, 6, 7, 4, 5]);
+    let model = TargetModel::Wasm32Simd128;
+    let code: String =
+        generate_c_blocks(&mask, Target::new(model, model.features()), false, None, 0)
+            .iter()
+            .map(|block| block.code.clone())
+            .collect();
+    assert!(code.contains("wasm_i32x4_shuffle("));
+    assert!(!code.contains("__builtin_shufflevector") && !code.contains("_mm"));
+}
+
+#[test]
+fn test_copy_to_c() {
+    // In place, the run copied to overlaps the one copied from.
+    for (in_place, function) in [(false, "memcpy"), (true, "memmove")] {
+        let mut program = Program::new(in_place);
+        program.copy(0, 1, 16);
+        let model = TargetModel::X86_64V3;
+        let code = encode_program_to_c(
+            &program,
+            1,
+            Target::new(model, model.features()),
+            &mut ConstantPool::default(),
+        );
+        assert_eq!(
+            code,
+            vec![format!(
+                "  {}(&out[1], &in[0], 16 * sizeof(float));\n",
+                function
+            )]
+        );
+    }
 }
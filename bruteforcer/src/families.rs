@@ -0,0 +1,175 @@
+use crate::optimize::ShiftMask;
+
+/// Constructors for the named permutation families used by FFT and
+/// matrix code. Each one maps element `i` of the input to position
+/// `values[i]` of the output, like every other `ShiftMask`.
+impl ShiftMask {
+    /// Reverse the whole array.
+    pub fn new_reverse(len: u32) -> Self {
+        (0..len).map(|i| len - 1 - i).collect::<Vec<u32>>().into()
+    }
+
+    /// Rotate the array right by `k` positions.
+    pub fn new_rotate(len: u32, k: u32) -> Result<Self, String> {
+        if len == 0 {
+            return Err("cannot rotate an empty pattern".to_string());
+        }
+
+        Ok((0..len)
+            .map(|i| (i + k % len) % len)
+            .collect::<Vec<u32>>()
+            .into())
+    }
+
+    /// Reverse the order of the blocks of `block` elements, keeping the
+    /// order of the elements within each block.
+    pub fn new_block_reverse(len: u32, block: u32) -> Result<Self, String> {
+        if block == 0 || !len.is_multiple_of(block) {
+            return Err("block size must evenly divide the pattern length".to_string());
+        }
+
+        let blocks = len / block;
+        Ok((0..len)
+            .map(|i| (blocks - 1 - i / block) * block + i % block)
+            .collect::<Vec<u32>>()
+            .into())
+    }
+
+    /// Transpose a row major `rows` by `cols` matrix.
+    pub fn new_transpose(rows: u32, cols: u32) -> Self {
+        (0..rows * cols)
+            .map(|i| (i % cols) * rows + i / cols)
+            .collect::<Vec<u32>>()
+            .into()
+    }
+
+    /// Move each element to the position with its index bits reversed.
+    pub fn new_bit_reversal(len: u32) -> Result<Self, String> {
+        let bits = Self::log2(len)?;
+
+        Ok((0..len)
+            .map(|i| i.reverse_bits().checked_shr(32 - bits).unwrap_or(0))
+            .collect::<Vec<u32>>()
+            .into())
+    }
+
+    /// Interleave the first half of the array with the second half.
+    pub fn new_perfect_shuffle(len: u32) -> Result<Self, String> {
+        if !len.is_multiple_of(2) {
+            return Err("a perfect shuffle needs an even pattern length".to_string());
+        }
+
+        let half = len / 2;
+        Ok((0..len)
+            .map(|i| if i < half { 2 * i } else { 2 * (i - half) + 1 })
+            .collect::<Vec<u32>>()
+            .into())
+    }
+
+    /// Split the even and odd positions into the first and second half,
+    /// the inverse of a perfect shuffle.
+    pub fn new_perfect_unshuffle(len: u32) -> Result<Self, String> {
+        if !len.is_multiple_of(2) {
+            return Err("a perfect unshuffle needs an even pattern length".to_string());
+        }
+
+        let half = len / 2;
+        Ok((0..len)
+            .map(|i| if i % 2 == 0 { i / 2 } else { half + i / 2 })
+            .collect::<Vec<u32>>()
+            .into())
+    }
+
+    /// Gather every `stride`th element, starting with those at offset 0,
+    /// then those at offset 1, and so on.
+    pub fn new_stride_gather(len: u32, stride: u32) -> Result<Self, String> {
+        if stride == 0 {
+            return Err("stride must be nonzero".to_string());
+        }
+
+        // Number of elements at each offset that come before offset r.
+        let before = |r: u32| (0..r).map(|o| (len - o).div_ceil(stride)).sum::<u32>();
+        let offsets: Vec<u32> = (0..stride.min(len)).map(before).collect();
+
+        Ok((0..len)
+            .map(|i| offsets[(i % stride) as usize] + i / stride)
+            .collect::<Vec<u32>>()
+            .into())
+    }
+
+    /// The butterfly permutation of an FFT stage, which swaps bit zero
+    /// of each index with bit `stage`.
+    pub fn new_butterfly(len: u32, stage: u32) -> Result<Self, String> {
+        let bits = Self::log2(len)?;
+        if stage >= bits.max(1) {
+            return Err(format!("butterfly stage must be below {}", bits.max(1)));
+        }
+
+        Ok((0..len)
+            .map(|i| {
+                let low = i & 1;
+                let high = (i >> stage) & 1;
+                (i & !(1 | (1 << stage))) | (low << stage) | high
+            })
+            .collect::<Vec<u32>>()
+            .into())
+    }
+
+    fn log2(len: u32) -> Result<u32, String> {
+        if !len.is_power_of_two() {
+            return Err("pattern length must be a power of two".to_string());
+        }
+
+        Ok(len.trailing_zeros())
+    }
+}
+
+#[test]
+fn test_families_are_permutations() {
+    let masks = [
+        ShiftMask::new_reverse(100),
+        ShiftMask::new_rotate(100, 7).unwrap(),
+        ShiftMask::new_block_reverse(96, 8).unwrap(),
+        ShiftMask::new_transpose(12, 9),
+        ShiftMask::new_bit_reversal(128).unwrap(),
+        ShiftMask::new_perfect_shuffle(100).unwrap(),
+        ShiftMask::new_perfect_unshuffle(100).unwrap(),
+        ShiftMask::new_stride_gather(100, 7).unwrap(),
+        ShiftMask::new_butterfly(128, 4).unwrap(),
+    ];
+
+    for mask in masks.iter() {
+        let mut sorted = mask.values().to_vec();
+        sorted.sort();
+        assert_eq!(sorted, (0..mask.len() as u32).collect::<Vec<u32>>());
+    }
+}
+
+#[test]
+fn test_families() {
+    assert_eq!(
+        ShiftMask::new_rotate(5, 2).unwrap().values(),
+        [2, 3, 4, 0, 1]
+    );
+    assert_eq!(ShiftMask::new_transpose(2, 3).values(), [0, 2, 4, 1, 3, 5]);
+    assert_eq!(
+        ShiftMask::new_bit_reversal(8).unwrap().values(),
+        [0, 4, 2, 6, 1, 5, 3, 7]
+    );
+    assert_eq!(
+        ShiftMask::new_perfect_shuffle(6).unwrap().values(),
+        [0, 2, 4, 1, 3, 5]
+    );
+    assert_eq!(
+        ShiftMask::new_stride_gather(7, 3).unwrap().values(),
+        [0, 3, 5, 1, 4, 6, 2]
+    );
+    assert_eq!(
+        ShiftMask::new_butterfly(8, 2).unwrap().values(),
+        [0, 4, 2, 6, 1, 5, 3, 7]
+    );
+
+    // Reversals self permute within every window, so are fully SIMDized.
+    let blocks = ShiftMask::new_reverse(64).optimize_to_blocks(255);
+    assert!(blocks.iter().all(|blk| blk.len() == 8));
+}
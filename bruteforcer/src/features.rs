@@ -0,0 +1,145 @@
+use std::fmt::Display;
+
+use clap::ValueEnum;
+
+use crate::args::Feature;
+
+/// A set of instruction set extensions that generated code may use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureSet(u32);
+
+impl Feature {
+    /// The extensions every CPU with this one also has, which the
+    /// compilers enable along with it.
+    const fn implies(&self) -> &'static [Feature] {
+        match self {
+            Feature::Sse2 => &[],
+            Feature::Ssse3 => &[Feature::Sse2],
+            Feature::Sse41 => &[Feature::Ssse3, Feature::Sse2],
+            Feature::Avx => &[Feature::Sse41, Feature::Ssse3, Feature::Sse2],
+            Feature::Avx2 => &[Feature::Avx, Feature::Sse41, Feature::Ssse3, Feature::Sse2],
+            Feature::Avx512f => &[
+                Feature::Avx2,
+                Feature::Avx,
+                Feature::Sse41,
+                Feature::Ssse3,
+                Feature::Sse2,
+            ],
+            Feature::Avx512vbmi => &[
+                Feature::Avx512f,
+                Feature::Avx2,
+                Feature::Avx,
+                Feature::Sse41,
+                Feature::Ssse3,
+                Feature::Sse2,
+            ],
+        }
+    }
+
+    /// Flag that enables this extension in gcc and clang.
+    pub const fn c_flag(&self) -> &'static str {
+        match self {
+            Feature::Sse2 => "-msse2",
+            Feature::Ssse3 => "-mssse3",
+            Feature::Sse41 => "-msse4.1",
+            Feature::Avx => "-mavx",
+            Feature::Avx2 => "-mavx2",
+            Feature::Avx512f => "-mavx512f",
+            Feature::Avx512vbmi => "-mavx512vbmi",
+        }
+    }
+}
+
+impl FeatureSet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// The extensions of the CPU we are running on, as reported by
+    /// CPUID and enabled by the OS in XCR0.
+    #[cfg(target_arch = "x86_64")]
+    pub fn host() -> Self {
+        let detected = [
+            (Feature::Sse2, is_x86_feature_detected!("sse2")),
+            (Feature::Ssse3, is_x86_feature_detected!("ssse3")),
+            (Feature::Sse41, is_x86_feature_detected!("sse4.1")),
+            (Feature::Avx, is_x86_feature_detected!("avx")),
+            (Feature::Avx2, is_x86_feature_detected!("avx2")),
+            (Feature::Avx512f, is_x86_feature_detected!("avx512f")),
+            (Feature::Avx512vbmi, is_x86_feature_detected!("avx512vbmi")),
+        ];
+
+        detected
+            .iter()
+            .filter(|(_, detected)| *detected)
+            .fold(Self::empty(), |set, (feature, _)| set.with(*feature))
+    }
+
+    /// None of the extensions are amd64 ones on other architectures.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn host() -> Self {
+        Self::empty()
+    }
+
+    pub const fn with(self, feature: Feature) -> Self {
+        Self(self.0 | (1 << feature as u32))
+    }
+
+    pub const fn has(&self, feature: Feature) -> bool {
+        self.0 & (1 << feature as u32) != 0
+    }
+
+    pub const fn contains(&self, other: FeatureSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + '_ {
+        Feature::value_variants()
+            .iter()
+            .copied()
+            .filter(|feature| self.has(*feature))
+    }
+
+    /// The features of `other` that are missing from this set.
+    pub const fn missing(&self, other: FeatureSet) -> FeatureSet {
+        Self(other.0 & !self.0)
+    }
+}
+
+impl From<&[Feature]> for FeatureSet {
+    /// Collect features along with everything they imply, so that
+    /// `--target-features avx2` is enough to get AVX code as well.
+    fn from(features: &[Feature]) -> Self {
+        features.iter().fold(Self::empty(), |set, feature| {
+            feature
+                .implies()
+                .iter()
+                .fold(set.with(*feature), |set, implied| set.with(*implied))
+        })
+    }
+}
+
+impl Display for FeatureSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<String> = self.iter().map(|feature| feature.to_string()).collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+#[test]
+fn test_feature_set() {
+    let avx2 = FeatureSet::from(&[Feature::Avx2][..]);
+    assert!(avx2.has(Feature::Avx) && avx2.has(Feature::Sse2));
+    assert!(!avx2.has(Feature::Avx512f));
+    assert_eq!(avx2.to_string(), "sse2,ssse3,sse4.1,avx,avx2");
+
+    let avx = FeatureSet::from(&[Feature::Avx][..]);
+    assert!(avx2.contains(avx));
+    assert!(!avx.contains(avx2));
+    assert_eq!(avx.missing(avx2), FeatureSet::empty().with(Feature::Avx2));
+    assert_eq!(FeatureSet::empty().to_string(), "none");
+}
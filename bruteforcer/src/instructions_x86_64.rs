@@ -7,34 +7,489 @@ use crate::encodings::SerializeAMD64MachineCode;
 /// Enumeration of the available X86_64 Instructions that we
 /// are utilizing for generating output programs. This is not
 /// a complete list of all instructions by ANY means.
+///
+/// Operands are given in AT&T order, ie sources first and the
+/// destination last.
 #[allow(unused)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     MOV(Operand, Operand),
     MOVQ(Operand, Operand),
     MOVL(Operand, Operand),
     XOR(Operand, Operand),
+    ADD(Operand, Operand),
+    SUB(Operand, Operand),
     RET,
+    /// Legacy SSE encodings, for targets without VEX. Those of two
+    /// sources overwrite the first source, their destination.
+    MOVUPS(Operand, Operand),
+    PSHUFD(Operand, Operand, Operand),
+    SHUFPS(Operand, Operand, Operand),
+    UNPCKLPS(Operand, Operand),
+    UNPCKHPS(Operand, Operand),
+    PALIGNR(Operand, Operand, Operand),
+    BLENDPS(Operand, Operand, Operand),
     VPERMPS(Operand, Operand, Operand),
     VPERMILPS(Operand, Operand, Operand),
     VPERMD(Operand, Operand, Operand),
+    VPERMT2PS(Operand, Operand, Operand),
+    VPERM2F128(Operand, Operand, Operand, Operand),
+    VINSERTF128(Operand, Operand, Operand, Operand),
+    VPALIGNR(Operand, Operand, Operand, Operand),
+    VSHUFPS(Operand, Operand, Operand, Operand),
+    VBLENDPS(Operand, Operand, Operand, Operand),
+    VUNPCKLPS(Operand, Operand, Operand),
+    VUNPCKHPS(Operand, Operand, Operand),
+    VMOVLHPS(Operand, Operand, Operand),
+    VMOVHLPS(Operand, Operand, Operand),
+    VGATHERDPS(Operand, Operand, Operand),
+    VPCMPEQD(Operand, Operand, Operand),
     VPMASKMOVD(Operand, Operand, Operand),
+    VMASKMOVPS(Operand, Operand, Operand),
     VMOVDQA(Operand, Operand),
+    VMOVDQU(Operand, Operand),
+    VMOVUPS(Operand, Operand),
+    /// AVX-512 loads of a mask register, and stores of the lanes of a
+    /// register that a mask register selects, as (source, mask,
+    /// destination).
+    KMOVW(Operand, Operand),
+    VMOVUPSK(Operand, Operand, Operand),
     VZEROUPPER,
     RDTSC,
 }
 
+impl Instruction {
+    /// This instruction with `f` applied to each of its operands.
+    pub fn map_operands(&self, mut f: impl FnMut(Operand) -> Operand) -> Instruction {
+        match *self {
+            Instruction::MOV(a, b) => Instruction::MOV(f(a), f(b)),
+            Instruction::MOVQ(a, b) => Instruction::MOVQ(f(a), f(b)),
+            Instruction::MOVL(a, b) => Instruction::MOVL(f(a), f(b)),
+            Instruction::XOR(a, b) => Instruction::XOR(f(a), f(b)),
+            Instruction::ADD(a, b) => Instruction::ADD(f(a), f(b)),
+            Instruction::SUB(a, b) => Instruction::SUB(f(a), f(b)),
+            Instruction::MOVUPS(a, b) => Instruction::MOVUPS(f(a), f(b)),
+            Instruction::PSHUFD(a, b, c) => Instruction::PSHUFD(f(a), f(b), f(c)),
+            Instruction::SHUFPS(a, b, c) => Instruction::SHUFPS(f(a), f(b), f(c)),
+            Instruction::UNPCKLPS(a, b) => Instruction::UNPCKLPS(f(a), f(b)),
+            Instruction::UNPCKHPS(a, b) => Instruction::UNPCKHPS(f(a), f(b)),
+            Instruction::PALIGNR(a, b, c) => Instruction::PALIGNR(f(a), f(b), f(c)),
+            Instruction::BLENDPS(a, b, c) => Instruction::BLENDPS(f(a), f(b), f(c)),
+            Instruction::VPERMPS(a, b, c) => Instruction::VPERMPS(f(a), f(b), f(c)),
+            Instruction::VPERMILPS(a, b, c) => Instruction::VPERMILPS(f(a), f(b), f(c)),
+            Instruction::VPERMD(a, b, c) => Instruction::VPERMD(f(a), f(b), f(c)),
+            Instruction::VPERMT2PS(a, b, c) => Instruction::VPERMT2PS(f(a), f(b), f(c)),
+            Instruction::VPERM2F128(a, b, c, d) => Instruction::VPERM2F128(f(a), f(b), f(c), f(d)),
+            Instruction::VINSERTF128(a, b, c, d) => {
+                Instruction::VINSERTF128(f(a), f(b), f(c), f(d))
+            }
+            Instruction::VPALIGNR(a, b, c, d) => Instruction::VPALIGNR(f(a), f(b), f(c), f(d)),
+            Instruction::VSHUFPS(a, b, c, d) => Instruction::VSHUFPS(f(a), f(b), f(c), f(d)),
+            Instruction::VBLENDPS(a, b, c, d) => Instruction::VBLENDPS(f(a), f(b), f(c), f(d)),
+            Instruction::VUNPCKLPS(a, b, c) => Instruction::VUNPCKLPS(f(a), f(b), f(c)),
+            Instruction::VUNPCKHPS(a, b, c) => Instruction::VUNPCKHPS(f(a), f(b), f(c)),
+            Instruction::VMOVLHPS(a, b, c) => Instruction::VMOVLHPS(f(a), f(b), f(c)),
+            Instruction::VMOVHLPS(a, b, c) => Instruction::VMOVHLPS(f(a), f(b), f(c)),
+            Instruction::VGATHERDPS(a, b, c) => Instruction::VGATHERDPS(f(a), f(b), f(c)),
+            Instruction::VPCMPEQD(a, b, c) => Instruction::VPCMPEQD(f(a), f(b), f(c)),
+            Instruction::VPMASKMOVD(a, b, c) => Instruction::VPMASKMOVD(f(a), f(b), f(c)),
+            Instruction::VMASKMOVPS(a, b, c) => Instruction::VMASKMOVPS(f(a), f(b), f(c)),
+            Instruction::VMOVDQA(a, b) => Instruction::VMOVDQA(f(a), f(b)),
+            Instruction::VMOVDQU(a, b) => Instruction::VMOVDQU(f(a), f(b)),
+            Instruction::VMOVUPS(a, b) => Instruction::VMOVUPS(f(a), f(b)),
+            Instruction::KMOVW(a, b) => Instruction::KMOVW(f(a), f(b)),
+            Instruction::VMOVUPSK(a, b, c) => Instruction::VMOVUPSK(f(a), f(b), f(c)),
+            Instruction::RET | Instruction::VZEROUPPER | Instruction::RDTSC => *self,
+        }
+    }
+
+    /// Every register this instruction names, including those used to
+    /// address memory.
+    pub fn registers(&self) -> Vec<Register> {
+        let mut registers = vec![];
+        self.map_operands(|op| {
+            registers.extend(op.registers());
+            op
+        });
+        registers
+    }
+
+    /// This instruction with `f` applied to every register it names.
+    pub fn map_registers(&self, f: impl Fn(Register) -> Register) -> Instruction {
+        self.map_operands(|op| op.map_registers(&f))
+    }
+}
+
 impl SerializeAMD64MachineCode for Instruction {
     fn write_amd64_bytes(&self, program: &mut Vec<u8>) {
         match &self {
             Instruction::MOV(_, _) => todo!(),
             Instruction::MOVQ(_, _) => todo!(),
-            Instruction::MOVL(_, _) => todo!(),
+            Instruction::MOVL(src, dst) => match (src, dst) {
+                (Operand::Register(reg), Operand::Register(rm)) => {
+                    write_legacy(program, 0x89, reg.number(), &ModRM::Register(rm.number()))
+                }
+                (Operand::Register(reg), mem) => {
+                    write_legacy(program, 0x89, reg.number(), &ModRM::from_memory(mem))
+                }
+                (mem, Operand::Register(reg)) => {
+                    write_legacy(program, 0x8b, reg.number(), &ModRM::from_memory(mem))
+                }
+                (Operand::Immediate(imm), mem) => {
+                    write_legacy(program, 0xc7, 0, &ModRM::from_memory(mem));
+                    program.extend_from_slice(&imm.to_le_bytes());
+                }
+                _ => todo!(),
+            },
             Instruction::RET => program.push(0xc3),
             Instruction::XOR(_, _) => todo!(),
-            Instruction::VPERMPS(_, _, _) => todo!(),
-            Instruction::VPERMD(_, _, _) => todo!(),
+            Instruction::ADD(Operand::Immediate(imm), Operand::Register(dst)) => {
+                write_legacy_wide(program, 0x81, 0, &ModRM::Register(dst.number()));
+                program.extend_from_slice(&imm.to_le_bytes());
+            }
+            Instruction::ADD(_, _) => todo!(),
+            Instruction::SUB(Operand::Immediate(imm), Operand::Register(dst)) => {
+                write_legacy_wide(program, 0x81, 5, &ModRM::Register(dst.number()));
+                program.extend_from_slice(&imm.to_le_bytes());
+            }
+            Instruction::SUB(_, _) => todo!(),
+            Instruction::MOVUPS(src, dst) => match (src, dst) {
+                (src, Operand::Register(reg)) => write_sse(
+                    program,
+                    None,
+                    &[0x10],
+                    reg.number(),
+                    &ModRM::from_operand(src),
+                ),
+                (Operand::Register(reg), dst) => write_sse(
+                    program,
+                    None,
+                    &[0x11],
+                    reg.number(),
+                    &ModRM::from_memory(dst),
+                ),
+                _ => todo!(),
+            },
+            Instruction::PSHUFD(Operand::Immediate(imm), src, Operand::Register(dst)) => {
+                write_sse(
+                    program,
+                    Some(0x66),
+                    &[0x70],
+                    dst.number(),
+                    &ModRM::from_operand(src),
+                );
+                program.push(*imm as u8);
+            }
+            Instruction::PSHUFD(_, _, _) => todo!(),
+            Instruction::SHUFPS(Operand::Immediate(imm), src, Operand::Register(dst)) => {
+                write_sse(
+                    program,
+                    None,
+                    &[0xc6],
+                    dst.number(),
+                    &ModRM::from_operand(src),
+                );
+                program.push(*imm as u8);
+            }
+            Instruction::SHUFPS(_, _, _) => todo!(),
+            Instruction::UNPCKLPS(src, Operand::Register(dst)) => write_sse(
+                program,
+                None,
+                &[0x14],
+                dst.number(),
+                &ModRM::from_operand(src),
+            ),
+            Instruction::UNPCKLPS(_, _) => todo!(),
+            Instruction::UNPCKHPS(src, Operand::Register(dst)) => write_sse(
+                program,
+                None,
+                &[0x15],
+                dst.number(),
+                &ModRM::from_operand(src),
+            ),
+            Instruction::UNPCKHPS(_, _) => todo!(),
+            Instruction::PALIGNR(Operand::Immediate(imm), src, Operand::Register(dst)) => {
+                write_sse(
+                    program,
+                    Some(0x66),
+                    &[0x3a, 0x0f],
+                    dst.number(),
+                    &ModRM::from_operand(src),
+                );
+                program.push(*imm as u8);
+            }
+            Instruction::PALIGNR(_, _, _) => todo!(),
+            Instruction::BLENDPS(Operand::Immediate(imm), src, Operand::Register(dst)) => {
+                write_sse(
+                    program,
+                    Some(0x66),
+                    &[0x3a, 0x0c],
+                    dst.number(),
+                    &ModRM::from_operand(src),
+                );
+                program.push(*imm as u8);
+            }
+            Instruction::BLENDPS(_, _, _) => todo!(),
+            Instruction::VPERMPS(src, idx, dst) => write_vex_rvm(
+                program,
+                0x16,
+                VexMap::M0F38,
+                VexPrefix::P66,
+                false,
+                src,
+                idx,
+                dst,
+            ),
+            Instruction::VPERMD(src, idx, dst) => write_vex_rvm(
+                program,
+                0x36,
+                VexMap::M0F38,
+                VexPrefix::P66,
+                false,
+                src,
+                idx,
+                dst,
+            ),
+            // The second table is the r/m operand, the index vector
+            // the vvvv one and the first table is overwritten.
+            Instruction::VPERMT2PS(table, idx, dst) => {
+                let (Operand::Register(idx), Operand::Register(dst)) = (idx, dst) else {
+                    todo!()
+                };
+                let rm = ModRM::from_operand(table);
+                write_evex(
+                    program,
+                    VexMap::M0F38,
+                    VexPrefix::P66,
+                    dst.is_ymm(),
+                    dst.number(),
+                    idx.number(),
+                    0,
+                    &rm,
+                );
+                program.push(0x7f);
+                rm.write(program, dst.number());
+            }
+            Instruction::VPERM2F128(Operand::Immediate(imm), src2, src1, dst) => {
+                write_vex_rvm(
+                    program,
+                    0x06,
+                    VexMap::M0F3A,
+                    VexPrefix::P66,
+                    false,
+                    src2,
+                    src1,
+                    dst,
+                );
+                program.push(*imm as u8);
+            }
+            Instruction::VPERM2F128(_, _, _, _) => todo!(),
+            Instruction::VINSERTF128(Operand::Immediate(imm), src2, src1, dst) => {
+                write_vex_rvm(
+                    program,
+                    0x18,
+                    VexMap::M0F3A,
+                    VexPrefix::P66,
+                    false,
+                    src2,
+                    src1,
+                    dst,
+                );
+                program.push(*imm as u8);
+            }
+            Instruction::VINSERTF128(_, _, _, _) => todo!(),
+            Instruction::VPALIGNR(Operand::Immediate(imm), low, high, dst) => {
+                write_vex_rvm(
+                    program,
+                    0x0f,
+                    VexMap::M0F3A,
+                    VexPrefix::P66,
+                    false,
+                    low,
+                    high,
+                    dst,
+                );
+                program.push(*imm as u8);
+            }
+            Instruction::VPALIGNR(_, _, _, _) => todo!(),
+            Instruction::VSHUFPS(Operand::Immediate(imm), src2, src1, dst) => {
+                write_vex_rvm(
+                    program,
+                    0xc6,
+                    VexMap::M0F,
+                    VexPrefix::None,
+                    false,
+                    src2,
+                    src1,
+                    dst,
+                );
+                program.push(*imm as u8);
+            }
+            Instruction::VSHUFPS(_, _, _, _) => todo!(),
+            Instruction::VBLENDPS(Operand::Immediate(imm), src2, src1, dst) => {
+                write_vex_rvm(
+                    program,
+                    0x0c,
+                    VexMap::M0F3A,
+                    VexPrefix::P66,
+                    false,
+                    src2,
+                    src1,
+                    dst,
+                );
+                program.push(*imm as u8);
+            }
+            Instruction::VBLENDPS(_, _, _, _) => todo!(),
+            Instruction::VUNPCKLPS(src2, src1, dst) => write_vex_rvm(
+                program,
+                0x14,
+                VexMap::M0F,
+                VexPrefix::None,
+                false,
+                src2,
+                src1,
+                dst,
+            ),
+            Instruction::VUNPCKHPS(src2, src1, dst) => write_vex_rvm(
+                program,
+                0x15,
+                VexMap::M0F,
+                VexPrefix::None,
+                false,
+                src2,
+                src1,
+                dst,
+            ),
+            Instruction::VMOVLHPS(src2, src1, dst) => write_vex_rvm(
+                program,
+                0x16,
+                VexMap::M0F,
+                VexPrefix::None,
+                false,
+                src2,
+                src1,
+                dst,
+            ),
+            Instruction::VMOVHLPS(src2, src1, dst) => write_vex_rvm(
+                program,
+                0x12,
+                VexMap::M0F,
+                VexPrefix::None,
+                false,
+                src2,
+                src1,
+                dst,
+            ),
+            Instruction::VGATHERDPS(mask, src, dst) => write_vex_rvm(
+                program,
+                0x92,
+                VexMap::M0F38,
+                VexPrefix::P66,
+                false,
+                src,
+                mask,
+                dst,
+            ),
+            Instruction::VPCMPEQD(src2, src1, dst) => write_vex_rvm(
+                program,
+                0x76,
+                VexMap::M0F,
+                VexPrefix::P66,
+                false,
+                src2,
+                src1,
+                dst,
+            ),
             Instruction::VPMASKMOVD(_, _, _) => todo!(),
+            // Loads and stores have their own opcodes, the mask is always
+            // the vvvv operand.
+            Instruction::VMASKMOVPS(src, mask, dst) => match (src, dst) {
+                (src, Operand::Register(_)) => write_vex_rvm(
+                    program,
+                    0x2c,
+                    VexMap::M0F38,
+                    VexPrefix::P66,
+                    false,
+                    src,
+                    mask,
+                    dst,
+                ),
+                (Operand::Register(src), dst) => {
+                    let Operand::Register(mask) = mask else {
+                        todo!()
+                    };
+                    let rm = ModRM::from_memory(dst);
+                    write_vex(
+                        program,
+                        VexMap::M0F38,
+                        VexPrefix::P66,
+                        false,
+                        src.is_ymm(),
+                        src.number(),
+                        mask.number(),
+                        &rm,
+                    );
+                    program.push(0x2e);
+                    rm.write(program, src.number());
+                }
+                _ => todo!(),
+            },
             Instruction::VMOVDQA(_, _) => todo!(),
+            Instruction::VMOVDQU(src, dst) => {
+                write_vex_move(program, 0x6f, 0x7f, VexPrefix::PF3, src, dst)
+            }
+            Instruction::VMOVUPS(src, dst) => {
+                write_vex_move(program, 0x10, 0x11, VexPrefix::None, src, dst)
+            }
+            Instruction::KMOVW(src, Operand::Register(dst)) => {
+                let rm = ModRM::from_operand(src);
+                write_vex(
+                    program,
+                    VexMap::M0F,
+                    VexPrefix::None,
+                    false,
+                    false,
+                    dst.number(),
+                    0,
+                    &rm,
+                );
+                program.push(0x90);
+                rm.write(program, dst.number());
+            }
+            Instruction::KMOVW(_, _) => todo!(),
+            Instruction::VMOVUPSK(Operand::Register(src), Operand::Register(mask), dst) => {
+                let rm = ModRM::from_memory(dst);
+                write_evex(
+                    program,
+                    VexMap::M0F,
+                    VexPrefix::None,
+                    src.is_ymm(),
+                    src.number(),
+                    0,
+                    mask.number(),
+                    &rm,
+                );
+                program.push(0x11);
+                rm.write(program, src.number());
+            }
+            Instruction::VMOVUPSK(_, _, _) => todo!(),
+            Instruction::VPERMILPS(Operand::Immediate(imm), src, Operand::Register(dst)) => {
+                let rm = ModRM::from_operand(src);
+                write_vex(
+                    program,
+                    VexMap::M0F3A,
+                    VexPrefix::P66,
+                    false,
+                    dst.is_ymm(),
+                    dst.number(),
+                    0,
+                    &rm,
+                );
+                program.push(0x04);
+                rm.write(program, dst.number());
+                program.push(*imm as u8);
+            }
             Instruction::VPERMILPS(_, _, _) => todo!(),
             Instruction::VZEROUPPER => {
                 program.push(0xc5);
@@ -57,16 +512,70 @@ impl Display for Instruction {
             Instruction::MOVL(src, dst) => write!(f, "movl {} {}", src, dst),
             Instruction::RET => write!(f, "ret"),
             Instruction::XOR(src, dst) => write!(f, "xor {} {}", src, dst),
+            Instruction::ADD(src, dst) => write!(f, "add {} {}", src, dst),
+            Instruction::SUB(src, dst) => write!(f, "sub {} {}", src, dst),
+            Instruction::MOVUPS(src, dst) => write!(f, "movups {} {}", src, dst),
+            Instruction::PSHUFD(imm, src, dst) => write!(f, "pshufd {} {} {}", imm, src, dst),
+            Instruction::SHUFPS(imm, src, dst) => write!(f, "shufps {} {} {}", imm, src, dst),
+            Instruction::UNPCKLPS(src, dst) => write!(f, "unpcklps {} {}", src, dst),
+            Instruction::UNPCKHPS(src, dst) => write!(f, "unpckhps {} {}", src, dst),
+            Instruction::PALIGNR(imm, src, dst) => write!(f, "palignr {} {} {}", imm, src, dst),
+            Instruction::BLENDPS(imm, src, dst) => write!(f, "blendps {} {} {}", imm, src, dst),
             Instruction::VPERMPS(reg1, reg2, reg3) => {
                 write!(f, "vpermps {} {} {}", reg1, reg2, reg3)
             }
             Instruction::VPERMD(reg1, reg2, reg3) => write!(f, "vpermd {} {} {}", reg1, reg2, reg3),
+            Instruction::VPERMT2PS(reg1, reg2, reg3) => {
+                write!(f, "vpermt2ps {} {} {}", reg1, reg2, reg3)
+            }
+            Instruction::VPERM2F128(imm, reg1, reg2, reg3) => {
+                write!(f, "vperm2f128 {} {} {} {}", imm, reg1, reg2, reg3)
+            }
+            Instruction::VINSERTF128(imm, reg1, reg2, reg3) => {
+                write!(f, "vinsertf128 {} {} {} {}", imm, reg1, reg2, reg3)
+            }
+            Instruction::VPALIGNR(imm, reg1, reg2, reg3) => {
+                write!(f, "vpalignr {} {} {} {}", imm, reg1, reg2, reg3)
+            }
+            Instruction::VSHUFPS(imm, reg1, reg2, reg3) => {
+                write!(f, "vshufps {} {} {} {}", imm, reg1, reg2, reg3)
+            }
+            Instruction::VBLENDPS(imm, reg1, reg2, reg3) => {
+                write!(f, "vblendps {} {} {} {}", imm, reg1, reg2, reg3)
+            }
+            Instruction::VUNPCKLPS(reg1, reg2, reg3) => {
+                write!(f, "vunpcklps {} {} {}", reg1, reg2, reg3)
+            }
+            Instruction::VUNPCKHPS(reg1, reg2, reg3) => {
+                write!(f, "vunpckhps {} {} {}", reg1, reg2, reg3)
+            }
+            Instruction::VMOVLHPS(reg1, reg2, reg3) => {
+                write!(f, "vmovlhps {} {} {}", reg1, reg2, reg3)
+            }
+            Instruction::VMOVHLPS(reg1, reg2, reg3) => {
+                write!(f, "vmovhlps {} {} {}", reg1, reg2, reg3)
+            }
+            Instruction::VGATHERDPS(mask, src, dst) => {
+                write!(f, "vgatherdps {} {} {}", mask, src, dst)
+            }
+            Instruction::VPCMPEQD(reg1, reg2, reg3) => {
+                write!(f, "vpcmpeqd {} {} {}", reg1, reg2, reg3)
+            }
             Instruction::VPMASKMOVD(reg1, reg2, reg3) => {
                 write!(f, "vpmaskmovd {} {} {}", reg1, reg2, reg3)
             }
+            Instruction::VMASKMOVPS(src, mask, dst) => {
+                write!(f, "vmaskmovps {} {} {}", src, mask, dst)
+            }
             Instruction::VMOVDQA(src, dst) => write!(f, "vmovdqa {} {}", src, dst),
-            Instruction::VPERMILPS(src, dst, mask) => {
-                write!(f, "vpermilps {} {} {}", src, dst, mask)
+            Instruction::VMOVDQU(src, dst) => write!(f, "vmovdqu {} {}", src, dst),
+            Instruction::VMOVUPS(src, dst) => write!(f, "vmovups {} {}", src, dst),
+            Instruction::KMOVW(src, dst) => write!(f, "kmovw {} {}", src, dst),
+            Instruction::VMOVUPSK(src, mask, dst) => {
+                write!(f, "vmovups {} {}{{{}}}", src, dst, mask)
+            }
+            Instruction::VPERMILPS(mask, src, dst) => {
+                write!(f, "vpermilps {} {} {}", mask, src, dst)
             }
             Instruction::VZEROUPPER => write!(f, "vzeroupper"),
             Instruction::RDTSC => write!(f, "rdtsc"),
@@ -74,10 +583,243 @@ impl Display for Instruction {
     }
 }
 
+/// The r/m half of a ModRM byte, either a register or a memory
+/// reference relative to a base register, optionally with a scaled
+/// index register (which is a vector register for gathers).
+enum ModRM {
+    Register(u8),
+    Displaced(u8, i32),
+    Indexed(u8, u8, i32),
+    RipRelative(i32),
+}
+
+impl ModRM {
+    fn from_memory(op: &Operand) -> Self {
+        match op {
+            Operand::Memory(base) => ModRM::Displaced(base.number(), 0),
+            Operand::Displaced(displ, base) => ModRM::Displaced(base.number(), *displ),
+            Operand::ScaledIndex(base, index, scale) => {
+                ModRM::Indexed(base.number(), index.number(), *scale)
+            }
+            Operand::RipRelative(offset) => ModRM::RipRelative(*offset),
+            _ => todo!(),
+        }
+    }
+
+    fn from_operand(op: &Operand) -> Self {
+        match op {
+            Operand::Register(reg) => ModRM::Register(reg.number()),
+            mem => ModRM::from_memory(mem),
+        }
+    }
+
+    /// The high bit of the r/m register, which lives in REX.B/VEX.B.
+    fn extension(&self) -> u8 {
+        match self {
+            ModRM::Register(rm) => rm >> 3,
+            ModRM::Displaced(base, _) | ModRM::Indexed(base, _, _) => base >> 3,
+            ModRM::RipRelative(_) => 0,
+        }
+    }
+
+    /// The high bit of the index register, which lives in REX.X/VEX.X.
+    fn index_extension(&self) -> u8 {
+        match self {
+            ModRM::Indexed(_, index, _) => index >> 3,
+            _ => 0,
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>, reg: u8) {
+        match self {
+            ModRM::Register(rm) => bytes.push(0xc0 | ((reg & 7) << 3) | (rm & 7)),
+            ModRM::Displaced(base, displ) => {
+                // Always use the 32 bit displacement form, rsp/r12 as a base
+                // can only be encoded with a SIB byte.
+                bytes.push(0x80 | ((reg & 7) << 3) | (base & 7));
+                if base & 7 == 4 {
+                    bytes.push(0x24);
+                }
+                bytes.extend_from_slice(&displ.to_le_bytes());
+            }
+            ModRM::Indexed(base, index, scale) => {
+                let scale = match scale {
+                    1 => 0,
+                    2 => 1,
+                    4 => 2,
+                    8 => 3,
+                    _ => todo!(),
+                };
+                bytes.push(0x80 | ((reg & 7) << 3) | 4);
+                bytes.push((scale << 6) | ((index & 7) << 3) | (base & 7));
+                bytes.extend_from_slice(&0_i32.to_le_bytes());
+            }
+            ModRM::RipRelative(offset) => {
+                bytes.push(((reg & 7) << 3) | 5);
+                let end = bytes.len() as i32 + 4;
+                bytes.extend_from_slice(&(offset - end).to_le_bytes());
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum VexMap {
+    M0F = 1,
+    M0F38 = 2,
+    M0F3A = 3,
+}
+
+#[derive(Clone, Copy)]
+enum VexPrefix {
+    None = 0,
+    P66 = 1,
+    PF3 = 2,
+}
+
+/// Same as `write_legacy`, but with REX.W set for a 64 bit operand size.
+fn write_legacy_wide(bytes: &mut Vec<u8>, opcode: u8, reg: u8, rm: &ModRM) {
+    bytes.push(0x48 | ((reg >> 3) << 2) | rm.extension());
+    bytes.push(opcode);
+    rm.write(bytes, reg);
+}
+
+fn write_legacy(bytes: &mut Vec<u8>, opcode: u8, reg: u8, rm: &ModRM) {
+    let rex = ((reg >> 3) << 2) | rm.extension();
+    if rex != 0 {
+        bytes.push(0x40 | rex);
+    }
+    bytes.push(opcode);
+    rm.write(bytes, reg);
+}
+
+/// Write a legacy SSE instruction. The mandatory `prefix` goes ahead of
+/// REX, and `opcode` are the bytes after the 0x0f escape.
+fn write_sse(bytes: &mut Vec<u8>, prefix: Option<u8>, opcode: &[u8], reg: u8, rm: &ModRM) {
+    bytes.extend(prefix);
+    let rex = ((reg >> 3) << 2) | (rm.index_extension() << 1) | rm.extension();
+    if rex != 0 {
+        bytes.push(0x40 | rex);
+    }
+    bytes.push(0x0f);
+    bytes.extend_from_slice(opcode);
+    rm.write(bytes, reg);
+}
+
+/// Write a three byte VEX prefix, the two byte form is never used
+/// so that we don't have to special case the extended registers.
+#[allow(clippy::too_many_arguments)]
+fn write_vex(
+    bytes: &mut Vec<u8>,
+    map: VexMap,
+    prefix: VexPrefix,
+    w: bool,
+    l: bool,
+    reg: u8,
+    vvvv: u8,
+    rm: &ModRM,
+) {
+    bytes.push(0xc4);
+    bytes.push(
+        (((reg >> 3) ^ 1) << 7)
+            | ((rm.index_extension() ^ 1) << 6)
+            | ((rm.extension() ^ 1) << 5)
+            | map as u8,
+    );
+    bytes.push(((w as u8) << 7) | ((!vvvv & 0xf) << 3) | ((l as u8) << 2) | prefix as u8);
+}
+
+/// Write a four byte EVEX prefix without broadcast, which only reaches
+/// the first 16 registers like VEX does. Writes are merged under the
+/// mask register numbered `mask`, where k0 doesn't mask at all. Memory
+/// operands always take a 32 bit displacement, which isn't scaled.
+#[allow(clippy::too_many_arguments)]
+fn write_evex(
+    bytes: &mut Vec<u8>,
+    map: VexMap,
+    prefix: VexPrefix,
+    l: bool,
+    reg: u8,
+    vvvv: u8,
+    mask: u8,
+    rm: &ModRM,
+) {
+    bytes.push(0x62);
+    bytes.push(
+        (((reg >> 3) ^ 1) << 7)
+            | ((rm.index_extension() ^ 1) << 6)
+            | ((rm.extension() ^ 1) << 5)
+            | (1 << 4)
+            | map as u8,
+    );
+    bytes.push(((!vvvv & 0xf) << 3) | (1 << 2) | prefix as u8);
+    bytes.push(((l as u8) << 5) | (1 << 3) | mask);
+}
+
+/// Encode a VEX instruction of the form `op rm, vvvv, reg` where the
+/// r/m operand may be memory.
+#[allow(clippy::too_many_arguments)]
+fn write_vex_rvm(
+    bytes: &mut Vec<u8>,
+    opcode: u8,
+    map: VexMap,
+    prefix: VexPrefix,
+    w: bool,
+    src: &Operand,
+    idx: &Operand,
+    dst: &Operand,
+) {
+    let (Operand::Register(idx), Operand::Register(dst)) = (idx, dst) else {
+        todo!()
+    };
+    let rm = ModRM::from_operand(src);
+    write_vex(
+        bytes,
+        map,
+        prefix,
+        w,
+        dst.is_ymm(),
+        dst.number(),
+        idx.number(),
+        &rm,
+    );
+    bytes.push(opcode);
+    rm.write(bytes, dst.number());
+}
+
+/// Encode a VEX move, which has a separate opcode for the load and
+/// store directions.
+fn write_vex_move(
+    bytes: &mut Vec<u8>,
+    load: u8,
+    store: u8,
+    prefix: VexPrefix,
+    src: &Operand,
+    dst: &Operand,
+) {
+    let (opcode, reg, rm) = match (src, dst) {
+        (src, Operand::Register(reg)) => (load, reg, ModRM::from_operand(src)),
+        (Operand::Register(reg), dst) => (store, reg, ModRM::from_memory(dst)),
+        _ => todo!(),
+    };
+    write_vex(
+        bytes,
+        VexMap::M0F,
+        prefix,
+        false,
+        reg.is_ymm(),
+        reg.number(),
+        0,
+        &rm,
+    );
+    bytes.push(opcode);
+    rm.write(bytes, reg.number());
+}
+
 /// Operands for use within assembly. Please refer to this awesome
 /// video for information about addressing modes from which this code
 /// is derived: https://www.youtube.com/watch?v=lUbPUWtmVUU
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(unused)]
 pub enum Operand {
     /// Provide integer data as an operand.
@@ -97,6 +839,47 @@ pub enum Operand {
     /// second register value scaled by the second integer value to use
     /// as the effective address.
     ScaledDisplacedIndex(i32, Register, i32),
+    /// Statically define a displacement from the value of a base
+    /// register to use as the effective address.
+    Displaced(i32, Register),
+    /// An index vector in the constant pool, which has to be laid out
+    /// by `Target::write_amd64_function` before it can be encoded.
+    Constant([u32; 8]),
+    /// An offset from the start of the code, encoded relative to the end
+    /// of the instruction. Only instructions without an immediate can
+    /// address memory this way.
+    RipRelative(i32),
+}
+
+impl Operand {
+    pub fn registers(&self) -> Vec<Register> {
+        match *self {
+            Operand::Immediate(_) | Operand::Constant(_) | Operand::RipRelative(_) => vec![],
+            Operand::Register(reg)
+            | Operand::Memory(reg)
+            | Operand::ScaledDisplacedIndex(_, reg, _)
+            | Operand::Displaced(_, reg) => vec![reg],
+            Operand::Index(base, index) | Operand::ScaledIndex(base, index, _) => vec![base, index],
+        }
+    }
+
+    pub fn map_registers(&self, f: impl Fn(Register) -> Register) -> Operand {
+        match *self {
+            Operand::Immediate(val) => Operand::Immediate(val),
+            Operand::Register(reg) => Operand::Register(f(reg)),
+            Operand::Memory(reg) => Operand::Memory(f(reg)),
+            Operand::Index(base, index) => Operand::Index(f(base), f(index)),
+            Operand::ScaledIndex(base, index, scale) => {
+                Operand::ScaledIndex(f(base), f(index), scale)
+            }
+            Operand::ScaledDisplacedIndex(displ, reg, scale) => {
+                Operand::ScaledDisplacedIndex(displ, f(reg), scale)
+            }
+            Operand::Displaced(displ, reg) => Operand::Displaced(displ, f(reg)),
+            Operand::Constant(lanes) => Operand::Constant(lanes),
+            Operand::RipRelative(offset) => Operand::RipRelative(offset),
+        }
+    }
 }
 
 impl Display for Operand {
@@ -110,6 +893,9 @@ impl Display for Operand {
             Operand::ScaledDisplacedIndex(displ, reg, scalar) => {
                 write!(f, "{}(, {}, {})", displ, reg, scalar)
             }
+            Operand::Displaced(displ, reg) => write!(f, "{}({})", displ, reg),
+            Operand::Constant(lanes) => write!(f, "{:?}(%rip)", lanes),
+            Operand::RipRelative(offset) => write!(f, "{}(%rip)", offset),
         }
     }
 }
@@ -123,12 +909,16 @@ impl SerializeAMD64MachineCode for Operand {
             Operand::Index(_, _) => todo!(),
             Operand::ScaledIndex(_, _, _) => todo!(),
             Operand::ScaledDisplacedIndex(_, _, _) => todo!(),
+            Operand::Displaced(_, _) => todo!(),
+            Operand::Constant(_) => todo!(),
+            Operand::RipRelative(_) => todo!(),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(unused)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Register {
     /// 64 Bit Accumulator Register
     RAX,
@@ -224,10 +1014,145 @@ pub enum Register {
     XMM14,
     XMM15,
 
+    /// The AVX-512 mask register that masked stores use.
+    K1,
+
     /// The EFLAGS register
     EFLAGS,
 }
 
+impl Register {
+    /// Whether this is one of the 256 bit AVX registers.
+    pub const fn is_ymm(&self) -> bool {
+        matches!(
+            self,
+            Register::YMM0
+                | Register::YMM1
+                | Register::YMM2
+                | Register::YMM3
+                | Register::YMM4
+                | Register::YMM5
+                | Register::YMM6
+                | Register::YMM7
+                | Register::YMM8
+                | Register::YMM9
+                | Register::YMM10
+                | Register::YMM11
+                | Register::YMM12
+                | Register::YMM13
+                | Register::YMM14
+                | Register::YMM15
+        )
+    }
+
+    /// Whether this is one of the 128 bit SSE registers.
+    pub const fn is_xmm(&self) -> bool {
+        matches!(
+            self,
+            Register::XMM0
+                | Register::XMM1
+                | Register::XMM2
+                | Register::XMM3
+                | Register::XMM4
+                | Register::XMM5
+                | Register::XMM6
+                | Register::XMM7
+                | Register::XMM8
+                | Register::XMM9
+                | Register::XMM10
+                | Register::XMM11
+                | Register::XMM12
+                | Register::XMM13
+                | Register::XMM14
+                | Register::XMM15
+        )
+    }
+
+    /// Whether this is one of the AVX-512 mask registers.
+    pub const fn is_mask(&self) -> bool {
+        matches!(self, Register::K1)
+    }
+
+    /// The register of the same width as this vector register with the
+    /// given number.
+    pub const fn with_number(&self, number: u8) -> Register {
+        if self.is_ymm() {
+            Register::ymm(number)
+        } else {
+            Register::xmm(number)
+        }
+    }
+
+    /// The ymm register with the given number.
+    pub const fn ymm(number: u8) -> Register {
+        const YMM: [Register; 16] = [
+            Register::YMM0,
+            Register::YMM1,
+            Register::YMM2,
+            Register::YMM3,
+            Register::YMM4,
+            Register::YMM5,
+            Register::YMM6,
+            Register::YMM7,
+            Register::YMM8,
+            Register::YMM9,
+            Register::YMM10,
+            Register::YMM11,
+            Register::YMM12,
+            Register::YMM13,
+            Register::YMM14,
+            Register::YMM15,
+        ];
+        YMM[number as usize]
+    }
+
+    /// The xmm register with the given number.
+    pub const fn xmm(number: u8) -> Register {
+        const XMM: [Register; 16] = [
+            Register::XMM0,
+            Register::XMM1,
+            Register::XMM2,
+            Register::XMM3,
+            Register::XMM4,
+            Register::XMM5,
+            Register::XMM6,
+            Register::XMM7,
+            Register::XMM8,
+            Register::XMM9,
+            Register::XMM10,
+            Register::XMM11,
+            Register::XMM12,
+            Register::XMM13,
+            Register::XMM14,
+            Register::XMM15,
+        ];
+        XMM[number as usize]
+    }
+
+    /// The 4 bit register number used within ModRM, REX and VEX fields.
+    pub const fn number(&self) -> u8 {
+        match self {
+            Register::RAX | Register::EAX | Register::XMM0 | Register::YMM0 => 0,
+            Register::RCX | Register::ECX | Register::XMM1 | Register::YMM1 | Register::K1 => 1,
+            Register::RDX | Register::EDX | Register::XMM2 | Register::YMM2 => 2,
+            Register::RBX | Register::EBX | Register::XMM3 | Register::YMM3 => 3,
+            Register::RSP | Register::ESP | Register::XMM4 | Register::YMM4 => 4,
+            Register::RBP | Register::EBP | Register::XMM5 | Register::YMM5 => 5,
+            Register::RSI | Register::ESI | Register::XMM6 | Register::YMM6 => 6,
+            Register::RDI | Register::EDI | Register::XMM7 | Register::YMM7 => 7,
+            Register::R8 | Register::XMM8 | Register::YMM8 => 8,
+            Register::R9 | Register::XMM9 | Register::YMM9 => 9,
+            Register::R10 | Register::XMM10 | Register::YMM10 => 10,
+            Register::R11 | Register::XMM11 | Register::YMM11 => 11,
+            Register::R12 | Register::XMM12 | Register::YMM12 => 12,
+            Register::R13 | Register::XMM13 | Register::YMM13 => 13,
+            Register::R14 | Register::XMM14 | Register::YMM14 => 14,
+            Register::R15 | Register::XMM15 | Register::YMM15 => 15,
+            Register::RIP | Register::EIP | Register::EFLAGS => todo!(),
+        }
+    }
+}
+
 impl Display for Register {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
@@ -290,6 +1215,7 @@ impl Display for Register {
             Register::XMM13 => write!(f, "%xmm13"),
             Register::XMM14 => write!(f, "%xmm14"),
             Register::XMM15 => write!(f, "%xmm15"),
+            Register::K1 => write!(f, "%k1"),
         }
     }
 }
@@ -355,6 +1281,7 @@ impl SerializeAMD64MachineCode for Register {
             Register::XMM13 => todo!(),
             Register::XMM14 => todo!(),
             Register::XMM15 => todo!(),
+            Register::K1 => todo!(),
             Register::EFLAGS => todo!(),
         }
     }
@@ -0,0 +1,586 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+};
+
+use crate::{
+    abstract_instructions::{in_place::InPlaceStep, InstructionBlock},
+    encodings::LowerIR,
+};
+
+/// A virtual register, written by exactly one op of a program.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VReg(pub u32);
+
+impl Debug for VReg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// An operation of the IR. Registers hold a number of 32 bit lanes,
+/// registers of a single lane are scalars.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Load consecutive values of the input, from `first` on.
+    Load { dst: VReg, first: u32 },
+    /// An index vector known when generating code.
+    Constant { dst: VReg, lanes: Vec<u32> },
+    /// Lane `i` of `dst` is lane `indices[i]` of `first` and `second`
+    /// one after the other. Permutes of a single register name it twice.
+    Shuffle {
+        dst: VReg,
+        first: VReg,
+        second: VReg,
+        indices: VReg,
+    },
+    /// Lane `i` of `dst` is lane `i` of `second` when bit `i` of
+    /// `select` is set, and of `first` otherwise.
+    Blend {
+        dst: VReg,
+        first: VReg,
+        second: VReg,
+        select: u32,
+    },
+    /// Load the values of the input at the positions in `indices`.
+    Gather { dst: VReg, indices: VReg },
+    /// Store the lanes of `src` to consecutive values of the output,
+    /// from `first` on.
+    Store { src: VReg, first: u32 },
+    /// Store only the lanes of `src` whose bit in `mask` is set, and
+    /// leave the other values of the output as they are.
+    MaskedStore { src: VReg, first: u32, mask: u32 },
+    /// Copy `len` consecutive values of the input from `from` on to the
+    /// output from `first` on, without naming a register.
+    Copy { from: u32, first: u32, len: u32 },
+}
+
+impl Op {
+    /// The register this op writes, if any.
+    pub const fn dst(&self) -> Option<VReg> {
+        match self {
+            Op::Load { dst, .. }
+            | Op::Constant { dst, .. }
+            | Op::Shuffle { dst, .. }
+            | Op::Blend { dst, .. }
+            | Op::Gather { dst, .. } => Some(*dst),
+            Op::Store { .. } | Op::MaskedStore { .. } | Op::Copy { .. } => None,
+        }
+    }
+
+    /// The registers this op reads.
+    pub fn sources(&self) -> Vec<VReg> {
+        match self {
+            Op::Load { .. } | Op::Constant { .. } | Op::Copy { .. } => vec![],
+            Op::Shuffle {
+                first,
+                second,
+                indices,
+                ..
+            } => vec![*first, *second, *indices],
+            Op::Blend { first, second, .. } => vec![*first, *second],
+            Op::Gather { indices, .. } => vec![*indices],
+            Op::Store { src, .. } | Op::MaskedStore { src, .. } => vec![*src],
+        }
+    }
+
+    /// Rename the registers this op reads with `f`.
+    fn map_sources(&mut self, f: impl Fn(VReg) -> VReg) {
+        match self {
+            Op::Load { .. } | Op::Constant { .. } | Op::Copy { .. } => {}
+            Op::Shuffle {
+                first,
+                second,
+                indices,
+                ..
+            } => {
+                *first = f(*first);
+                *second = f(*second);
+                *indices = f(*indices);
+            }
+            Op::Blend { first, second, .. } => {
+                *first = f(*first);
+                *second = f(*second);
+            }
+            Op::Gather { indices, .. } => *indices = f(*indices),
+            Op::Store { src, .. } | Op::MaskedStore { src, .. } => *src = f(*src),
+        }
+    }
+}
+
+/// A program of the IR, which permutes `in` into `out`. Every op
+/// remembers the block it was lowered from, so that generated code can
+/// still be traced back to blocks after ops were removed or merged.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub ops: Vec<(usize, Op)>,
+    /// The number of lanes of each register.
+    lanes: Vec<u32>,
+    /// The lanes of each constant.
+    constants: HashMap<VReg, Vec<u32>>,
+    /// Whether `in` and `out` are the same buffer.
+    pub in_place: bool,
+    /// The block ops are being lowered from.
+    block: usize,
+}
+
+impl Program {
+    pub fn new(in_place: bool) -> Self {
+        Self {
+            ops: vec![],
+            lanes: vec![],
+            constants: HashMap::new(),
+            in_place,
+            block: 0,
+        }
+    }
+
+    /// Lower an out of place program of blocks, which have to fit the
+    /// target already.
+    pub fn from_blocks(blocks: &[InstructionBlock]) -> Self {
+        let mut program = Self::new(false);
+        for (i, blk) in blocks.iter().enumerate() {
+            program.lower_block(i, blk);
+        }
+        program
+    }
+
+    /// Lower a block out of place, as block `index` of the program.
+    pub fn lower_block(&mut self, index: usize, blk: &InstructionBlock) {
+        self.block = index;
+        let loaded = blk.lower_ir_load(self);
+        blk.lower_ir_store(self, &loaded);
+    }
+
+    /// Lower an in place program, a saved block stays in its registers
+    /// until the step restoring it.
+    pub fn from_in_place_steps(steps: &[InPlaceStep]) -> Self {
+        let mut program = Self::new(true);
+        let mut saved: HashMap<usize, Vec<VReg>> = HashMap::new();
+
+        for (i, step) in steps.iter().enumerate() {
+            program.block = i;
+            match step {
+                InPlaceStep::Block(_, blk) => {
+                    let loaded = blk.lower_ir_load(&mut program);
+                    blk.lower_ir_store(&mut program, &loaded);
+                }
+                InPlaceStep::Save(_, blk, slot) => {
+                    let loaded = blk.lower_ir_load(&mut program);
+                    saved.insert(*slot, loaded);
+                }
+                InPlaceStep::Restore(_, blk, slot) => {
+                    let loaded = saved
+                        .remove(slot)
+                        .expect("restored a block that wasn't saved");
+                    blk.lower_ir_store(&mut program, &loaded);
+                }
+            }
+        }
+        program
+    }
+
+    /// The number of lanes of a register.
+    pub fn lanes(&self, reg: VReg) -> u32 {
+        self.lanes[reg.0 as usize]
+    }
+
+    /// The lanes of a register written by a `Constant`.
+    pub fn constant(&self, reg: VReg) -> &[u32] {
+        &self.constants[&reg]
+    }
+
+    fn fresh(&mut self, lanes: u32) -> VReg {
+        self.lanes.push(lanes);
+        VReg(self.lanes.len() as u32 - 1)
+    }
+
+    fn push(&mut self, op: Op) {
+        self.ops.push((self.block, op));
+    }
+
+    pub fn load(&mut self, first: u32, lanes: u32) -> VReg {
+        let dst = self.fresh(lanes);
+        self.push(Op::Load { dst, first });
+        dst
+    }
+
+    pub fn index_vector(&mut self, lanes: Vec<u32>) -> VReg {
+        let dst = self.fresh(lanes.len() as u32);
+        self.constants.insert(dst, lanes.clone());
+        self.push(Op::Constant { dst, lanes });
+        dst
+    }
+
+    /// Shuffle two registers by `indices`, a shuffle that leaves the
+    /// first register as is doesn't need an op.
+    pub fn shuffle(&mut self, first: VReg, second: VReg, indices: &[u32]) -> VReg {
+        let lanes = self.lanes(first);
+        let indices: Vec<u32> = if first == second {
+            indices.iter().map(|lane| lane % lanes).collect()
+        } else {
+            indices.to_vec()
+        };
+        if indices
+            .iter()
+            .enumerate()
+            .all(|(i, lane)| i as u32 == *lane)
+        {
+            return first;
+        }
+
+        let indices = self.index_vector(indices);
+        let dst = self.fresh(lanes);
+        self.push(Op::Shuffle {
+            dst,
+            first,
+            second,
+            indices,
+        });
+        dst
+    }
+
+    /// Permute the lanes of a single register.
+    pub fn permute(&mut self, src: VReg, indices: &[u32]) -> VReg {
+        self.shuffle(src, src, indices)
+    }
+
+    pub fn blend(&mut self, first: VReg, second: VReg, select: u32) -> VReg {
+        let dst = self.fresh(self.lanes(first));
+        self.push(Op::Blend {
+            dst,
+            first,
+            second,
+            select,
+        });
+        dst
+    }
+
+    pub fn gather(&mut self, positions: Vec<u32>) -> VReg {
+        let indices = self.index_vector(positions);
+        let dst = self.fresh(self.lanes(indices));
+        self.push(Op::Gather { dst, indices });
+        dst
+    }
+
+    pub fn store(&mut self, src: VReg, first: u32) {
+        self.push(Op::Store { src, first });
+    }
+
+    pub fn masked_store(&mut self, src: VReg, first: u32, mask: u32) {
+        self.push(Op::MaskedStore { src, first, mask });
+    }
+
+    pub fn copy(&mut self, from: u32, first: u32, len: u32) {
+        self.push(Op::Copy { from, first, len });
+    }
+
+    /// Rename every read of the registers in `renames`.
+    fn rename(&mut self, renames: &HashMap<VReg, VReg>) {
+        for (_, op) in self.ops.iter_mut() {
+            op.map_sources(|reg| *renames.get(&reg).unwrap_or(&reg));
+        }
+    }
+
+    /// Run every pass, in an order where each one leaves work for the
+    /// next: merged index vectors make gathers identical, and removed
+    /// stores leave the ops computing their values unused.
+    pub fn optimize(&mut self) {
+        self.deduplicate_constants();
+        self.eliminate_redundant_loads();
+        self.eliminate_dead_stores();
+        self.eliminate_dead_code();
+    }
+
+    /// Merge index vectors with the same lanes into the first of them,
+    /// which then stays in a register for all of its users.
+    pub fn deduplicate_constants(&mut self) {
+        let mut first: HashMap<Vec<u32>, VReg> = HashMap::new();
+        let mut renames = HashMap::new();
+
+        self.ops.retain(|(_, op)| match op {
+            Op::Constant { dst, lanes } => match first.get(lanes) {
+                Some(reg) => {
+                    renames.insert(*dst, *reg);
+                    false
+                }
+                None => {
+                    first.insert(lanes.clone(), *dst);
+                    true
+                }
+            },
+            _ => true,
+        });
+        self.rename(&renames);
+    }
+
+    /// Reuse the registers of a load or gather for a later one reading
+    /// the same values, unless a store in between may have changed them.
+    /// The input is only ever written when permuting in place.
+    pub fn eliminate_redundant_loads(&mut self) {
+        let mut loads: HashMap<(u32, u32), VReg> = HashMap::new();
+        let mut gathers: HashMap<VReg, VReg> = HashMap::new();
+        let mut renames = HashMap::new();
+        let lanes = self.lanes.clone();
+        let in_place = self.in_place;
+
+        self.ops.retain(|(_, op)| match *op {
+            Op::Load { dst, first } => {
+                let key = (first, lanes[dst.0 as usize]);
+                match loads.get(&key) {
+                    Some(reg) => {
+                        renames.insert(dst, *reg);
+                        false
+                    }
+                    None => {
+                        loads.insert(key, dst);
+                        true
+                    }
+                }
+            }
+            Op::Gather { dst, indices } => {
+                // Index vectors are merged first, so identical gathers
+                // name the same one.
+                let indices = *renames.get(&indices).unwrap_or(&indices);
+                match gathers.get(&indices) {
+                    Some(reg) => {
+                        renames.insert(dst, *reg);
+                        false
+                    }
+                    None => {
+                        gathers.insert(indices, dst);
+                        true
+                    }
+                }
+            }
+            Op::Store { src, first } | Op::MaskedStore { src, first, .. } if in_place => {
+                let end = first + lanes[src.0 as usize];
+                loads.retain(|(start, len), _| start + len <= first || end <= *start);
+                gathers.clear();
+                true
+            }
+            Op::Copy { first, len, .. } if in_place => {
+                let end = first + len;
+                loads.retain(|(start, len), _| start + len <= first || end <= *start);
+                gathers.clear();
+                true
+            }
+            _ => true,
+        });
+        self.rename(&renames);
+    }
+
+    /// Remove stores whose values are all overwritten by later stores
+    /// before anything reads them back, which only loads of an in place
+    /// program can.
+    pub fn eliminate_dead_stores(&mut self) {
+        let mut overwritten: HashSet<u32> = HashSet::new();
+        let mut dead = vec![false; self.ops.len()];
+
+        for (i, (_, op)) in self.ops.iter().enumerate().rev() {
+            match *op {
+                Op::Store { src, first } => {
+                    let positions = first..first + self.lanes(src);
+                    dead[i] = positions.clone().all(|p| overwritten.contains(&p));
+                    overwritten.extend(positions);
+                }
+                Op::MaskedStore { src, first, mask } => {
+                    let positions: Vec<u32> = (0..self.lanes(src))
+                        .filter(|lane| mask & (1 << lane) != 0)
+                        .map(|lane| first + lane)
+                        .collect();
+                    dead[i] = positions.iter().all(|p| overwritten.contains(p));
+                    overwritten.extend(positions);
+                }
+                Op::Copy { from, first, len } => {
+                    let positions = first..first + len;
+                    dead[i] = positions.clone().all(|p| overwritten.contains(&p));
+                    overwritten.extend(positions);
+                    if self.in_place {
+                        for p in from..from + len {
+                            overwritten.remove(&p);
+                        }
+                    }
+                }
+                Op::Load { dst, first } if self.in_place => {
+                    for p in first..first + self.lanes(dst) {
+                        overwritten.remove(&p);
+                    }
+                }
+                Op::Gather { indices, .. } if self.in_place => {
+                    for p in self.constants[&indices].iter() {
+                        overwritten.remove(p);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut dead = dead.into_iter();
+        self.ops.retain(|_| !dead.next().unwrap());
+    }
+
+    /// Remove ops whose registers are never read.
+    pub fn eliminate_dead_code(&mut self) {
+        let mut live: HashSet<VReg> = HashSet::new();
+        let mut dead = vec![false; self.ops.len()];
+
+        for (i, (_, op)) in self.ops.iter().enumerate().rev() {
+            if let Some(dst) = op.dst() {
+                if !live.contains(&dst) {
+                    dead[i] = true;
+                    continue;
+                }
+            }
+            live.extend(op.sources());
+        }
+
+        let mut dead = dead.into_iter();
+        self.ops.retain(|_| !dead.next().unwrap());
+    }
+
+    /// Split registers wider than `width` lanes into registers of
+    /// `width` lanes, for targets whose vectors are narrower than the
+    /// blocks. Each part of a shuffle takes the lanes it needs from at
+    /// most two parts of its sources at a time.
+    pub fn legalize(&mut self, width: u32) {
+        if self.lanes.iter().all(|lanes| *lanes <= width) {
+            return;
+        }
+
+        let ops = std::mem::take(&mut self.ops);
+        let mut parts: HashMap<VReg, Vec<VReg>> = HashMap::new();
+        let part = |parts: &HashMap<VReg, Vec<VReg>>, reg: VReg| -> Vec<VReg> {
+            parts.get(&reg).cloned().unwrap_or_else(|| vec![reg])
+        };
+
+        for (block, op) in ops {
+            self.block = block;
+            let wide = op.dst().is_some_and(|dst| self.lanes(dst) > width);
+            match op {
+                Op::Load { dst, first } if wide => {
+                    let split = (0..self.lanes(dst) / width)
+                        .map(|i| self.load(first + i * width, width))
+                        .collect();
+                    parts.insert(dst, split);
+                }
+                Op::Shuffle {
+                    dst,
+                    first,
+                    second,
+                    indices,
+                } if wide => {
+                    let mut sources = part(&parts, first);
+                    sources.extend(part(&parts, second));
+                    let indices = self.constants[&indices].clone();
+
+                    let split = indices
+                        .chunks(width as usize)
+                        .map(|chunk| self.legalize_shuffle(&sources, chunk, width))
+                        .collect();
+                    parts.insert(dst, split);
+                }
+                Op::Blend {
+                    dst,
+                    first,
+                    second,
+                    select,
+                } if wide => {
+                    let split = part(&parts, first)
+                        .into_iter()
+                        .zip(part(&parts, second))
+                        .enumerate()
+                        .map(|(i, (first, second))| {
+                            let select = (select >> (i as u32 * width)) & ((1 << width) - 1);
+                            self.blend(first, second, select)
+                        })
+                        .collect();
+                    parts.insert(dst, split);
+                }
+                // Index vectors are only read by shuffles, which were
+                // split above, and by gathers, which no target with
+                // narrow vectors has.
+                Op::Constant { .. } if wide => {}
+                Op::Store { src, first } if self.lanes(src) > width => {
+                    for (i, reg) in part(&parts, src).into_iter().enumerate() {
+                        self.store(reg, first + i as u32 * width);
+                    }
+                }
+                Op::MaskedStore { src, first, mask } if self.lanes(src) > width => {
+                    for (i, reg) in part(&parts, src).into_iter().enumerate() {
+                        let mask = (mask >> (i as u32 * width)) & ((1 << width) - 1);
+                        self.masked_store(reg, first + i as u32 * width, mask);
+                    }
+                }
+                op => self.push(op),
+            }
+        }
+    }
+
+    /// One part of a shuffle being legalized, where `sources` are the
+    /// parts of both sources of the shuffle one after the other.
+    fn legalize_shuffle(&mut self, sources: &[VReg], chunk: &[u32], width: u32) -> VReg {
+        // The parts each lane is read from, without duplicates.
+        let mut used: Vec<usize> = vec![];
+        for lane in chunk.iter() {
+            let source = (*lane / width) as usize;
+            if !used.contains(&source) {
+                used.push(source);
+            }
+        }
+
+        // Shuffle pairs of parts, then blend the pairs together.
+        let mut result: Option<VReg> = None;
+        for pair in used.chunks(2) {
+            let (first, second) = (pair[0], *pair.get(1).unwrap_or(&pair[0]));
+            let indices: Vec<u32> = chunk
+                .iter()
+                .map(|lane| {
+                    let offset = lane % width;
+                    match (*lane / width) as usize {
+                        source if source == first => offset,
+                        source if source == second => width + offset,
+                        _ => 0,
+                    }
+                })
+                .collect();
+            let shuffled = self.shuffle(sources[first], sources[second], &indices);
+
+            result = Some(match result {
+                None => shuffled,
+                Some(reg) => {
+                    let select = chunk
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, lane)| pair.contains(&((**lane / width) as usize)))
+                        .fold(0, |select, (i, _)| select | 1 << i);
+                    self.blend(reg, shuffled, select)
+                }
+            });
+        }
+
+        result.unwrap()
+    }
+}
+
+#[test]
+fn test_optimize() {
+    // Two blocks reversing the same window into the same place, the
+    // first of which is overwritten by the second.
+    let mut program = Program::new(false);
+    for block in 0..2 {
+        program.block = block;
+        let loaded = program.load(4, 4);
+        let reversed = program.permute(loaded, &[3, 2, 1, 0]);
+        program.store(reversed, 0);
+    }
+    program.optimize();
+
+    let ops: Vec<&Op> = program.ops.iter().map(|(_, op)| op).collect();
+    assert_eq!(ops.len(), 4);
+    assert!(matches!(ops[0], Op::Load { first: 4, .. }));
+    assert!(matches!(ops[1], Op::Constant { .. }));
+    assert!(matches!(ops[2], Op::Shuffle { .. }));
+    assert!(matches!(ops[3], Op::Store { first: 0, .. }));
+    assert_eq!(program.ops[3].0, 1);
+}
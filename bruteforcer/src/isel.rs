@@ -0,0 +1,494 @@
+use crate::{
+    args::Feature,
+    encodings::Target,
+    instructions_x86_64::{Instruction, Operand, Register},
+    ir::{Op, Program},
+};
+
+/// Each op is selected as if it had all registers to itself, with its
+/// sources in registers 0 and 1 and its result in register 15. Index
+/// vectors are expected in registers 14 and 11, and 12 and 13 are free
+/// to use as temporaries. The `RegisterAllocator` renames them. Scalars
+/// are moved through eax.
+pub const FIRST: u8 = 0;
+pub const SECOND: u8 = 1;
+pub const RESULT: u8 = 15;
+pub const INDICES: [u8; 2] = [14, 11];
+const TEMPORARIES: [u8; 2] = [12, 13];
+
+/// The instructions selected for an op, along with the index vectors
+/// they read.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub instrs: Vec<Instruction>,
+    pub constants: Vec<(u8, [u32; 8])>,
+}
+
+impl Selection {
+    /// How expensive the selection is, an index vector costs about as
+    /// much as an instruction to keep around.
+    fn cost(&self) -> usize {
+        self.instrs.len() + self.constants.len()
+    }
+}
+
+/// The register numbered `number` for a value of `lanes` lanes.
+fn register(lanes: u32, number: u8) -> Operand {
+    Operand::Register(match lanes {
+        1 => Register::EAX,
+        4 => Register::xmm(number),
+        _ => Register::ymm(number),
+    })
+}
+
+/// Select the instructions of an op for the target.
+pub fn select_amd64(op: &Op, program: &Program, target: Target) -> Selection {
+    let mut selection = select_vex(op, program, target);
+    if !target.features.has(Feature::Avx) {
+        selection.instrs = selection.instrs.iter().flat_map(legacy).collect();
+    }
+    selection
+}
+
+/// Select the instructions of an op in their VEX encodings, whatever
+/// the target. The C backend spells shuffles as the intrinsics of
+/// these, which compilers encode for the target themselves.
+pub fn select_vex(op: &Op, program: &Program, target: Target) -> Selection {
+    let mut selection = Selection::default();
+
+    match *op {
+        Op::Load { dst, first } => {
+            let lanes = program.lanes(dst);
+            let src = Operand::Displaced(4 * first as i32, Register::RDI);
+            selection.instrs.push(match lanes {
+                1 => Instruction::MOVL(src, register(lanes, RESULT)),
+                _ => Instruction::VMOVUPS(src, register(lanes, RESULT)),
+            });
+        }
+        // Index vectors are materialized by the allocator, only once an
+        // instruction needs one in a register.
+        Op::Constant { .. } => {}
+        Op::Shuffle {
+            first,
+            second,
+            indices,
+            ..
+        } => {
+            let lanes = program.constant(indices);
+            selection = select_shuffle(lanes, first == second, target);
+        }
+        Op::Blend { dst, select, .. } => {
+            let lanes = program.lanes(dst);
+            selection.instrs.push(Instruction::VBLENDPS(
+                Operand::Immediate(select as i32),
+                register(lanes, SECOND),
+                register(lanes, FIRST),
+                register(lanes, RESULT),
+            ));
+        }
+        Op::Gather { indices, .. } => {
+            // Gathers clear their mask as lanes complete, so it has to
+            // be reset every time.
+            let mask = register(8, TEMPORARIES[1]);
+            selection
+                .instrs
+                .push(Instruction::VPCMPEQD(mask, mask, mask));
+            selection.instrs.push(Instruction::VGATHERDPS(
+                mask,
+                Operand::ScaledIndex(Register::RDI, Register::ymm(INDICES[0]), 4),
+                register(8, RESULT),
+            ));
+            selection
+                .constants
+                .push((INDICES[0], program.constant(indices).try_into().unwrap()));
+        }
+        Op::Store { src, first } => {
+            let lanes = program.lanes(src);
+            let dst = Operand::Displaced(4 * first as i32, Register::RSI);
+            selection.instrs.push(match lanes {
+                1 => Instruction::MOVL(register(lanes, FIRST), dst),
+                _ => Instruction::VMOVUPS(register(lanes, FIRST), dst),
+            });
+        }
+        // With AVX-512 the mask is loaded into k1 from the low lane of a
+        // constant, which is only read by the store. Otherwise it is an
+        // index vector whose stored lanes have their sign bit set.
+        Op::MaskedStore { src, first, mask } => {
+            let lanes = program.lanes(src);
+            let dst = Operand::Displaced(4 * first as i32, Register::RSI);
+            if target.features.has(Feature::Avx512f) {
+                let k1 = Operand::Register(Register::K1);
+                selection.instrs.push(Instruction::KMOVW(
+                    Operand::Constant(std::array::from_fn(|lane| if lane == 0 { mask } else { 0 })),
+                    k1,
+                ));
+                selection
+                    .instrs
+                    .push(Instruction::VMOVUPSK(register(lanes, FIRST), k1, dst));
+            } else {
+                selection.instrs.push(Instruction::VMASKMOVPS(
+                    register(lanes, FIRST),
+                    register(lanes, INDICES[0]),
+                    dst,
+                ));
+                selection.constants.push((
+                    INDICES[0],
+                    std::array::from_fn(|lane| if mask & (1 << lane) != 0 { u32::MAX } else { 0 }),
+                ));
+            }
+        }
+        // Moved a register at a time, taking turns between temporaries so
+        // that the moves don't wait on each other. A run that isn't a
+        // whole number of registers ends with one overlapping the last,
+        // which only works while the input and output are apart.
+        Op::Copy { from, first, len } => {
+            assert!(!program.in_place, "copies are only planned out of place");
+            let lanes = if len >= 8 && target.features.has(Feature::Avx) {
+                8
+            } else {
+                4
+            };
+            for (i, offset) in (0..len).step_by(lanes as usize).enumerate() {
+                let offset = offset.min(len - lanes);
+                let temporary = register(lanes, TEMPORARIES[i % 2]);
+                selection.instrs.push(Instruction::VMOVUPS(
+                    Operand::Displaced(4 * (from + offset) as i32, Register::RDI),
+                    temporary,
+                ));
+                selection.instrs.push(Instruction::VMOVUPS(
+                    temporary,
+                    Operand::Displaced(4 * (first + offset) as i32, Register::RSI),
+                ));
+            }
+        }
+    }
+
+    selection
+}
+
+/// The legacy SSE encoding of an instruction selected for 128 bit
+/// registers, for targets without VEX. Shuffles of two sources first
+/// move their first source into the destination, which they overwrite.
+/// That writes the result before the last instruction, so the allocator
+/// keeps it apart from the sources.
+fn legacy(instr: &Instruction) -> Vec<Instruction> {
+    match *instr {
+        Instruction::MOVL(_, _) => vec![*instr],
+        Instruction::VMOVUPS(src, dst) => vec![Instruction::MOVUPS(src, dst)],
+        Instruction::VPERMILPS(imm, src, dst) => vec![Instruction::PSHUFD(imm, src, dst)],
+        Instruction::VSHUFPS(imm, second, first, dst) => vec![
+            Instruction::MOVUPS(first, dst),
+            Instruction::SHUFPS(imm, second, dst),
+        ],
+        Instruction::VUNPCKLPS(second, first, dst) => vec![
+            Instruction::MOVUPS(first, dst),
+            Instruction::UNPCKLPS(second, dst),
+        ],
+        Instruction::VUNPCKHPS(second, first, dst) => vec![
+            Instruction::MOVUPS(first, dst),
+            Instruction::UNPCKHPS(second, dst),
+        ],
+        Instruction::VPALIGNR(imm, low, high, dst) => vec![
+            Instruction::MOVUPS(high, dst),
+            Instruction::PALIGNR(imm, low, dst),
+        ],
+        Instruction::VBLENDPS(imm, second, first, dst) => vec![
+            Instruction::MOVUPS(first, dst),
+            Instruction::BLENDPS(imm, second, dst),
+        ],
+        _ => unreachable!("{} is only encoded with VEX", instr),
+    }
+}
+
+/// The `vpermilps` and `vshufps` immediate selecting `lanes` within
+/// each 128 bit half.
+fn immediate(lanes: &[u32]) -> Operand {
+    Operand::Immediate(
+        lanes
+            .iter()
+            .enumerate()
+            .fold(0, |imm, (i, lane)| imm | ((lane & 3) << (2 * i)) as i32),
+    )
+}
+
+/// Select a shuffle that keeps lanes within their 128 bit half, where
+/// `pattern` indexes the lanes of a half of `x` followed by those of
+/// the same half of `y`. Every shuffle of two sources with a pattern
+/// no single instruction has is a blend of two permutes.
+fn select_in_lane(
+    pattern: &[u32],
+    x: Operand,
+    y: Operand,
+    dst: Operand,
+    wide: bool,
+    target: Target,
+) -> Vec<Instruction> {
+    let from = |offset: u32| pattern.iter().all(|lane| (*lane & 4) == offset);
+    if from(0) {
+        return vec![Instruction::VPERMILPS(immediate(pattern), x, dst)];
+    }
+    if from(4) {
+        return vec![Instruction::VPERMILPS(immediate(pattern), y, dst)];
+    }
+
+    let swapped: Vec<u32> = pattern.iter().map(|lane| lane ^ 4).collect();
+    for (pattern, x, y) in [(pattern, x, y), (&swapped[..], y, x)] {
+        match pattern {
+            [0, 4, 1, 5] => return vec![Instruction::VUNPCKLPS(y, x, dst)],
+            [2, 6, 3, 7] => return vec![Instruction::VUNPCKHPS(y, x, dst)],
+            [a, b, c, d] if *a < 4 && *b < 4 && *c >= 4 && *d >= 4 => {
+                return vec![Instruction::VSHUFPS(immediate(pattern), y, x, dst)];
+            }
+            _ => {}
+        }
+
+        // Lanes `shift` to `shift + 3` of x and y one after the other.
+        if let Some(shift) = (1..4).find(|shift| (0..4).all(|i| pattern[i] == i as u32 + shift)) {
+            if !wide || target.features.has(Feature::Avx2) {
+                return vec![Instruction::VPALIGNR(
+                    Operand::Immediate(4 * shift as i32),
+                    x,
+                    y,
+                    dst,
+                )];
+            }
+        }
+    }
+
+    // Blends select every lane on its own, so the pattern is repeated
+    // for the upper half.
+    let mut select = pattern
+        .iter()
+        .enumerate()
+        .fold(0, |select, (i, lane)| select | ((lane >> 2) << i) as i32);
+    if wide {
+        select |= select << 4;
+    }
+    if pattern
+        .iter()
+        .enumerate()
+        .all(|(i, lane)| lane & 3 == i as u32)
+    {
+        return vec![Instruction::VBLENDPS(Operand::Immediate(select), y, x, dst)];
+    }
+
+    let lanes = if wide { 8 } else { 4 };
+    let (permuted_x, permuted_y) = (
+        register(lanes, TEMPORARIES[0]),
+        register(lanes, TEMPORARIES[1]),
+    );
+    vec![
+        Instruction::VPERMILPS(immediate(pattern), x, permuted_x),
+        Instruction::VPERMILPS(immediate(pattern), y, permuted_y),
+        Instruction::VBLENDPS(Operand::Immediate(select), permuted_y, permuted_x, dst),
+    ]
+}
+
+/// Select a shuffle of `lanes`, which index the lanes of the first
+/// source followed by those of the second.
+fn select_shuffle(lanes: &[u32], single: bool, target: Target) -> Selection {
+    let width = lanes.len() as u32;
+    let mut sources = [FIRST, SECOND];
+    let mut lanes = lanes.to_vec();
+    if single || lanes.iter().all(|lane| *lane < width) {
+        sources[1] = FIRST;
+    } else if lanes.iter().all(|lane| *lane >= width) {
+        sources = [SECOND, SECOND];
+        lanes.iter_mut().for_each(|lane| *lane -= width);
+    }
+    let single = sources[0] == sources[1];
+
+    if width == 4 {
+        return Selection {
+            instrs: select_in_lane(
+                &lanes,
+                register(4, sources[0]),
+                register(4, sources[1]),
+                register(4, RESULT),
+                false,
+                target,
+            ),
+            constants: vec![],
+        };
+    }
+
+    let mut candidates = select_halves(&lanes, sources, target);
+
+    // Anything else needs a full permute of each source.
+    if target.features.has(Feature::Avx2) {
+        let indices = |source: u32| -> [u32; 8] {
+            std::array::from_fn(|i| {
+                let lane = lanes[i] % 16;
+                if lane / 8 == source {
+                    lane % 8
+                } else {
+                    0
+                }
+            })
+        };
+
+        if single {
+            candidates.push(Selection {
+                instrs: vec![Instruction::VPERMPS(
+                    register(8, sources[0]),
+                    register(8, INDICES[0]),
+                    register(8, RESULT),
+                )],
+                constants: vec![(INDICES[0], indices(0))],
+            });
+        } else if target.features.has(Feature::Avx512f) {
+            // The first source is copied into the result, which the
+            // permute then merges the second source into.
+            let mut indices = [0; 8];
+            indices.copy_from_slice(&lanes);
+            candidates.push(Selection {
+                instrs: vec![
+                    Instruction::VMOVUPS(register(8, sources[0]), register(8, RESULT)),
+                    Instruction::VPERMT2PS(
+                        register(8, sources[1]),
+                        register(8, INDICES[0]),
+                        register(8, RESULT),
+                    ),
+                ],
+                constants: vec![(INDICES[0], indices)],
+            });
+        } else {
+            let select = lanes
+                .iter()
+                .enumerate()
+                .fold(0, |select, (i, lane)| select | ((lane / 8) << i) as i32);
+            let (permuted_first, permuted_second) =
+                (register(8, TEMPORARIES[0]), register(8, TEMPORARIES[1]));
+            candidates.push(Selection {
+                instrs: vec![
+                    Instruction::VPERMPS(
+                        register(8, sources[0]),
+                        register(8, INDICES[0]),
+                        permuted_first,
+                    ),
+                    Instruction::VPERMPS(
+                        register(8, sources[1]),
+                        register(8, INDICES[1]),
+                        permuted_second,
+                    ),
+                    Instruction::VBLENDPS(
+                        Operand::Immediate(select),
+                        permuted_second,
+                        permuted_first,
+                        register(8, RESULT),
+                    ),
+                ],
+                constants: vec![(INDICES[0], indices(0)), (INDICES[1], indices(1))],
+            });
+        }
+    }
+
+    candidates
+        .into_iter()
+        .min_by_key(|selection| selection.cost())
+        .expect("blocks only shuffle across halves on targets with AVX2")
+}
+
+/// Select a shuffle of eight lanes where each half of the result reads
+/// from at most two halves of the sources. Those halves are first put
+/// side by side with `vperm2f128`, as the first and second operand of a
+/// shuffle within halves, which has to be the same for both halves.
+fn select_halves(lanes: &[u32], sources: [u8; 2], target: Target) -> Vec<Selection> {
+    // The halves of the sources are numbered 0 to 3, like the operands
+    // of `vperm2f128`.
+    let halves: Vec<Vec<u32>> = lanes
+        .chunks(4)
+        .map(|half| {
+            let mut read: Vec<u32> = vec![];
+            for lane in half.iter() {
+                if !read.contains(&(lane / 4)) {
+                    read.push(lane / 4);
+                }
+            }
+            read
+        })
+        .collect();
+    if halves.iter().any(|read| read.len() > 2) {
+        return vec![];
+    }
+
+    let orders = |read: &Vec<u32>| -> Vec<(u32, u32)> {
+        match read[..] {
+            [half] => vec![(half, half)],
+            [first, second] => vec![(first, second), (second, first)],
+            _ => unreachable!(),
+        }
+    };
+
+    // A pair of halves as a register, which is one of the sources when
+    // the halves are those of a source in order.
+    let arrange = |pair: [u32; 2], temporary: u8, instrs: &mut Vec<Instruction>| -> Operand {
+        match pair {
+            [0, 1] => register(8, sources[0]),
+            [2, 3] => register(8, sources[1]),
+            _ => {
+                let dst = register(8, temporary);
+                instrs.push(Instruction::VPERM2F128(
+                    Operand::Immediate((pair[0] | pair[1] << 4) as i32),
+                    register(8, sources[1]),
+                    register(8, sources[0]),
+                    dst,
+                ));
+                dst
+            }
+        }
+    };
+
+    let mut candidates = vec![];
+    for (low_x, low_y) in orders(&halves[0]) {
+        for (high_x, high_y) in orders(&halves[1]) {
+            let pattern = |half: &[u32], x: u32| -> Vec<u32> {
+                half.iter()
+                    .map(|lane| {
+                        if lane / 4 == x {
+                            lane % 4
+                        } else {
+                            4 + lane % 4
+                        }
+                    })
+                    .collect()
+            };
+            let pattern_low = pattern(&lanes[..4], low_x);
+            if pattern_low != pattern(&lanes[4..], high_x) {
+                continue;
+            }
+
+            let mut instrs = vec![];
+            let identity = pattern_low == [0, 1, 2, 3];
+            if identity {
+                // The halves only have to be put in place.
+                let x = arrange([low_x, high_x], RESULT, &mut instrs);
+                if instrs.is_empty() {
+                    instrs.push(Instruction::VMOVUPS(x, register(8, RESULT)));
+                }
+            } else {
+                let x = arrange([low_x, high_x], TEMPORARIES[0], &mut instrs);
+                let y = if pattern_low.iter().any(|lane| *lane >= 4) {
+                    arrange([low_y, high_y], TEMPORARIES[1], &mut instrs)
+                } else {
+                    x
+                };
+                instrs.extend(select_in_lane(
+                    &pattern_low,
+                    x,
+                    y,
+                    register(8, RESULT),
+                    true,
+                    target,
+                ));
+            }
+
+            candidates.push(Selection {
+                instrs,
+                constants: vec![],
+            });
+        }
+    }
+
+    candidates
+}
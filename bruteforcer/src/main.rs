@@ -1,56 +1,155 @@
-use std::{io::stderr, process::exit};
+use std::process::exit;
 
-use args::BruteforcerCmds;
+use args::{BruteforcerCmds, PatternFamily, TargetModel};
 use clap::Parser;
-use encodings::Architecture;
 use itertools::Itertools;
-use libc::fprintf;
 use playground::Playground;
+use rand::{rngs::StdRng, SeedableRng};
 
 use crate::{
-    encodings::{CEncoder, SerializeAMD64MachineCode},
+    abstract_instructions::{in_place::lower_in_place_amd64, InstructionBlock},
+    bench::{read_corpus, run_bench},
+    encodings::Target,
+    features::FeatureSet,
     instructions_x86_64::Instruction,
-    optimize::ShiftMask,
+    optimize::{RandomConfig, ShiftMask},
+    regalloc::lower_amd64_blocks,
+    report::{read_results, Report, ReportConfig},
+    targets::IsaFamily,
+    verify::{check_output, compile_and_run, emit_c_program, generate_c_blocks, scratch_dir},
+    vla::simulate_vla,
 };
 
 mod abstract_instructions;
+mod analyze;
 mod args;
+mod bench;
+mod counters;
 mod encodings;
+mod families;
+mod features;
 mod instructions_x86_64;
+mod ir;
+mod isel;
 mod optimize;
+mod overlap;
+mod peephole;
 mod playground;
+mod recognize;
+mod regalloc;
+mod report;
+mod reroll;
+mod schedule;
+mod targets;
+mod tiling;
+mod verify;
+mod vla;
 
 fn main() {
     let args = args::BruteforcerArgs::parse();
-    let arch: Architecture;
-    match Architecture::try_from(args.arch) {
-        Ok(a) => arch = a,
-        Err(e) => {
-            println!("{}", e);
+
+    if args.list_targets {
+        print!("{}", TargetModel::list());
+        return;
+    }
+
+    let Some(model) = args.target.or_else(TargetModel::host) else {
+        println!("no target model matches the host, pick one with --target");
+        exit(1);
+    };
+    let features = match (args.target_features, args.target) {
+        (Some(_), _) if model.description().family != IsaFamily::X86_64 => {
+            println!("target features can only be given for x86-64 targets");
+            exit(1);
+        }
+        (Some(features), _) => FeatureSet::from(&features[..]),
+        (None, Some(model)) => model.features(),
+        (None, None) => model.host_features(),
+    };
+    let target = Target::new(model, features);
+    if args.in_place && target.is_vector_length_agnostic() {
+        println!("in place code can't be generated for {}", model);
+        exit(1);
+    }
+
+    let tile = (args.tile || args.tile_size.is_some()).then(|| {
+        let mut config = model.description().tile;
+        if let Some(size) = args.tile_size {
+            config.tile_bytes = size;
+        }
+        config
+    });
+
+    // Every subcommand relies on the pattern being a permutation, like
+    // those of a corpus have to be.
+    if let Some(pattern) = &args.pattern {
+        if !ShiftMask::from(pattern.clone()).is_permutation() {
+            println!("pattern {} is not a permutation", pattern.iter().join(","));
             exit(1);
         }
     }
 
-    match args.cmd {
+    let Some(cmd) = args.cmd else {
+        println!("please provide a subcommand, or --list-targets");
+        exit(1);
+    };
+
+    match cmd {
         BruteforcerCmds::Bruteforce => {
+            if !target.runs_on_host() {
+                println!(
+                    "unable to run code for {} with {} on this host, which has {}",
+                    model,
+                    features,
+                    FeatureSet::host()
+                );
+                exit(1);
+            }
+
             if let Some(mask) = args.pattern {
                 let mask: ShiftMask = mask.into();
-                let blocks = mask.optimize_to_blocks(255);
-                let pg: Playground;
-                unsafe {
-                    pg = Playground::new(4096);
+
+                if args.in_place {
+                    let mut program_buffer: Vec<u8> = vec![];
+                    let steps = mask.optimize_to_in_place_blocks(255, target);
+                    target.write_amd64_function(
+                        &lower_in_place_amd64(&steps, target),
+                        &mut program_buffer,
+                    );
+
+                    let pg: Playground;
+                    unsafe {
+                        pg = Playground::new(program_buffer.len().next_multiple_of(4096) as u32);
+                    }
+
+                    println!(
+                        "in place program is correct: {}",
+                        pg.run_is_correct_in_place(&program_buffer, &mask)
+                    );
+                    return;
                 }
 
+                let blocks: Vec<InstructionBlock> = mask
+                    .optimize_to_blocks(255)
+                    .into_iter()
+                    .flat_map(|blk| blk.fit_to(target))
+                    .collect();
+                // Orders of the blocks can lower to programs of different
+                // lengths, the playground grows to fit the largest so far.
+                let mut pg: Option<Playground> = None;
                 let mut program_buffer: Vec<u8> = vec![];
 
-                for blks in blocks.iter().permutations(blocks.len()) {
-                    Instruction::RDTSC.write_amd64_bytes(&mut program_buffer);
-                    for blk in blks.iter() {
-                        blk.write_amd64_bytes(&mut program_buffer);
+                for blks in blocks.iter().copied().permutations(blocks.len()) {
+                    let mut instrs = vec![Instruction::RDTSC];
+                    instrs.append(&mut lower_amd64_blocks(&blks, target));
+                    instrs.push(Instruction::RDTSC);
+                    target.write_amd64_function(&instrs, &mut program_buffer);
+
+                    let size = program_buffer.len().next_multiple_of(4096) as u32;
+                    if pg.as_ref().is_none_or(|pg| pg.size() < size) {
+                        pg = Some(unsafe { Playground::new(size) });
                     }
-                    Instruction::RDTSC.write_amd64_bytes(&mut program_buffer);
-                    Instruction::RET.write_amd64_bytes(&mut program_buffer);
-                    let _output = pg.run_is_correct(&program_buffer, &mask);
+                    let _output = pg.as_ref().unwrap().run_is_correct(&program_buffer, &mask);
                     program_buffer.clear();
                 }
             } else {
@@ -60,18 +159,275 @@ fn main() {
         BruteforcerCmds::SimpleCFunc => {
             if let Some(mask) = args.pattern {
                 let mask: ShiftMask = mask.into();
-                let blocks = mask.optimize_to_blocks(255);
 
-                for (i, block) in blocks.iter().enumerate() {
-                    eprint!("{:?}", block);
-                    print!("{}", block.encode_to_c(i as u32, arch));
+                for block in
+                    generate_c_blocks(&mask, target, args.in_place, tile, args.unroll).iter()
+                {
+                    eprint!("{}", block.label);
+                    print!("{}", block.code);
                 }
             } else {
                 println!("please provide a pattern to generate a corresponding C function body")
             }
         }
-        BruteforcerCmds::RandomPattern => {
-            println!("{}\n", ShiftMask::new_random(args.len));
+        BruteforcerCmds::RandomPattern {
+            seed,
+            weights,
+            max_displacement,
+            uniform,
+            count,
+        } => {
+            if weights.len() != 4 || (!uniform && weights.iter().all(|w| *w == 0)) {
+                println!("please provide four chunk weights, at least one of them nonzero");
+                exit(1);
+            }
+            let config = RandomConfig {
+                weights: [weights[0], weights[1], weights[2], weights[3]],
+                max_displacement,
+                uniform,
+            };
+
+            let seed = seed.unwrap_or_else(|| {
+                let seed = rand::random();
+                eprintln!("seed: {}", seed);
+                seed
+            });
+            let mut rng = StdRng::seed_from_u64(seed);
+
+            for _ in 0..count {
+                println!(
+                    "{}\n",
+                    ShiftMask::new_random_with(args.len, &config, &mut rng)
+                );
+            }
+        }
+        BruteforcerCmds::StructuredPattern { family, k, rows } => {
+            let len = args.len;
+            let mask = match family {
+                PatternFamily::Reverse => Ok(ShiftMask::new_reverse(len)),
+                PatternFamily::Rotate => ShiftMask::new_rotate(len, k),
+                PatternFamily::BlockReverse => ShiftMask::new_block_reverse(len, k),
+                PatternFamily::Transpose => {
+                    if rows == 0 || !len.is_multiple_of(rows) {
+                        Err("rows must evenly divide the pattern length".to_string())
+                    } else {
+                        Ok(ShiftMask::new_transpose(rows, len / rows))
+                    }
+                }
+                PatternFamily::BitReversal => ShiftMask::new_bit_reversal(len),
+                PatternFamily::Shuffle => ShiftMask::new_perfect_shuffle(len),
+                PatternFamily::Unshuffle => ShiftMask::new_perfect_unshuffle(len),
+                PatternFamily::StrideGather => ShiftMask::new_stride_gather(len, k),
+                PatternFamily::Butterfly => ShiftMask::new_butterfly(len, k),
+            };
+
+            match mask {
+                Ok(mask) => println!("{}\n", mask),
+                Err(e) => {
+                    println!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        BruteforcerCmds::Verify { corpus, cc, keep } => {
+            let masks: Vec<ShiftMask> = match (corpus, args.pattern) {
+                (Some(corpus), _) => {
+                    let corpus = std::fs::read_to_string(&corpus)
+                        .map_err(|e| format!("unable to read {}: {}", corpus, e))
+                        .and_then(|contents| read_corpus(&contents));
+                    match corpus {
+                        Ok(corpus) => corpus.into_iter().map(|p| p.mask).collect(),
+                        Err(e) => {
+                            println!("{}", e);
+                            exit(1);
+                        }
+                    }
+                }
+                (None, Some(pattern)) => vec![pattern.into()],
+                (None, None) => {
+                    println!("please provide a pattern or a corpus to verify");
+                    exit(1);
+                }
+            };
+            // Vector length agnostic code is run on the lane simulator at
+            // every vector length, unless there is a compiler (and a way to
+            // run its output).
+            let simulate = target.is_vector_length_agnostic() && cc.is_none();
+            let cc = cc.unwrap_or_else(|| std::env::var("CC").unwrap_or("cc".to_string()));
+
+            let dir = match scratch_dir() {
+                Ok(dir) => dir,
+                Err(e) => {
+                    println!("{}", e);
+                    exit(1);
+                }
+            };
+
+            let mut failed = false;
+            for (i, mask) in masks.iter().enumerate() {
+                let blocks = generate_c_blocks(mask, target, args.in_place, tile, args.unroll);
+
+                let outputs = if simulate {
+                    simulate_vla(target, mask.len(), &mask.optimize_to_vla_blocks())
+                        .into_iter()
+                        .map(|(bits, output)| (format!(" at {} bit vectors", bits), output))
+                        .collect()
+                } else {
+                    let source = emit_c_program(&blocks, mask.len(), target, args.in_place);
+                    match compile_and_run(&source, &dir, &cc, &target.c_flags()) {
+                        Ok(output) => vec![(String::new(), output)],
+                        Err(e) => {
+                            println!("pattern {}: {}", i, e);
+                            failed = true;
+                            continue;
+                        }
+                    }
+                };
+
+                for (at, output) in outputs.iter() {
+                    let mismatches = check_output(mask, &blocks, output);
+                    if mismatches.is_empty() {
+                        println!(
+                            "pattern {} ({} values, {} blocks){}: ok",
+                            i,
+                            mask.len(),
+                            blocks.len(),
+                            at
+                        );
+                        continue;
+                    }
+
+                    failed = true;
+                    println!(
+                        "pattern {} ({} values, {} blocks){}: {} values are wrong",
+                        i,
+                        mask.len(),
+                        blocks.len(),
+                        at,
+                        mismatches.len()
+                    );
+                    for wrong in mismatches.chunk_by(|a, b| a.block == b.block) {
+                        let block = wrong[0].block;
+                        println!("  block {} {}:", block, blocks[block].label);
+                        for m in wrong.iter() {
+                            println!(
+                                "    out[{}] = {}, expected {}",
+                                m.position, m.found, m.expected
+                            );
+                        }
+                    }
+                }
+            }
+
+            if keep {
+                println!("generated sources kept in {}", dir.display());
+            } else {
+                let _ = std::fs::remove_dir_all(&dir);
+            }
+
+            if failed {
+                exit(1);
+            }
+        }
+        BruteforcerCmds::Benchmark {
+            corpus,
+            strategies,
+            warmup,
+            iterations,
+            no_schedule,
+            output,
+            counters,
+        } => {
+            if !target.runs_on_host() {
+                println!(
+                    "unable to benchmark code for {} with {} on this host, which has {}",
+                    model,
+                    features,
+                    FeatureSet::host()
+                );
+                exit(1);
+            }
+
+            let corpus = std::fs::read_to_string(&corpus)
+                .map_err(|e| format!("unable to read {}: {}", corpus, e))
+                .and_then(|contents| read_corpus(&contents));
+            let result = corpus.and_then(|corpus| {
+                run_bench(
+                    &corpus,
+                    &strategies,
+                    target,
+                    !no_schedule,
+                    warmup,
+                    iterations,
+                    &counters,
+                    &output,
+                )
+            });
+
+            if let Err(e) = result {
+                println!("{}", e);
+                exit(1);
+            }
+        }
+        BruteforcerCmds::Report {
+            inputs,
+            baseline,
+            resamples,
+            confidence,
+            alpha,
+            seed,
+            html,
+            fail_on_regression,
+        } => {
+            if !(0.0..1.0).contains(&confidence) || !(0.0..1.0).contains(&alpha) {
+                println!("confidence and alpha must be between 0 and 1");
+                exit(1);
+            }
+
+            let runs = inputs
+                .iter()
+                .map(|input| {
+                    std::fs::read_to_string(input)
+                        .map_err(|e| format!("unable to read {}: {}", input, e))
+                        .and_then(|contents| read_results(input, &contents))
+                })
+                .collect::<Result<Vec<_>, String>>();
+            let runs = match runs {
+                Ok(runs) => runs,
+                Err(e) => {
+                    println!("{}", e);
+                    exit(1);
+                }
+            };
+
+            let config = ReportConfig {
+                baseline,
+                resamples,
+                confidence,
+                alpha,
+                seed,
+            };
+            let report = Report::new(&runs, config);
+            print!("{}", report.to_markdown());
+
+            if let Some(path) = html {
+                if let Err(e) = std::fs::write(&path, report.to_html()) {
+                    println!("unable to write {}: {}", path, e);
+                    exit(1);
+                }
+            }
+
+            if fail_on_regression && report.regressions() > 0 {
+                exit(1);
+            }
+        }
+        BruteforcerCmds::Analyze => {
+            if let Some(mask) = args.pattern {
+                let mask: ShiftMask = mask.into();
+                print!("{}", mask.analyze());
+            } else {
+                println!("please provide a pattern to analyze")
+            }
         }
         BruteforcerCmds::InputSequence => {
             for i in 0..args.len {
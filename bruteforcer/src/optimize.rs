@@ -1,12 +1,16 @@
 use std::{collections::VecDeque, fmt::Display};
 
-use rand::Rng;
-use rand::{seq::SliceRandom, thread_rng};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 
 use crate::abstract_instructions::{
-    eight::EightInstruction, four::FourInstruction, single::SingleInstruction,
-    sixteen::SixteenInstruction, InstructionBlock,
+    eight::EightInstruction, four::FourInstruction, in_place::InPlaceStep,
+    partial::PartialInstruction, single::SingleInstruction, sixteen::SixteenInstruction,
+    two_source::TwoSourceInstruction, InstructionBlock,
 };
+use crate::encodings::Target;
 
 /// A shiftmask wrapper struct.
 pub struct ShiftMask {
@@ -14,6 +18,33 @@ pub struct ShiftMask {
     values: Vec<u32>,
 }
 
+/// Parameters for generating random patterns.
+#[derive(Debug, Clone)]
+pub struct RandomConfig {
+    /// Relative weights of drawing a single value, or a shuffled run
+    /// of 4, 8 or 16 values, at each step.
+    pub weights: [u32; 4],
+    /// Largest distance between the position a run is placed at and the
+    /// values within it, or unbounded.
+    pub max_displacement: Option<u32>,
+    /// Generate a uniformly random permutation instead, as a baseline.
+    pub uniform: bool,
+}
+
+impl RandomConfig {
+    pub const CHUNK_WIDTHS: [usize; 4] = [1, 4, 8, 16];
+}
+
+impl Default for RandomConfig {
+    fn default() -> Self {
+        Self {
+            weights: [1, 1, 1, 1],
+            max_displacement: None,
+            uniform: false,
+        }
+    }
+}
+
 impl From<Vec<u32>> for ShiftMask {
     fn from(value: Vec<u32>) -> Self {
         Self { values: value }
@@ -24,13 +55,9 @@ impl Display for ShiftMask {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for (i, val) in self.values.iter().enumerate() {
             if i < self.values.len() - 1 {
-                if let Err(e) = write!(f, "{},", *val) {
-                    return Err(e);
-                }
+                write!(f, "{},", *val)?;
             } else {
-                if let Err(e) = write!(f, "{}", *val) {
-                    return Err(e);
-                }
+                write!(f, "{}", *val)?;
             }
         }
 
@@ -44,77 +71,57 @@ impl ShiftMask {
         Self { values }
     }
 
+    #[allow(unused)]
     pub fn new_random(len: u32) -> Self {
-        let mut dst_set: Vec<u32> = vec![];
-        let mut src_set: Vec<u32> = (0 as u32..len as u32).collect();
+        Self::new_random_with(len, &RandomConfig::default(), &mut StdRng::from_entropy())
+    }
 
-        while src_set.len() > 0 {
-            let len = src_set.len();
+    /// Generate a random pattern from the given generator. Unless a
+    /// uniform permutation is requested, the pattern is built front to
+    /// back by repeatedly drawing either a single remaining value, or a
+    /// run of 4, 8 or 16 remaining values that is then shuffled, so that
+    /// the pattern contains subsets that can be SIMDized.
+    ///
+    /// Panics if all of the chunk weights are zero.
+    pub fn new_random_with<R: Rng>(len: u32, config: &RandomConfig, rng: &mut R) -> Self {
+        let mut dst_set: Vec<u32> = vec![];
+        let mut src_set: Vec<u32> = (0_u32..len).collect();
 
-            match rand::thread_rng().gen_range(1..=4) {
-                1 => dst_set.push(src_set.remove(rand::thread_rng().gen_range(0..=len - 1))),
-                2 => {
-                    let base_index = rand::thread_rng().gen_range(0..=len - 1);
-                    if len >= 4 && len - base_index >= 4 {
-                        let mut loc_vec = vec![];
+        if config.uniform {
+            // This is a Fisher-Yates shuffle.
+            src_set.shuffle(rng);
+            return Self { values: src_set };
+        }
 
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
+        let chunks =
+            WeightedIndex::new(config.weights).expect("chunk weights must not all be zero");
 
-                        loc_vec.shuffle(&mut thread_rng());
-                        dst_set.append(&mut loc_vec);
-                    }
-                }
+        while !src_set.is_empty() {
+            let len = src_set.len();
+            let mut width = RandomConfig::CHUNK_WIDTHS[chunks.sample(rng)];
+            if width > len {
+                width = 1;
+            }
 
-                3 => {
-                    let base_index = rand::thread_rng().gen_range(0..=len - 1);
-                    if len >= 8 && len - base_index >= 8 {
-                        let mut loc_vec = vec![];
-
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-
-                        loc_vec.shuffle(&mut thread_rng());
-                        dst_set.append(&mut loc_vec);
-                    }
+            // The range of run starts to pick from, which is every run
+            // that fits unless the displacement of the run is bounded.
+            let last = len - width;
+            let (lo, hi) = match config.max_displacement {
+                None => (0, last),
+                Some(distance) => {
+                    let pos = dst_set.len() as u64;
+                    let distance = distance as u64;
+                    let lo = src_set.partition_point(|v| (*v as u64) + distance < pos);
+                    let hi = src_set.partition_point(|v| (*v as u64) <= pos + distance);
+                    let lo = lo.min(last);
+                    (lo, hi.saturating_sub(width).clamp(lo, last))
                 }
+            };
 
-                4 => {
-                    let base_index = rand::thread_rng().gen_range(0..=len - 1);
-                    if len >= 16 && len - base_index >= 16 {
-                        let mut loc_vec = vec![];
-
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-                        loc_vec.push(src_set.remove(base_index));
-
-                        loc_vec.shuffle(&mut thread_rng());
-                        dst_set.append(&mut loc_vec);
-                    }
-                }
-                _ => continue,
-            }
+            let base_index = rng.gen_range(lo..=hi);
+            let mut loc_vec: Vec<u32> = src_set.drain(base_index..base_index + width).collect();
+            loc_vec.shuffle(rng);
+            dst_set.append(&mut loc_vec);
         }
 
         Self { values: dst_set }
@@ -124,15 +131,23 @@ impl ShiftMask {
         self.values.len()
     }
 
+    pub fn values(&self) -> &[u32] {
+        &self.values
+    }
+
+    /// Whether every value from 0 to the length of the mask occurs in it
+    /// exactly once, which everything planning blocks relies on.
+    pub fn is_permutation(&self) -> bool {
+        let mut sorted = self.values.clone();
+        sorted.sort_unstable();
+        sorted.iter().enumerate().all(|(i, v)| i as u32 == *v)
+    }
+
     // pub const SIMD_COUNTS: [u8; 3] = [4, 8, 16];
     pub const SIMD_COUNTS: [u8; 2] = [4, 8];
 
-    pub fn permute_array_by_mask(&self, input: &Vec<u32>) -> Vec<u32> {
-        let mut output = vec![];
-
-        for _ in 0..input.len() {
-            output.push(0);
-        }
+    pub fn permute_array_by_mask(&self, input: &[u32]) -> Vec<u32> {
+        let mut output = vec![0; input.len()];
 
         for (index, value) in self.values.iter().enumerate() {
             output[*value as usize] = input[index];
@@ -141,20 +156,53 @@ impl ShiftMask {
         output
     }
 
+    /// Cover the mask with blocks. Structured regions are recognized
+    /// first, and what is left over is merged into windows of 4 and 8
+    /// values that self permute. Blocks are ordered by their first input.
     pub fn optimize_to_blocks(&self, num_iter: u8) -> VecDeque<InstructionBlock> {
+        let (structured, claimed) = self.recognize_structured();
+        let singles = self
+            .values
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !claimed[*index])
+            .map(|(index, val)| SingleInstruction::new(index as u32, *val));
+
+        let mut blocks: Vec<InstructionBlock> = self.optimize_windows(singles, num_iter).into();
+        if !structured.is_empty() {
+            blocks.extend(structured);
+            blocks.sort_by_cached_key(|blk| blk.get_first_input_index());
+        }
+
+        blocks.into()
+    }
+
+    /// Cover the mask with blocks from the window pass alone, without
+    /// recognizing structured regions first.
+    pub fn optimize_to_window_blocks(&self, num_iter: u8) -> VecDeque<InstructionBlock> {
+        let singles = self
+            .values
+            .iter()
+            .enumerate()
+            .map(|(index, val)| SingleInstruction::new(index as u32, *val));
+
+        self.optimize_windows(singles, num_iter)
+    }
+
+    /// Merge runs of single instructions into blocks of `SIMD_COUNTS`
+    /// values wherever they self permute, then merge what is left into
+    /// blocks reading from two windows, and into partial blocks.
+    fn optimize_windows(
+        &self,
+        singles: impl Iterator<Item = SingleInstruction>,
+        num_iter: u8,
+    ) -> VecDeque<InstructionBlock> {
         // check through all 4 blocks
-        let mut set1: VecDeque<InstructionBlock> = VecDeque::new();
+        let mut set1: VecDeque<InstructionBlock> = singles.map(InstructionBlock::Single).collect();
         let mut set2: VecDeque<InstructionBlock> = VecDeque::new();
 
         let mut store_vec: Vec<SingleInstruction> = vec![];
 
-        for (index, val) in self.values.iter().enumerate() {
-            set1.push_back(InstructionBlock::Single(SingleInstruction::new(
-                index as u32,
-                *val,
-            )));
-        }
-
         for simd_count in Self::SIMD_COUNTS.iter() {
             if *simd_count > num_iter {
                 continue;
@@ -163,7 +211,7 @@ impl ShiftMask {
             let mut sum = 0;
             let mut queue: Vec<InstructionBlock> = vec![];
 
-            while set1.len() > 0 {
+            while !set1.is_empty() {
                 if let Some(instr) = set1.pop_front() {
                     sum += instr.len() as u8;
                     queue.push(instr);
@@ -220,7 +268,7 @@ impl ShiftMask {
                 }
             }
 
-            while queue.len() > 0 {
+            while !queue.is_empty() {
                 let elem = queue.remove(0);
                 set2.push_back(elem);
             }
@@ -229,7 +277,142 @@ impl ShiftMask {
             set2 = VecDeque::new();
         }
 
-        set1
+        let blocks = self.merge_two_source_windows(set1, num_iter);
+        self.merge_partial_windows(blocks, num_iter)
+    }
+
+    /// Merge the singles a window can't be found for into blocks of
+    /// `SIMD_COUNTS` consecutive outputs, widest first, when their values
+    /// are read from at most two windows of the input. The blocks are
+    /// then ordered by their first input again.
+    fn merge_two_source_windows(
+        &self,
+        blocks: VecDeque<InstructionBlock>,
+        num_iter: u8,
+    ) -> VecDeque<InstructionBlock> {
+        let mut by_output: Vec<Option<SingleInstruction>> = vec![None; self.values.len()];
+        for blk in blocks.iter() {
+            if let InstructionBlock::Single(single) = blk {
+                by_output[single.value as usize] = Some(*single);
+            }
+        }
+
+        let mut merged = vec![];
+        for simd_count in Self::SIMD_COUNTS.iter().rev() {
+            if *simd_count > num_iter {
+                continue;
+            }
+
+            let width = *simd_count as usize;
+            let mut first = 0;
+            while first + width <= by_output.len() {
+                let window: Option<Vec<SingleInstruction>> =
+                    by_output[first..first + width].iter().copied().collect();
+                match window.and_then(|singles| {
+                    TwoSourceInstruction::new_from_singles(&singles, self.values.len() as u32)
+                }) {
+                    Some(two_source) => {
+                        by_output[first..first + width].fill(None);
+                        merged.push(InstructionBlock::TwoSource(two_source));
+                        first += width;
+                    }
+                    None => first += 1,
+                }
+            }
+        }
+
+        Self::replace_singles(blocks, merged, |single| {
+            by_output[single.value as usize].is_none()
+        })
+    }
+
+    /// Merge the singles that are still left into blocks of `SIMD_COUNTS`
+    /// values of which more than half self permute, widest first. Every
+    /// window of the input is tried in turn, and the most of its singles
+    /// whose values fit in a window of the output are merged.
+    fn merge_partial_windows(
+        &self,
+        blocks: VecDeque<InstructionBlock>,
+        num_iter: u8,
+    ) -> VecDeque<InstructionBlock> {
+        let len = self.values.len();
+        let mut by_input: Vec<Option<SingleInstruction>> = vec![None; len];
+        for blk in blocks.iter() {
+            if let InstructionBlock::Single(single) = blk {
+                by_input[single.index as usize] = Some(*single);
+            }
+        }
+
+        let mut merged = vec![];
+        for simd_count in Self::SIMD_COUNTS.iter().rev() {
+            let width = *simd_count as usize;
+            if *simd_count > num_iter || width > len {
+                continue;
+            }
+
+            for first in 0..=len - width {
+                let mut singles: Vec<SingleInstruction> = by_input[first..first + width]
+                    .iter()
+                    .flatten()
+                    .copied()
+                    .collect();
+                if singles.len() <= width / 2 {
+                    continue;
+                }
+
+                singles.sort_by_key(|single| single.value);
+                let (start, count) = (0..singles.len())
+                    .map(|start| {
+                        let end = singles[start].value + width as u32;
+                        let count = singles[start..]
+                            .iter()
+                            .take_while(|single| single.value < end)
+                            .count();
+                        (start, count)
+                    })
+                    .max_by_key(|(_, count)| *count)
+                    .unwrap();
+                if count <= width / 2 {
+                    continue;
+                }
+
+                let chosen = &singles[start..start + count];
+                for single in chosen.iter() {
+                    by_input[single.index as usize] = None;
+                }
+                merged.push(InstructionBlock::Partial(
+                    PartialInstruction::new_from_singles(chosen, width as u32, len as u32),
+                ));
+            }
+        }
+
+        Self::replace_singles(blocks, merged, |single| {
+            by_input[single.index as usize].is_none()
+        })
+    }
+
+    /// Replace the singles that were `merged` into new blocks with those
+    /// blocks, ordered by their first input again.
+    fn replace_singles(
+        blocks: VecDeque<InstructionBlock>,
+        new_blocks: Vec<InstructionBlock>,
+        merged: impl Fn(&SingleInstruction) -> bool,
+    ) -> VecDeque<InstructionBlock> {
+        if new_blocks.is_empty() {
+            return blocks;
+        }
+
+        let mut blocks: Vec<InstructionBlock> = blocks
+            .into_iter()
+            .filter(|blk| match blk {
+                InstructionBlock::Single(single) => !merged(single),
+                _ => true,
+            })
+            .collect();
+        blocks.extend(new_blocks);
+        blocks.sort_by_cached_key(|blk| blk.get_first_input_index());
+
+        blocks.into()
     }
 
     // Returns whether a range of elements "self permutes" on
@@ -237,13 +420,13 @@ impl ShiftMask {
     // to a single SIMD instruction.
     pub fn chunk_self_permutes(
         full_vec: &mut Vec<SingleInstruction>,
-        chunk: &Vec<InstructionBlock>,
+        chunk: &[InstructionBlock],
         simd_count: u8,
     ) -> bool {
         full_vec.clear();
 
         for blk in chunk.iter() {
-            let mut cloned: Vec<SingleInstruction> = blk.clone().into();
+            let mut cloned: Vec<SingleInstruction> = (*blk).into();
             full_vec.append(&mut cloned);
         }
 
@@ -270,9 +453,167 @@ impl ShiftMask {
         (max_src - min_src == (simd_count - 1) as i32)
             && (max_dst - min_dst == (simd_count - 1) as i32)
     }
+
+    /// Returns the cycle decomposition of this mask. Each cycle starts
+    /// at its smallest source index and follows `i, values[i],
+    /// values[values[i]], ...`, so fixed points are cycles of length one.
+    pub fn cycles(&self) -> Vec<Vec<u32>> {
+        let mut visited = vec![false; self.values.len()];
+        let mut cycles = vec![];
+
+        for start in 0..self.values.len() {
+            let mut cycle = vec![];
+            let mut pos = start;
+
+            while !visited[pos] {
+                visited[pos] = true;
+                cycle.push(pos as u32);
+                pos = self.values[pos] as usize;
+            }
+
+            if !cycle.is_empty() {
+                cycles.push(cycle);
+            }
+        }
+
+        cycles
+    }
+
+    /// Plan a program that permutes a single buffer in place. Blocks are
+    /// found by the window pass of `optimize_to_blocks`, as structured
+    /// blocks can need more registers than a temporary slot holds. They
+    /// are then ordered so that every position is read before it is
+    /// overwritten: along each cycle of the permutation, the block
+    /// reading a position has to run before the block writing to it.
+    /// When every remaining block is waiting on another one, a block is
+    /// saved by loading all of its lanes into a temporary slot, and
+    /// restored once its destination has been read. Blocks that don't
+    /// fit the target are split before ordering, as the moves of a split
+    /// block can overwrite each other's sources.
+    pub fn optimize_to_in_place_blocks(&self, num_iter: u8, target: Target) -> Vec<InPlaceStep> {
+        let blocks: Vec<InstructionBlock> = self
+            .optimize_to_window_blocks(num_iter)
+            .into_iter()
+            .flat_map(|blk| blk.fit_to(target))
+            .collect();
+
+        // The block reading from each position of the buffer.
+        let mut reader = vec![0; self.values.len()];
+        for (id, blk) in blocks.iter().enumerate() {
+            let singles: Vec<SingleInstruction> = (*blk).into();
+            for single in singles.iter() {
+                reader[single.index as usize] = id;
+            }
+        }
+
+        // The blocks that have to wait for each block to load, and the
+        // number of blocks each block is still waiting on.
+        let mut successors: Vec<Vec<usize>> = vec![vec![]; blocks.len()];
+        let mut predecessors: Vec<Vec<usize>> = vec![vec![]; blocks.len()];
+        let mut waiting = vec![0; blocks.len()];
+        for cycle in self.cycles().iter() {
+            for src in cycle.iter() {
+                let first = reader[self.values[*src as usize] as usize];
+                let second = reader[*src as usize];
+                if first != second && !successors[first].contains(&second) {
+                    successors[first].push(second);
+                    predecessors[second].push(first);
+                    waiting[second] += 1;
+                }
+            }
+        }
+
+        fn release(
+            id: usize,
+            successors: &[Vec<usize>],
+            waiting: &mut [usize],
+            ready: &mut VecDeque<usize>,
+        ) {
+            for succ in successors[id].iter() {
+                waiting[*succ] -= 1;
+                if waiting[*succ] == 0 {
+                    ready.push_back(*succ);
+                }
+            }
+        }
+
+        let mut steps = vec![];
+        let mut ready: VecDeque<usize> = (0..blocks.len()).filter(|id| waiting[*id] == 0).collect();
+        let mut saved: Vec<Option<usize>> = vec![None; blocks.len()];
+        let mut done = vec![false; blocks.len()];
+        // Which temporary slots are in use.
+        let mut slots: Vec<bool> = vec![];
+        let mut next = 0;
+        let mut seen = vec![0; blocks.len()];
+        let mut visited = 0;
+
+        loop {
+            while let Some(id) = ready.pop_front() {
+                let blk = blocks[id];
+                match saved[id] {
+                    Some(slot) => {
+                        steps.push(InPlaceStep::Restore(id as u32, blk, slot));
+                        slots[slot] = false;
+                    }
+                    None => {
+                        // Fixed points are already where they need to be.
+                        if let InstructionBlock::Single(single) = blk {
+                            if single.index == single.value {
+                                done[id] = true;
+                                continue;
+                            }
+                        }
+
+                        steps.push(InPlaceStep::Block(id as u32, blk));
+                        release(id, &successors, &mut waiting, &mut ready);
+                    }
+                }
+                done[id] = true;
+            }
+
+            while next < blocks.len() && (done[next] || saved[next].is_some()) {
+                next += 1;
+            }
+
+            if next == blocks.len() {
+                break;
+            }
+
+            // Every pending block waits on another pending block, so walking
+            // backwards from any of them has to end up going around a cycle.
+            // Saving a block on that cycle lets the rest of it drain before
+            // the block is restored, which keeps few temporaries live.
+            let mut id = next;
+            visited += 1;
+            while seen[id] != visited {
+                seen[id] = visited;
+                id = *predecessors[id]
+                    .iter()
+                    .find(|pred| !done[**pred] && saved[**pred].is_none())
+                    .unwrap();
+            }
+
+            let blk = blocks[id];
+            let slot = match slots.iter().position(|used| !used) {
+                Some(slot) => slot,
+                None => {
+                    slots.push(false);
+                    slots.len() - 1
+                }
+            };
+            slots[slot] = true;
+
+            saved[id] = Some(slot);
+            steps.push(InPlaceStep::Save(id as u32, blk, slot));
+            release(id, &successors, &mut waiting, &mut ready);
+        }
+
+        steps
+    }
 }
 
 #[test]
+#[allow(clippy::useless_vec)]
 fn test_canonical_permute() {
     let mask = ShiftMask::new(vec![3, 2, 1, 0]);
     assert_eq!(
@@ -282,6 +623,7 @@ fn test_canonical_permute() {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison, clippy::useless_vec)]
 fn test_self_permute() {
     let mut tmp_vec: Vec<SingleInstruction> = vec![];
 
@@ -344,3 +686,117 @@ fn test_dp() {
         ]
     );
 }
+
+#[test]
+fn test_cycles() {
+    let mask = ShiftMask::new(vec![1, 2, 0, 3, 5, 4]);
+    assert_eq!(mask.cycles(), vec![vec![0, 1, 2], vec![3], vec![4, 5]]);
+}
+
+#[test]
+fn test_is_permutation() {
+    assert!(ShiftMask::new(vec![1, 2, 0, 3, 5, 4]).is_permutation());
+    // Out of range, and repeated values.
+    assert!(!ShiftMask::new(vec![5, 1, 0]).is_permutation());
+    assert!(!ShiftMask::new(vec![1, 1, 0]).is_permutation());
+}
+
+#[test]
+fn test_seeded_random() {
+    let configs = [
+        RandomConfig::default(),
+        RandomConfig {
+            weights: [0, 0, 1, 1],
+            max_displacement: Some(32),
+            uniform: false,
+        },
+        RandomConfig {
+            uniform: true,
+            ..Default::default()
+        },
+    ];
+
+    for config in configs.iter() {
+        let first = ShiftMask::new_random_with(500, config, &mut StdRng::seed_from_u64(42));
+        let second = ShiftMask::new_random_with(500, config, &mut StdRng::seed_from_u64(42));
+        assert_eq!(first.values, second.values);
+
+        let mut sorted = first.values.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..500).collect::<Vec<u32>>());
+    }
+}
+
+#[test]
+fn test_two_source_windows() {
+    // Outputs 0 to 3 interleave inputs 0 to 5, and outputs 8 to 11 read
+    // from inputs 2, 3, 6 and 7, while inputs 8 to 11 are a window.
+    let mask = ShiftMask::new(vec![0, 2, 8, 10, 1, 3, 9, 11, 4, 5, 6, 7]);
+    let blocks = mask.optimize_to_blocks(255);
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(
+        blocks[0],
+        InstructionBlock::TwoSource(TwoSourceInstruction {
+            first_in: [0, 4],
+            first_out: 0,
+            width: 4,
+            lanes: [0, 4, 1, 5, 0, 0, 0, 0],
+        })
+    );
+    assert_eq!(
+        blocks[1],
+        InstructionBlock::TwoSource(TwoSourceInstruction {
+            first_in: [2, 6],
+            first_out: 8,
+            width: 4,
+            lanes: [0, 4, 1, 5, 0, 0, 0, 0],
+        })
+    );
+    assert!(matches!(blocks[2], InstructionBlock::Four(_)));
+
+    let mut singles: Vec<SingleInstruction> =
+        blocks.iter().flat_map(|blk| Vec::from(*blk)).collect();
+    singles.sort_by_key(|single| single.index);
+    assert_eq!(singles.len(), mask.len());
+    assert!(singles
+        .iter()
+        .all(|single| mask.values()[single.index as usize] == single.value));
+
+    // Without vectors, everything stays a single.
+    assert!(mask
+        .optimize_to_window_blocks(1)
+        .iter()
+        .all(|blk| blk.len() == 1));
+}
+
+#[test]
+fn test_partial_windows() {
+    // The last three values rotate, which is too few for a window but
+    // more than half of one, that is moved back within the pattern.
+    let mask = ShiftMask::new(vec![1, 0, 2, 3, 6, 4, 5]);
+    let blocks = mask.optimize_to_blocks(255);
+    assert_eq!(blocks.len(), 2);
+    assert!(matches!(blocks[0], InstructionBlock::Four(_)));
+    assert_eq!(
+        blocks[1],
+        InstructionBlock::Partial(PartialInstruction {
+            first_in: 3,
+            first_out: 3,
+            width: 4,
+            lanes: [0, 2, 3, 1, 4, 5, 6, 7],
+            stored: 0b1110,
+            overlapping: false,
+        })
+    );
+
+    let mut singles: Vec<SingleInstruction> = blocks[1].into();
+    singles.sort_by_key(|single| single.index);
+    assert_eq!(
+        singles,
+        vec![
+            SingleInstruction::new(4, 6),
+            SingleInstruction::new(5, 4),
+            SingleInstruction::new(6, 5),
+        ]
+    );
+}
@@ -0,0 +1,212 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::{
+    abstract_instructions::{
+        partial::PartialInstruction, single::SingleInstruction, InstructionBlock,
+    },
+    encodings::Target,
+};
+
+/// How a partial block ends up being stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PartialStore {
+    Masked,
+    Overlapping,
+    Singles,
+}
+
+/// Fit the blocks of an out of place permutation to the target like
+/// `InstructionBlock::fit_to`, picking the cheapest way to store every
+/// partial block by the cost model of the target: with a masked store,
+/// as scalar moves, or by storing its whole output window and leaving
+/// the blocks that write the lanes it doesn't store to overwrite them.
+///
+/// Blocks are then ordered so that every overlapping block comes before
+/// the blocks fixing its wrong lanes, keeping the given order as far as
+/// possible. A block is only made overlapping if that order exists.
+pub fn fit_overlapping(blocks: &[InstructionBlock], target: Target) -> Vec<InstructionBlock> {
+    let singles: Vec<Vec<SingleInstruction>> = blocks.iter().map(|blk| (*blk).into()).collect();
+    let len = singles
+        .iter()
+        .flatten()
+        .map(|single| single.value as usize + 1)
+        .max()
+        .unwrap_or(0);
+
+    // The block writing to each position of the output.
+    let mut writer = vec![0; len];
+    for (id, moves) in singles.iter().enumerate() {
+        for single in moves.iter() {
+            writer[single.value as usize] = id;
+        }
+    }
+
+    // Blocks that have to run after each block.
+    let mut after: Vec<Vec<usize>> = vec![vec![]; blocks.len()];
+    let mut fitted: Vec<Vec<InstructionBlock>> = vec![];
+    for (id, blk) in blocks.iter().enumerate() {
+        let InstructionBlock::Partial(partial) = blk else {
+            fitted.push(blk.fit_to(target));
+            continue;
+        };
+
+        let fixing: Vec<usize> = (0..partial.width)
+            .filter(|i| partial.stored & (1 << i) == 0)
+            .map(|i| writer[(partial.first_out + i) as usize])
+            .collect();
+        let overlapping = PartialInstruction {
+            overlapping: true,
+            ..*partial
+        };
+
+        let mut choices = partial_store_costs(partial, target);
+        choices.sort_by_key(|(_, cost)| *cost);
+        for (choice, _) in choices {
+            match choice {
+                PartialStore::Masked => {
+                    fitted.push(vec![InstructionBlock::Partial(PartialInstruction {
+                        overlapping: false,
+                        ..*partial
+                    })]);
+                }
+                PartialStore::Overlapping => {
+                    if fixing.iter().any(|fix| reaches(&after, *fix, id)) {
+                        continue;
+                    }
+                    after[id] = fixing.clone();
+                    fitted.push(vec![InstructionBlock::Partial(overlapping)]);
+                }
+                PartialStore::Singles => fitted.push(blk.fit_to(target)),
+            }
+            break;
+        }
+    }
+
+    // Kahn's algorithm, taking the first block in the given order among
+    // those that are ready.
+    let mut waiting = vec![0; blocks.len()];
+    for fix in after.iter().flatten() {
+        waiting[*fix] += 1;
+    }
+    let mut ready: BinaryHeap<Reverse<usize>> = (0..blocks.len())
+        .filter(|id| waiting[*id] == 0)
+        .map(Reverse)
+        .collect();
+
+    let mut ordered = vec![];
+    while let Some(Reverse(id)) = ready.pop() {
+        ordered.extend_from_slice(&fitted[id]);
+        for fix in after[id].iter() {
+            waiting[*fix] -= 1;
+            if waiting[*fix] == 0 {
+                ready.push(Reverse(*fix));
+            }
+        }
+    }
+
+    assert!(
+        overlapping_stores_are_fixed(&ordered, len),
+        "wrong lanes of an overlapping store are left in the output"
+    );
+    ordered
+}
+
+/// The latency of every way a partial block can be stored on the target.
+fn partial_store_costs(partial: &PartialInstruction, target: Target) -> Vec<(PartialStore, u32)> {
+    let costs = &target.description().costs;
+    let shuffle = if partial.width * 32 > 128 {
+        costs.cross_lane_shuffle
+    } else {
+        costs.shuffle
+    };
+
+    let mut choices = vec![(
+        PartialStore::Singles,
+        partial.stored.count_ones() * (costs.load + costs.store),
+    )];
+    let masked = InstructionBlock::Partial(PartialInstruction {
+        overlapping: false,
+        ..*partial
+    });
+    if let (true, Some(masked_store)) = (masked.fits(target), costs.masked_store) {
+        choices.push((PartialStore::Masked, costs.load + shuffle + masked_store));
+    }
+    let overlapping = InstructionBlock::Partial(PartialInstruction {
+        overlapping: true,
+        ..*partial
+    });
+    if overlapping.fits(target) {
+        choices.push((
+            PartialStore::Overlapping,
+            costs.load + shuffle + costs.store,
+        ));
+    }
+
+    choices
+}
+
+/// Whether block `to` has to run after block `from`.
+fn reaches(after: &[Vec<usize>], from: usize, to: usize) -> bool {
+    let mut seen = vec![false; after.len()];
+    let mut stack = vec![from];
+    while let Some(id) = stack.pop() {
+        if id == to {
+            return true;
+        }
+        if !seen[id] {
+            seen[id] = true;
+            stack.extend_from_slice(&after[id]);
+        }
+    }
+
+    false
+}
+
+/// Whether every one of the `len` positions of the output is last
+/// written by the block that stores its value, rather than as a wrong
+/// lane of an overlapping block.
+pub fn overlapping_stores_are_fixed(blocks: &[InstructionBlock], len: usize) -> bool {
+    let mut correct = vec![false; len];
+    for blk in blocks.iter() {
+        if let InstructionBlock::Partial(partial) = blk {
+            if partial.overlapping {
+                for i in 0..partial.width {
+                    correct[(partial.first_out + i) as usize] = false;
+                }
+            }
+        }
+
+        let singles: Vec<SingleInstruction> = (*blk).into();
+        for single in singles.iter() {
+            correct[single.value as usize] = true;
+        }
+    }
+
+    correct.iter().all(|c| *c)
+}
+
+#[test]
+fn test_overlapping_stores() {
+    use crate::{args::TargetModel, optimize::ShiftMask};
+
+    // The partial block of the last three values stores a wrong first
+    // lane, which the block of the first four values has to fix.
+    let mask = ShiftMask::from(vec![1, 0, 2, 3, 6, 4, 5]);
+    let blocks: Vec<InstructionBlock> = mask.optimize_to_blocks(255).into();
+    for model in [TargetModel::X86_64V3, TargetModel::Armv8Neon] {
+        let target = Target::new(model, model.features());
+        let fitted = fit_overlapping(&blocks, target);
+        assert!(fitted.iter().any(|blk| matches!(
+            blk,
+            InstructionBlock::Partial(PartialInstruction {
+                overlapping: true,
+                ..
+            })
+        )));
+        assert!(overlapping_stores_are_fixed(&fitted, 7));
+
+        let mut reversed = fitted.clone();
+        reversed.reverse();
+        assert!(!overlapping_stores_are_fixed(&reversed, 7));
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    args::Feature,
+    encodings::Target,
+    instructions_x86_64::{Instruction, Operand, Register},
+    schedule::{Effects, Location, Space},
+};
+
+/// How many instructions apart two stores can be and still be merged.
+const STORE_WINDOW: usize = 32;
+
+/// Rewrite known patterns of a lowered program into cheaper ones. Index
+/// vectors that select within 128 bit halves become immediates, permutes
+/// that leave their source as is are removed, runs of scalar copies of
+/// consecutive values become vector copies and 128 bit stores next to
+/// each other become one 256 bit store. Whatever the rewrites leave
+/// unused is removed last.
+pub fn peephole_amd64(instrs: &[Instruction], target: Target, in_place: bool) -> Vec<Instruction> {
+    let mut instrs = specialize_permutes(instrs);
+    instrs = remove_identity_permutes(&instrs);
+    if target.features.has(Feature::Avx) {
+        instrs = merge_scalar_copies(&instrs, in_place);
+        instrs = merge_stores(&instrs, in_place);
+    }
+    eliminate_dead_instructions(&instrs, in_place)
+}
+
+/// The locations that are read after each instruction, before they are
+/// written again.
+fn live_after(instrs: &[Instruction], in_place: bool) -> Vec<HashSet<Location>> {
+    let mut live: HashSet<Location> = HashSet::new();
+    let mut after = vec![HashSet::new(); instrs.len()];
+
+    for (i, instr) in instrs.iter().enumerate().rev() {
+        after[i] = live.clone();
+        let effects = Effects::of(instr, in_place);
+        for loc in effects.writes.iter() {
+            live.remove(loc);
+        }
+        live.extend(effects.reads);
+    }
+
+    after
+}
+
+/// The `vpermilps` immediate of an index vector that keeps every lane
+/// within its 128 bit half, and permutes both halves the same way.
+fn in_lane_immediate(lanes: &[u32; 8]) -> Option<i32> {
+    let in_lane = (0..8).all(|i| (lanes[i] & 7) / 4 == i as u32 / 4)
+        && (0..4).all(|i| lanes[i] & 3 == lanes[i + 4] & 3);
+    in_lane.then(|| (0..4).fold(0, |imm, i| imm | ((lanes[i] & 3) << (2 * i)) as i32))
+}
+
+/// Turn `vpermps` whose index vector is known and selects within halves
+/// into `vpermilps` with an immediate. Index vectors are known when they
+/// were loaded from the constant pool.
+fn specialize_permutes(instrs: &[Instruction]) -> Vec<Instruction> {
+    let mut constants: HashMap<u8, [u32; 8]> = HashMap::new();
+    let mut out = vec![];
+
+    for instr in instrs.iter() {
+        let rewritten = match *instr {
+            Instruction::VPERMPS(src, Operand::Register(idx), dst) => constants
+                .get(&idx.number())
+                .and_then(in_lane_immediate)
+                .map(|imm| Instruction::VPERMILPS(Operand::Immediate(imm), src, dst)),
+            _ => None,
+        };
+
+        for loc in Effects::of(instr, false).writes.iter() {
+            if let Location::Register(true, number) = *loc {
+                constants.remove(&number);
+            }
+        }
+        if let Instruction::VMOVDQU(Operand::Constant(lanes), Operand::Register(dst)) = *instr {
+            constants.insert(dst.number(), lanes);
+        }
+
+        out.push(rewritten.unwrap_or(*instr));
+    }
+
+    out
+}
+
+/// The source a permute leaves as is, if it is one.
+fn identity_source(instr: &Instruction) -> Option<Operand> {
+    let all_lanes = |dst: &Operand| match dst {
+        Operand::Register(reg) if reg.is_ymm() => 0xff,
+        _ => 0xf,
+    };
+
+    match *instr {
+        Instruction::VPERMILPS(Operand::Immediate(0xe4), src, _) => Some(src),
+        Instruction::VSHUFPS(Operand::Immediate(0xe4), src2, src1, _) if src1 == src2 => Some(src1),
+        Instruction::VBLENDPS(Operand::Immediate(0), _, src1, _) => Some(src1),
+        Instruction::VBLENDPS(Operand::Immediate(select), src2, _, dst)
+            if select == all_lanes(&dst) =>
+        {
+            Some(src2)
+        }
+        Instruction::VPERM2F128(Operand::Immediate(0x10), _, src1, _) => Some(src1),
+        Instruction::VPERM2F128(Operand::Immediate(0x32), src2, _, _) => Some(src2),
+        Instruction::VPALIGNR(Operand::Immediate(0), low, _, _) => Some(low),
+        Instruction::VPALIGNR(Operand::Immediate(16), _, high, _) => Some(high),
+        _ => None,
+    }
+}
+
+/// Remove permutes that leave their source as is, or make them a move
+/// when their result lives in another register.
+fn remove_identity_permutes(instrs: &[Instruction]) -> Vec<Instruction> {
+    instrs
+        .iter()
+        .filter_map(|instr| {
+            let Some(src) = identity_source(instr) else {
+                return Some(*instr);
+            };
+            let mut dst = None;
+            instr.map_operands(|op| {
+                dst = Some(op);
+                op
+            });
+            let dst = dst.unwrap();
+            if src == dst {
+                None
+            } else {
+                Some(Instruction::VMOVUPS(src, dst))
+            }
+        })
+        .collect()
+}
+
+/// A scalar load of the input followed by the store of the loaded value
+/// to the output, as displacements of both.
+fn scalar_copy(load: &Instruction, store: &Instruction) -> Option<(i32, i32, Register)> {
+    match (*load, *store) {
+        (
+            Instruction::MOVL(Operand::Displaced(from, Register::RDI), Operand::Register(reg)),
+            Instruction::MOVL(Operand::Register(stored), Operand::Displaced(to, Register::RSI)),
+        ) if reg == stored => Some((from, to, reg)),
+        _ => None,
+    }
+}
+
+/// Turn runs of scalar copies of consecutive values, to consecutive
+/// places, into a vector load and store through a register nothing is
+/// live in. Values that are still read after their copy are left alone.
+fn merge_scalar_copies(instrs: &[Instruction], in_place: bool) -> Vec<Instruction> {
+    let live = live_after(instrs, in_place);
+    let mut out = vec![];
+    let mut i = 0;
+
+    while i < instrs.len() {
+        let mut run = 0;
+        let mut first = None;
+        while run < 8 && i + 2 * run + 1 < instrs.len() {
+            let end = i + 2 * run + 1;
+            let Some((from, to, reg)) = scalar_copy(&instrs[end - 1], &instrs[end]) else {
+                break;
+            };
+            let (first_from, first_to) = *first.get_or_insert((from, to));
+            if from != first_from + 4 * run as i32
+                || to != first_to + 4 * run as i32
+                || live[end].contains(&Location::register(reg))
+            {
+                break;
+            }
+            run += 1;
+        }
+
+        let lanes = if run >= 8 { 8 } else { 4 };
+        let last = i + 2 * lanes - 1;
+        let free = (run >= 4)
+            .then(|| {
+                (0..16).find(|number| !live[last].contains(&Location::Register(true, *number)))
+            })
+            .flatten();
+        // Loading all values before storing any is only the same when
+        // no store writes a value a later load reads.
+        let (from, to) = first.unwrap_or((0, 0));
+        let overlaps = in_place && to > from && to < from + 4 * lanes as i32;
+
+        match free {
+            Some(number) if !overlaps => {
+                let reg = Operand::Register(if lanes == 8 {
+                    Register::ymm(number)
+                } else {
+                    Register::xmm(number)
+                });
+                out.push(Instruction::VMOVDQU(
+                    Operand::Displaced(from, Register::RDI),
+                    reg,
+                ));
+                out.push(Instruction::VMOVDQU(
+                    reg,
+                    Operand::Displaced(to, Register::RSI),
+                ));
+                i += 2 * lanes;
+            }
+            _ => {
+                out.push(instrs[i]);
+                i += 1;
+            }
+        }
+    }
+
+    out
+}
+
+/// A store of a whole xmm register to the output.
+fn xmm_store(instr: &Instruction) -> Option<(Register, i32)> {
+    match *instr {
+        Instruction::VMOVUPS(Operand::Register(reg), Operand::Displaced(displ, Register::RSI))
+            if reg.is_xmm() =>
+        {
+            Some((reg, displ))
+        }
+        _ => None,
+    }
+}
+
+/// Merge stores of xmm registers to both halves of 32 bytes of the
+/// output into one store, at the later of the two, after inserting one
+/// register into the upper half of the other. Nothing in between may
+/// touch what the earlier store writes or change its register, and one
+/// of the registers has to be unused afterwards to hold both halves.
+fn merge_stores(instrs: &[Instruction], in_place: bool) -> Vec<Instruction> {
+    let live = live_after(instrs, in_place);
+    let mut merged_into: HashMap<usize, Vec<Instruction>> = HashMap::new();
+    let mut removed = vec![false; instrs.len()];
+
+    for i in 0..instrs.len() {
+        let Some((first, first_displ)) = xmm_store(&instrs[i]) else {
+            continue;
+        };
+        if removed[i] || merged_into.contains_key(&i) {
+            continue;
+        }
+        let stored = Effects::of(&instrs[i], in_place).writes;
+
+        for j in i + 1..instrs.len().min(i + STORE_WINDOW) {
+            if let Some((second, second_displ)) = xmm_store(&instrs[j]) {
+                let free = [first, second]
+                    .into_iter()
+                    .find(|reg| !live[j].contains(&Location::register(*reg)));
+                if (second_displ - first_displ).abs() == 16
+                    && !removed[j]
+                    && !merged_into.contains_key(&j)
+                {
+                    if let Some(free) = free {
+                        let (low, high) = if first_displ < second_displ {
+                            (first, second)
+                        } else {
+                            (second, first)
+                        };
+                        let dst = Operand::Register(Register::ymm(free.number()));
+                        merged_into.insert(
+                            j,
+                            vec![
+                                Instruction::VINSERTF128(
+                                    Operand::Immediate(1),
+                                    Operand::Register(high),
+                                    Operand::Register(Register::ymm(low.number())),
+                                    dst,
+                                ),
+                                Instruction::VMOVUPS(
+                                    dst,
+                                    Operand::Displaced(
+                                        first_displ.min(second_displ),
+                                        Register::RSI,
+                                    ),
+                                ),
+                            ],
+                        );
+                        removed[i] = true;
+                        break;
+                    }
+                }
+            }
+
+            let effects = Effects::of(&instrs[j], in_place);
+            let touches = effects.barrier
+                || effects.writes.contains(&Location::register(first))
+                || effects
+                    .reads
+                    .iter()
+                    .chain(effects.writes.iter())
+                    .any(|loc| stored.contains(loc))
+                || effects.wide_reads.iter().any(|space| {
+                    stored
+                        .iter()
+                        .any(|loc| matches!(loc, Location::Element(s, _) if s == space))
+                });
+            if touches {
+                break;
+            }
+        }
+    }
+
+    let mut out = vec![];
+    for (i, instr) in instrs.iter().enumerate() {
+        if let Some(instrs) = merged_into.remove(&i) {
+            out.extend(instrs);
+        } else if !removed[i] {
+            out.push(*instr);
+        }
+    }
+    out
+}
+
+/// Remove instructions that only write registers and stack elements
+/// nothing reads afterwards. The pointers to the buffers and the stack
+/// pointer are live throughout.
+fn eliminate_dead_instructions(instrs: &[Instruction], in_place: bool) -> Vec<Instruction> {
+    let pinned = [Register::RSP, Register::RDI, Register::RSI].map(Location::register);
+    let mut live: HashSet<Location> = HashSet::new();
+    let mut dead = vec![false; instrs.len()];
+
+    for (i, instr) in instrs.iter().enumerate().rev() {
+        let effects = Effects::of(instr, in_place);
+        let removable = !effects.barrier
+            && !effects.writes.is_empty()
+            && effects.writes.iter().all(|loc| match loc {
+                Location::Register(_, _) | Location::Mask(_) => !pinned.contains(loc),
+                Location::Element(space, _) => *space == Space::Stack,
+            });
+        if removable && effects.writes.iter().all(|loc| !live.contains(loc)) {
+            dead[i] = true;
+            continue;
+        }
+
+        for loc in effects.writes.iter() {
+            live.remove(loc);
+        }
+        live.extend(effects.reads);
+    }
+
+    instrs
+        .iter()
+        .zip(dead)
+        .filter(|(_, dead)| !dead)
+        .map(|(instr, _)| *instr)
+        .collect()
+}
+
+#[test]
+fn test_rewrites_are_correct() {
+    use crate::{
+        abstract_instructions::{
+            four::FourInstruction, single::SingleInstruction, InstructionBlock,
+        },
+        args::TargetModel,
+        optimize::ShiftMask,
+        playground::assert_runs_correctly,
+        regalloc::lower_amd64_blocks,
+    };
+
+    // The rewrites are checked for AVX2, and only run where the host has it.
+    let model = TargetModel::X86_64V3;
+    let target = Target::new(model, model.features());
+
+    // Eight values copied one at a time.
+    let singles: Vec<InstructionBlock> = (0..8)
+        .map(|i| InstructionBlock::Single(SingleInstruction::new(i, i)))
+        .collect();
+    let instrs = lower_amd64_blocks(&singles, target);
+    assert_eq!(instrs.len(), 2);
+    assert_runs_correctly(
+        &instrs,
+        target,
+        &ShiftMask::from((0..8).collect::<Vec<u32>>()),
+        false,
+    );
+
+    // Two windows of four reversed, stored next to each other.
+    let windows: Vec<InstructionBlock> = (0..2)
+        .map(|w| {
+            let singles: Vec<InstructionBlock> = [1, 0, 3, 2]
+                .iter()
+                .map(|i| InstructionBlock::Single(SingleInstruction::new(4 * w + i, 4 * w + 3 - i)))
+                .collect();
+            InstructionBlock::Four(FourInstruction::new_from_instr(singles).unwrap())
+        })
+        .collect();
+    let mask = ShiftMask::from(vec![3, 2, 1, 0, 7, 6, 5, 4]);
+    let instrs = lower_amd64_blocks(&windows, target);
+    assert!(instrs
+        .iter()
+        .any(|instr| matches!(instr, Instruction::VINSERTF128(_, _, _, _))));
+    assert_runs_correctly(&instrs, target, &mask, false);
+
+    // A permute within halves through an index vector, followed by one
+    // that leaves its source as is.
+    let (src, idx, dst) = (Register::YMM0, Register::YMM14, Register::YMM1);
+    let lanes = [1, 0, 3, 2, 5, 4, 7, 6];
+    let instrs = [
+        Instruction::VMOVDQU(Operand::Constant(lanes), Operand::Register(idx)),
+        Instruction::VMOVUPS(Operand::Memory(Register::RDI), Operand::Register(src)),
+        Instruction::VPERMPS(
+            Operand::Register(src),
+            Operand::Register(idx),
+            Operand::Register(dst),
+        ),
+        Instruction::VPERMILPS(
+            Operand::Immediate(0xe4),
+            Operand::Register(dst),
+            Operand::Register(dst),
+        ),
+        Instruction::VMOVUPS(Operand::Register(dst), Operand::Memory(Register::RSI)),
+    ];
+    let rewritten = peephole_amd64(&instrs, target, false);
+    assert_eq!(rewritten.len(), 3);
+    assert!(matches!(
+        rewritten[1],
+        Instruction::VPERMILPS(Operand::Immediate(0xb1), _, _)
+    ));
+    let mask = ShiftMask::from(lanes.to_vec());
+    assert_runs_correctly(&instrs, target, &mask, false);
+    assert_runs_correctly(&rewritten, target, &mask, false);
+}
@@ -1,9 +1,21 @@
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{_mm_lfence, _rdtsc};
 use core::mem::size_of;
 use std::mem::transmute;
+#[cfg(not(target_arch = "x86_64"))]
+use std::time::Instant;
 
 use libc::{c_int, c_void};
 
-use crate::optimize::ShiftMask;
+use crate::{counters::PerfCounters, optimize::ShiftMask};
+
+/// A single timed call of a program.
+pub struct Measurement {
+    /// Time stamp counter ticks taken by the call.
+    pub ticks: u64,
+    /// Readings of the counters, `None` where one isn't available.
+    pub counters: Vec<Option<u64>>,
+}
 
 pub struct Playground {
     raw_memory: *mut c_void,
@@ -25,7 +37,7 @@ impl Playground {
             0,
         );
 
-        if pointer == 0x0 as *mut c_void {
+        if pointer == libc::MAP_FAILED {
             panic!("unable to allocate address space for program testing");
         }
 
@@ -35,36 +47,67 @@ impl Playground {
         }
     }
 
+    /// The number of bytes a program can take up.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
     // Provide raw bytes to copy over to executable memory, and run, return the output array
-    fn run(&self, func: &Vec<u8>, input: &Vec<u32>) -> Vec<u32> {
-        let runner: extern "C" fn(*mut c_int, *mut c_int);
-        let f_input: *mut c_int;
-        let f_output: *mut c_int;
+    fn run(&self, func: &[u8], input: &[u32]) -> Vec<u32> {
+        self.execute(func, input, false)
+    }
+
+    // Same as run, but hand the program the same buffer as both its
+    // input and output, so that it has to permute the data in place.
+    fn run_in_place(&self, func: &[u8], input: &[u32]) -> Vec<u32> {
+        self.execute(func, input, true)
+    }
+
+    // Place a program into executable memory, and return it as a function.
+    fn load(&self, func: &[u8]) -> extern "C" fn(*mut c_int, *mut c_int) {
+        if func.len() > self.size as usize {
+            panic!(
+                "program of {} bytes does not fit into a playground of {} bytes",
+                func.len(),
+                self.size
+            );
+        }
 
         // Clear out our memory region before placing some new crap there.
-        // println!("clearing/allocating memory now");
         unsafe {
             libc::memset(self.raw_memory, 0x00, self.size as usize);
-            f_input = libc::calloc(input.len() as usize, size_of::<i32>()) as *mut c_int;
-            f_output = libc::calloc(input.len() as usize, size_of::<i32>()) as *mut c_int;
-        }
-
-        // Placing inputs in memory.
-        for (i, val) in input.iter().enumerate() {
-            unsafe {
-                *f_input.offset(i as isize) = *val as i32;
-            }
         }
 
         let byte_ptr = self.raw_memory as *mut u8;
         // Placing program in memory
         for (i, byte) in func.iter().enumerate() {
-            unsafe { *byte_ptr.offset(i as isize) = *byte }
+            unsafe { *byte_ptr.add(i) = *byte }
         }
 
-        // println!("transmuting function now: {:?}, {:?}", f_input, f_output);
+        unsafe { transmute::<*mut c_void, extern "C" fn(*mut c_int, *mut c_int)>(self.raw_memory) }
+    }
+
+    fn execute(&self, func: &[u8], input: &[u32], in_place: bool) -> Vec<u32> {
+        let f_input: *mut c_int;
+        let f_output: *mut c_int;
+
+        let runner = self.load(func);
+
+        // println!("allocating memory now");
         unsafe {
-            runner = transmute(self.raw_memory);
+            f_input = libc::calloc(input.len(), size_of::<i32>()) as *mut c_int;
+            f_output = if in_place {
+                f_input
+            } else {
+                libc::calloc(input.len(), size_of::<i32>()) as *mut c_int
+            };
+        }
+
+        // Placing inputs in memory.
+        for (i, val) in input.iter().enumerate() {
+            unsafe {
+                *f_input.add(i) = *val as i32;
+            }
         }
 
         // println!("running function now");
@@ -76,26 +119,96 @@ impl Playground {
         for (i, _) in input.iter().enumerate() {
             let retval: i32;
             unsafe {
-                retval = *f_output.offset(i as isize);
+                retval = *f_output.add(i);
             }
             out.push(retval as u32);
         }
 
         unsafe {
             libc::free(f_input as *mut c_void);
-            libc::free(f_output as *mut c_void);
+            if !in_place {
+                libc::free(f_output as *mut c_void);
+            }
         }
 
         out
     }
 
-    pub fn run_is_correct(&self, func: &Vec<u8>, shift: &ShiftMask) -> bool {
+    /// Time a program on an array of `len` values, returning the time
+    /// stamp counter ticks taken by each of `iterations` calls after
+    /// `warmup` untimed calls, along with the readings of `counters`.
+    /// The same buffers are used for every call.
+    pub fn time(
+        &self,
+        func: &[u8],
+        len: usize,
+        in_place: bool,
+        warmup: u32,
+        iterations: u32,
+        counters: &PerfCounters,
+    ) -> Vec<Measurement> {
+        let runner = self.load(func);
+        let mut input: Vec<c_int> = (0..len as c_int).collect();
+        let mut output: Vec<c_int> = vec![0; len];
+        let (f_input, f_output) = if in_place {
+            (input.as_mut_ptr(), input.as_mut_ptr())
+        } else {
+            (input.as_mut_ptr(), output.as_mut_ptr())
+        };
+
+        for _ in 0..warmup {
+            runner(f_input, f_output);
+        }
+
+        (0..iterations)
+            .map(|_| {
+                counters.start();
+                let ticks = ticks(|| runner(f_input, f_output));
+                let counters = counters.stop();
+
+                Measurement { ticks, counters }
+            })
+            .collect()
+    }
+
+    pub fn run_is_correct(&self, func: &[u8], shift: &ShiftMask) -> bool {
         let permute_in = (1..(shift.len() + 1) as u32).collect::<Vec<u32>>();
         let res = self.run(func, &permute_in);
         let permute_out = shift.permute_array_by_mask(&permute_in);
 
         permute_out.eq(&res)
     }
+
+    pub fn run_is_correct_in_place(&self, func: &[u8], shift: &ShiftMask) -> bool {
+        let permute_in = (1..(shift.len() + 1) as u32).collect::<Vec<u32>>();
+        let res = self.run_in_place(func, &permute_in);
+        let permute_out = shift.permute_array_by_mask(&permute_in);
+
+        permute_out.eq(&res)
+    }
+}
+
+/// The time stamp counter ticks taken by `f`. The reads are fenced to
+/// keep `f` from starting before the first, or the second from running
+/// ahead of `f`.
+#[cfg(target_arch = "x86_64")]
+fn ticks(f: impl FnOnce()) -> u64 {
+    unsafe {
+        _mm_lfence();
+        let start = _rdtsc();
+        _mm_lfence();
+        f();
+        _mm_lfence();
+        _rdtsc() - start
+    }
+}
+
+/// Without a time stamp counter, nanoseconds stand in for ticks.
+#[cfg(not(target_arch = "x86_64"))]
+fn ticks(f: impl FnOnce()) -> u64 {
+    let start = Instant::now();
+    f();
+    start.elapsed().as_nanos() as u64
 }
 
 impl Drop for Playground {
@@ -103,3 +216,205 @@ impl Drop for Playground {
         unsafe { libc::munmap(self.raw_memory, self.size as usize) };
     }
 }
+
+/// Run the code of `instrs` on a mask and assert it permutes correctly.
+/// Code the host can't run is left unchecked.
+#[cfg(test)]
+pub fn assert_runs_correctly(
+    instrs: &[crate::instructions_x86_64::Instruction],
+    target: crate::encodings::Target,
+    mask: &ShiftMask,
+    in_place: bool,
+) {
+    if !target.runs_on_host() {
+        return;
+    }
+
+    let mut program = vec![];
+    target.write_amd64_function(instrs, &mut program);
+    let pg = unsafe { Playground::new(program.len().next_multiple_of(4096) as u32) };
+    let correct = if in_place {
+        pg.run_is_correct_in_place(&program, mask)
+    } else {
+        pg.run_is_correct(&program, mask)
+    };
+    assert!(correct, "pattern {} on {:?}", mask, target.model);
+}
+
+#[test]
+fn test_blocks_are_correct() {
+    use crate::{
+        abstract_instructions::InstructionBlock,
+        args::{Feature, TargetModel},
+        encodings::Target,
+        features::FeatureSet,
+        optimize::RandomConfig,
+        regalloc::lower_amd64_blocks,
+        schedule::schedule_amd64,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // Blocks that need more than the target has fall back to scalar moves.
+    let targets = [
+        Target::host(),
+        Target::new(TargetModel::X86_64V3, TargetModel::X86_64V3.features()),
+        Target::new(TargetModel::X86_64V3, FeatureSet::from(&[Feature::Avx][..])),
+        Target::new(TargetModel::X86_64V2, TargetModel::X86_64V2.features()),
+        Target::new(TargetModel::X86_64V2, FeatureSet::empty()),
+    ];
+
+    for target in targets.iter().filter(|target| target.runs_on_host()) {
+        for len in [10, 50, 250] {
+            let mask = ShiftMask::new_random_with(
+                len,
+                &RandomConfig::default(),
+                &mut StdRng::seed_from_u64(len as u64),
+            );
+            let blocks: Vec<InstructionBlock> = mask.optimize_to_blocks(255).into();
+            let instrs = lower_amd64_blocks(&blocks, *target);
+            let scheduled = schedule_amd64(&instrs, &target.description().costs, false);
+
+            for instrs in [instrs, scheduled] {
+                assert_runs_correctly(&instrs, *target, &mask, false);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_families_are_correct() {
+    use crate::{
+        abstract_instructions::InstructionBlock, encodings::Target, regalloc::lower_amd64_blocks,
+        schedule::schedule_amd64,
+    };
+
+    let target = Target::host();
+
+    let masks = [
+        ShiftMask::new_reverse(100),
+        ShiftMask::new_rotate(100, 13).unwrap(),
+        ShiftMask::new_block_reverse(96, 4).unwrap(),
+        ShiftMask::new_transpose(8, 8),
+        ShiftMask::new_bit_reversal(64).unwrap(),
+        ShiftMask::new_perfect_shuffle(64).unwrap(),
+        ShiftMask::new_stride_gather(64, 4).unwrap(),
+        ShiftMask::new_butterfly(64, 3).unwrap(),
+        ShiftMask::new_transpose(12, 8),
+        ShiftMask::from(vec![5, 6, 7, 0, 1, 2, 3, 4, 10, 11, 8, 9, 12, 13, 14, 15]),
+    ];
+
+    for mask in masks.iter() {
+        let blocks: Vec<InstructionBlock> = mask.optimize_to_blocks(255).into();
+        let instrs = schedule_amd64(
+            &lower_amd64_blocks(&blocks, target),
+            &target.description().costs,
+            false,
+        );
+        assert_runs_correctly(&instrs, target, mask, false);
+    }
+}
+
+#[test]
+fn test_in_place_is_correct() {
+    use crate::{
+        abstract_instructions::in_place::lower_in_place_amd64,
+        args::{Feature, TargetModel},
+        encodings::Target,
+        features::FeatureSet,
+        optimize::RandomConfig,
+        schedule::schedule_amd64,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let targets = [
+        Target::host(),
+        Target::new(TargetModel::X86_64V3, FeatureSet::from(&[Feature::Avx][..])),
+        Target::new(TargetModel::X86_64V2, TargetModel::X86_64V2.features()),
+    ];
+
+    for target in targets.iter().filter(|target| target.runs_on_host()) {
+        for len in [10, 50, 250] {
+            let mask = ShiftMask::new_random_with(
+                len,
+                &RandomConfig::default(),
+                &mut StdRng::seed_from_u64(len as u64),
+            );
+            let steps = mask.optimize_to_in_place_blocks(255, *target);
+            let instrs = lower_in_place_amd64(&steps, *target);
+            let scheduled = schedule_amd64(&instrs, &target.description().costs, true);
+
+            for instrs in [instrs, scheduled] {
+                assert_runs_correctly(&instrs, *target, &mask, true);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_spilled_blocks_are_correct() {
+    use crate::{
+        abstract_instructions::{
+            in_place::{lower_in_place_amd64, InPlaceStep},
+            reverse::ReverseInstruction,
+            InstructionBlock,
+        },
+        encodings::Target,
+        instructions_x86_64::{Instruction, Operand, Register},
+    };
+
+    // Reverse each window of four values into the next one, saving all
+    // twenty windows before storing any, which is more than fit in the
+    // registers.
+    let target = Target::host();
+    let windows = 20;
+    let values: Vec<u32> = (0..4 * windows)
+        .map(|i| 4 * ((i / 4 + 1) % windows) + 3 - i % 4)
+        .collect();
+    let blocks: Vec<InstructionBlock> = (0..windows)
+        .map(|w| {
+            InstructionBlock::Reverse(ReverseInstruction::new(4 * w, 4 * ((w + 1) % windows), 4))
+        })
+        .collect();
+    let mut steps: Vec<InPlaceStep> = blocks
+        .iter()
+        .enumerate()
+        .map(|(w, blk)| InPlaceStep::Save(w as u32, *blk, w))
+        .collect();
+    steps.extend(
+        blocks
+            .iter()
+            .enumerate()
+            .map(|(w, blk)| InPlaceStep::Restore(w as u32, *blk, w)),
+    );
+
+    let instrs = lower_in_place_amd64(&steps, target);
+    assert!(instrs.iter().any(|instr| matches!(
+        instr,
+        Instruction::VMOVUPS(Operand::Register(_), Operand::Displaced(_, Register::RSP))
+    )));
+
+    assert_runs_correctly(&instrs, target, &ShiftMask::from(values), true);
+}
+
+#[test]
+fn test_masked_stores_are_correct() {
+    use crate::{
+        abstract_instructions::InstructionBlock, args::TargetModel, encodings::Target,
+        instructions_x86_64::Instruction, regalloc::lower_amd64_blocks,
+    };
+
+    // The last three values rotate within a window they only partly
+    // fill, which AVX-512 stores under a mask register.
+    let mask = ShiftMask::from(vec![1, 0, 2, 3, 6, 4, 5]);
+    let blocks: Vec<InstructionBlock> = mask.optimize_to_blocks(255).into();
+    for model in [TargetModel::X86_64V3, TargetModel::X86_64V4] {
+        let target = Target::new(model, model.features());
+        let instrs = lower_amd64_blocks(&blocks, target);
+        assert!(instrs.iter().any(|instr| matches!(
+            (model, instr),
+            (TargetModel::X86_64V3, Instruction::VMASKMOVPS(_, _, _))
+                | (TargetModel::X86_64V4, Instruction::VMOVUPSK(_, _, _))
+        )));
+        assert_runs_correctly(&instrs, target, &mask, false);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::{
+    abstract_instructions::{
+        bit_reverse::BitReverseInstruction, copy::CopyInstruction, reverse::ReverseInstruction,
+        rotate::RotateInstruction, transpose::TransposeInstruction, InstructionBlock,
+    },
+    optimize::ShiftMask,
+};
+
+/// The shortest run of values moved along together that is copied on
+/// its own, shorter ones are as well off in permuted windows.
+const MIN_COPY_LEN: usize = 16;
+
+/// Reverse the low `bits` bits of `value`.
+fn reverse_bits(value: u32, bits: u32) -> u32 {
+    value.reverse_bits().checked_shr(32 - bits).unwrap_or(0)
+}
+
+impl ShiftMask {
+    /// Find the regions of this mask that are runs of values moved along
+    /// together, bit reversals, matrix transposes, or reversed or
+    /// rotated windows, which all have better instruction sequences than
+    /// a permute with an index vector (or no SIMD block at all). Returns
+    /// the structured blocks found, along with which source indices they
+    /// cover.
+    pub fn recognize_structured(&self) -> (Vec<InstructionBlock>, Vec<bool>) {
+        let len = self.values().len();
+        let mut inverse = vec![0; len];
+        for (i, val) in self.values().iter().enumerate() {
+            inverse[*val as usize] = i as u32;
+        }
+
+        let mut blocks = vec![];
+        let mut claimed = vec![false; len];
+
+        for i in 0..len {
+            if claimed[i] {
+                continue;
+            }
+
+            let mut found: Vec<InstructionBlock> =
+                self.match_copy(i, &claimed).into_iter().collect();
+            if found.is_empty() {
+                found = self.match_bit_reversal(i, &claimed);
+            }
+            if found.is_empty() {
+                found = [8, 4]
+                    .iter()
+                    .find_map(|size| self.match_transpose(i, *size, &inverse, &claimed))
+                    .map(|blk| vec![blk])
+                    .unwrap_or_default();
+            }
+            if found.is_empty() {
+                // Leave windows of 4 alone if they are part of a window of
+                // 8 that self permutes, which is a single block already.
+                let widths: &[u32] = if self.window_self_permutes(i, 8) {
+                    &[8]
+                } else {
+                    &[8, 4]
+                };
+                found = widths
+                    .iter()
+                    .find_map(|width| {
+                        self.match_reverse(i, *width, &claimed)
+                            .or_else(|| self.match_rotate(i, *width, &claimed))
+                    })
+                    .map(|blk| vec![blk])
+                    .unwrap_or_default();
+            }
+
+            for blk in found.into_iter() {
+                let singles: Vec<_> = blk.into();
+                for single in singles.iter() {
+                    claimed[single.index as usize] = true;
+                }
+                blocks.push(blk);
+            }
+        }
+
+        (blocks, claimed)
+    }
+
+    /// Whether the `width` values from `start` are all unclaimed.
+    fn window_is_free(&self, start: usize, width: usize, claimed: &[bool]) -> bool {
+        start + width <= claimed.len() && claimed[start..start + width].iter().all(|c| !c)
+    }
+
+    /// Whether the `width` values from `start` are sent to a window of
+    /// `width` values, in any order.
+    pub fn window_self_permutes(&self, start: usize, width: usize) -> bool {
+        let Some(window) = self.values().get(start..start + width) else {
+            return false;
+        };
+        let min = window.iter().min().unwrap();
+        let max = window.iter().max().unwrap();
+
+        (max - min) as usize == width - 1
+    }
+
+    /// Match a bit reversal of at least 16 values starting at `start`,
+    /// which has to send the value after the first halfway across.
+    fn match_bit_reversal(&self, start: usize, claimed: &[bool]) -> Vec<InstructionBlock> {
+        let values = self.values();
+        if start + 1 >= values.len() || values[start + 1] <= values[start] {
+            return vec![];
+        }
+
+        let first_out = values[start];
+        let half = values[start + 1] - first_out;
+        if !half.is_power_of_two() || half < 8 {
+            return vec![];
+        }
+
+        let count = 2 * half as usize;
+        let bits = count.trailing_zeros();
+        if !self.window_is_free(start, count, claimed)
+            || (0..count).any(|j| values[start + j] != first_out + reverse_bits(j as u32, bits))
+        {
+            return vec![];
+        }
+
+        // Output window `g` gathers with a stride of an eighth of the
+        // region, from the bit reversal of `g` within the rest of the bits.
+        let stride = count as u32 / 8;
+        (0..stride)
+            .map(|g| {
+                InstructionBlock::BitReverse(BitReverseInstruction::new(
+                    start as u32 + reverse_bits(g, bits - 3),
+                    stride,
+                    first_out + 8 * g,
+                ))
+            })
+            .collect()
+    }
+
+    /// Match a `size` by `size` tile of a transpose whose first row
+    /// starts at `start`. The strides are taken from where the next
+    /// value of the row and the next row of the output come from.
+    fn match_transpose(
+        &self,
+        start: usize,
+        size: u32,
+        inverse: &[u32],
+        claimed: &[bool],
+    ) -> Option<InstructionBlock> {
+        let values = self.values();
+        let first_out = values[start];
+        let next = *values.get(start + 1)?;
+        let below = *inverse.get(first_out as usize + 1)? as usize;
+        if next < first_out + size || below < start + size as usize {
+            return None;
+        }
+
+        let transpose = TransposeInstruction::new(
+            start as u32,
+            (below - start) as u32,
+            first_out,
+            next - first_out,
+            size,
+        );
+        let singles: Vec<_> = transpose.into();
+        let matches = singles.iter().all(|single| {
+            let index = single.index as usize;
+            index < values.len() && !claimed[index] && values[index] == single.value
+        });
+
+        matches.then_some(InstructionBlock::Transpose(transpose))
+    }
+
+    /// The longest run from `start` where `out[i + k] = in[i]` for a
+    /// constant `k`, if it is long enough to be copied.
+    fn match_copy(&self, start: usize, claimed: &[bool]) -> Option<InstructionBlock> {
+        let values = self.values();
+        let len = (start..values.len())
+            .take_while(|i| !claimed[*i] && values[*i] == values[start] + (i - start) as u32)
+            .count();
+
+        (len >= MIN_COPY_LEN).then_some(InstructionBlock::Copy(CopyInstruction::new(
+            start as u32,
+            values[start],
+            len as u32,
+        )))
+    }
+
+    fn match_reverse(
+        &self,
+        start: usize,
+        width: u32,
+        claimed: &[bool],
+    ) -> Option<InstructionBlock> {
+        let values = self.values();
+        if !self.window_is_free(start, width as usize, claimed) || values[start] < width - 1 {
+            return None;
+        }
+
+        let reverse = ReverseInstruction::new(start as u32, values[start] + 1 - width, width);
+        let singles: Vec<_> = reverse.into();
+        singles
+            .iter()
+            .all(|single| values[single.index as usize] == single.value)
+            .then_some(InstructionBlock::Reverse(reverse))
+    }
+
+    fn match_rotate(&self, start: usize, width: u32, claimed: &[bool]) -> Option<InstructionBlock> {
+        let values = self.values();
+        if !self.window_is_free(start, width as usize, claimed)
+            || !self.window_self_permutes(start, width as usize)
+        {
+            return None;
+        }
+
+        let first_out = *values[start..start + width as usize].iter().min().unwrap();
+        let amount = values[start] - first_out;
+        if amount == 0 {
+            return None;
+        }
+
+        let rotate = RotateInstruction::new(start as u32, first_out, width, amount);
+        let singles: Vec<_> = rotate.into();
+        singles
+            .iter()
+            .all(|single| values[single.index as usize] == single.value)
+            .then_some(InstructionBlock::Rotate(rotate))
+    }
+}
+
+#[test]
+fn test_recognize_structured() {
+    let matches = |mask: ShiftMask| {
+        let (blocks, claimed) = mask.recognize_structured();
+        assert!(claimed.iter().all(|c| *c));
+        blocks
+    };
+
+    let blocks = matches(ShiftMask::new_transpose(16, 8));
+    assert!(blocks
+        .iter()
+        .all(|blk| matches!(blk, InstructionBlock::Transpose(t) if t.size == 8)));
+    let blocks = matches(ShiftMask::new_transpose(12, 4));
+    assert!(blocks
+        .iter()
+        .all(|blk| matches!(blk, InstructionBlock::Transpose(t) if t.size == 4)));
+    let blocks = matches(ShiftMask::new_bit_reversal(256).unwrap());
+    assert!(blocks
+        .iter()
+        .all(|blk| matches!(blk, InstructionBlock::BitReverse(_))));
+    let blocks = matches(ShiftMask::new_reverse(64));
+    assert!(blocks
+        .iter()
+        .all(|blk| matches!(blk, InstructionBlock::Reverse(r) if r.width == 8)));
+    let blocks = matches(ShiftMask::new_rotate(8, 3).unwrap());
+    assert_eq!(
+        blocks,
+        vec![InstructionBlock::Rotate(RotateInstruction::new(0, 0, 8, 3))]
+    );
+
+    // The middle of the pattern is moved four places along, and what
+    // is left at either end is too short to be copied.
+    let mut values: Vec<u32> = (0..40).collect();
+    values[4..36].iter_mut().for_each(|val| *val += 4);
+    values[36..].copy_from_slice(&[4, 5, 6, 7]);
+    let (blocks, claimed) = ShiftMask::from(values).recognize_structured();
+    assert_eq!(
+        blocks,
+        vec![InstructionBlock::Copy(CopyInstruction::new(4, 8, 32))]
+    );
+    assert_eq!(claimed.iter().filter(|c| **c).count(), 32);
+
+    // Random windows are left for the window pass.
+    let mask = ShiftMask::from(vec![1, 2, 3, 0, 4, 7, 5, 6, 8]);
+    assert!(mask.recognize_structured().0.is_empty());
+}
+
+#[test]
+fn test_match_copy() {
+    use crate::{args::TargetModel, encodings::Target, verify::generate_c_blocks};
+
+    // A run of `len` values moved one place along, then the value that
+    // wraps around to the front.
+    let run = |len: usize| {
+        let mut values: Vec<u32> = (1..=len as u32).collect();
+        values.push(0);
+        ShiftMask::from(values)
+    };
+    let claimed = vec![false; MIN_COPY_LEN + 1];
+    assert_eq!(run(MIN_COPY_LEN - 1).match_copy(0, &claimed), None);
+    assert_eq!(
+        run(MIN_COPY_LEN).match_copy(0, &claimed),
+        Some(InstructionBlock::Copy(CopyInstruction::new(
+            0,
+            1,
+            MIN_COPY_LEN as u32
+        )))
+    );
+
+    // Out of place the run is copied with memcpy.
+    let model = TargetModel::X86_64V3;
+    let code: String = generate_c_blocks(
+        &run(MIN_COPY_LEN),
+        Target::new(model, model.features()),
+        false,
+        None,
+        0,
+    )
+    .iter()
+    .map(|block| block.code.clone())
+    .collect();
+    assert!(code.contains("memcpy(&out[1], &in[0], 16 * sizeof(float))"));
+}
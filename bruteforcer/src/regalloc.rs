@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+
+use crate::{
+    abstract_instructions::InstructionBlock,
+    args::Feature,
+    encodings::Target,
+    instructions_x86_64::{Instruction, Operand, Register},
+    ir::{Op, Program, VReg},
+    isel::{select_amd64, FIRST, RESULT, SECOND},
+    peephole::peephole_amd64,
+    schedule::{Effects, Location},
+};
+
+/// Caller saved registers that scalar moves rotate through. Moves of 32
+/// bits only use the low half of r8 to r11.
+const SCALAR_REGISTERS: [Register; 7] = [
+    Register::EAX,
+    Register::ECX,
+    Register::EDX,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+];
+
+/// Each spilled register gets a slot wide enough for a ymm register.
+const SLOT_SIZE: i32 = 32;
+
+/// What a register holds from one op to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    /// An index vector, which can always be loaded from the constant
+    /// pool again.
+    Constant([u32; 8]),
+    /// A virtual register that is still to be read.
+    Virtual(VReg),
+}
+
+/// The vector registers, or the scalar ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Vector,
+    Scalar,
+}
+
+#[derive(Debug)]
+struct Bank {
+    registers: Vec<Option<Value>>,
+    last_used: Vec<usize>,
+}
+
+impl Bank {
+    fn new(size: usize) -> Self {
+        Self {
+            registers: vec![None; size],
+            last_used: vec![0; size],
+        }
+    }
+}
+
+/// Assigns registers to the virtual registers of a program. Each op is
+/// selected as if it had all registers to itself, and its register
+/// numbers are renamed to registers that hold nothing live, evicting
+/// the least recently used index vector first. Virtual registers are
+/// only spilled to a stack slot when an op needs more registers than
+/// are left, the one read again last goes first.
+#[derive(Debug)]
+pub struct RegisterAllocator<'a> {
+    program: &'a Program,
+    target: Target,
+    vector: Bank,
+    scalar: Bank,
+    clock: usize,
+    /// The ops reading each virtual register, in order.
+    uses: HashMap<VReg, Vec<usize>>,
+    /// The stack slot of each virtual register that was ever spilled.
+    slots: HashMap<VReg, usize>,
+}
+
+impl<'a> RegisterAllocator<'a> {
+    pub fn new(program: &'a Program, target: Target) -> Self {
+        let mut uses: HashMap<VReg, Vec<usize>> = HashMap::new();
+        for (i, (_, op)) in program.ops.iter().enumerate() {
+            for reg in operands(op) {
+                uses.entry(reg).or_default().push(i);
+            }
+        }
+
+        Self {
+            program,
+            target,
+            vector: Bank::new(16),
+            scalar: Bank::new(SCALAR_REGISTERS.len()),
+            clock: 0,
+            uses,
+            slots: HashMap::new(),
+        }
+    }
+
+    /// Lower the whole program, allocating a stack frame when anything
+    /// had to be spilled.
+    pub fn lower(mut self) -> Vec<Instruction> {
+        let mut body = vec![];
+        for i in 0..self.program.ops.len() {
+            self.lower_op(i, &mut body);
+        }
+
+        let frame = Operand::Immediate(SLOT_SIZE * self.slots.len() as i32);
+        let mut instrs = vec![];
+        if !self.slots.is_empty() {
+            instrs.push(Instruction::SUB(frame, Operand::Register(Register::RSP)));
+        }
+        instrs.append(&mut body);
+        if !self.slots.is_empty() {
+            instrs.push(Instruction::ADD(frame, Operand::Register(Register::RSP)));
+        }
+        instrs
+    }
+
+    fn bank(&mut self, class: Class) -> &mut Bank {
+        match class {
+            Class::Vector => &mut self.vector,
+            Class::Scalar => &mut self.scalar,
+        }
+    }
+
+    fn class(&self, reg: VReg) -> Class {
+        if self.program.lanes(reg) == 1 {
+            Class::Scalar
+        } else {
+            Class::Vector
+        }
+    }
+
+    /// The machine register numbered `number` in `class`, sized for
+    /// `reg`.
+    fn register(&self, class: Class, number: u8, reg: VReg) -> Register {
+        match (class, self.program.lanes(reg)) {
+            (Class::Scalar, _) => SCALAR_REGISTERS[number as usize],
+            (Class::Vector, 4) => Register::xmm(number),
+            (Class::Vector, _) => Register::ymm(number),
+        }
+    }
+
+    /// A move between a register of `class` and its stack slot.
+    fn spill_move(&self, class: Class, src: Operand, dst: Operand) -> Instruction {
+        match class {
+            Class::Scalar => Instruction::MOVL(src, dst),
+            Class::Vector if self.target.features.has(Feature::Avx) => {
+                Instruction::VMOVUPS(src, dst)
+            }
+            Class::Vector => Instruction::MOVUPS(src, dst),
+        }
+    }
+
+    fn slot(&self, reg: VReg) -> Operand {
+        Operand::Displaced(SLOT_SIZE * self.slots[&reg] as i32, Register::RSP)
+    }
+
+    /// The next op after `i` reading `reg`, if any.
+    fn next_use(&self, reg: VReg, i: usize) -> Option<usize> {
+        self.uses
+            .get(&reg)
+            .and_then(|uses| uses.iter().find(|op| **op > i).copied())
+    }
+
+    fn lower_op(&mut self, i: usize, out: &mut Vec<Instruction>) {
+        let op = &self.program.ops[i].1;
+        let selection = select_amd64(op, self.program, self.target);
+        if selection.instrs.is_empty() {
+            return;
+        }
+        self.clock += 1;
+
+        let scalar = match (op.dst(), operands(op).first()) {
+            (Some(dst), _) => self.program.lanes(dst) == 1,
+            (None, Some(src)) => self.program.lanes(*src) == 1,
+            _ => false,
+        };
+        if scalar {
+            self.lower_scalar(i, &selection.instrs, out);
+            return;
+        }
+
+        // The registers this op reads or writes, which nothing else may
+        // be moved into until it is done.
+        let mut mapping: [Option<u8>; 16] = [None; 16];
+        let mut taken: Vec<u8> = vec![];
+        let numbers: Vec<u8> = selection
+            .instrs
+            .iter()
+            .flat_map(|instr| instr.registers())
+            .filter(is_vector)
+            .map(|reg| reg.number())
+            .collect();
+
+        for (number, reg) in [FIRST, SECOND].into_iter().zip(operands(op)) {
+            if numbers.contains(&number) {
+                let resident = self.resident(reg, i, &taken, out);
+                mapping[number as usize] = Some(resident);
+                taken.push(resident);
+            }
+        }
+
+        for (number, lanes) in selection.constants.iter() {
+            let resident =
+                (0..16).find(|r| self.vector.registers[*r] == Some(Value::Constant(*lanes)));
+            let reg = match resident {
+                Some(reg) => reg as u8,
+                None => {
+                    let reg = self.choose(Class::Vector, &taken, i, out);
+                    out.push(Instruction::VMOVDQU(
+                        Operand::Constant(*lanes),
+                        Operand::Register(Register::ymm(reg)),
+                    ));
+                    self.vector.registers[reg as usize] = Some(Value::Constant(*lanes));
+                    reg
+                }
+            };
+            mapping[*number as usize] = Some(reg);
+            taken.push(reg);
+        }
+
+        // Temporaries are written while the sources are still read,
+        // but the result is mostly only written by the last instruction,
+        // so it can reuse a source this op reads for the last time.
+        for number in numbers {
+            if number != RESULT && mapping[number as usize].is_none() {
+                let reg = self.choose(Class::Vector, &taken, i, out);
+                self.vector.registers[reg as usize] = None;
+                mapping[number as usize] = Some(reg);
+                taken.push(reg);
+            }
+        }
+        let result = Location::Register(true, RESULT);
+        let early = selection.instrs[..selection.instrs.len() - 1]
+            .iter()
+            .any(|instr| Effects::of(instr, false).writes.contains(&result));
+        if !early {
+            for reg in self.release(op, i) {
+                taken.retain(|t| *t != reg);
+            }
+        }
+
+        if let Some(dst) = op.dst() {
+            let reg = self.choose(Class::Vector, &taken, i, out);
+            self.vector.registers[reg as usize] = Some(Value::Virtual(dst));
+            mapping[RESULT as usize] = Some(reg);
+        }
+        if early {
+            self.release(op, i);
+        }
+
+        for reg in mapping.iter().flatten() {
+            self.vector.last_used[*reg as usize] = self.clock;
+        }
+        out.extend(selection.instrs.iter().map(|instr| {
+            instr.map_registers(|reg| match mapping[reg.number() as usize] {
+                Some(number) if is_vector(&reg) => reg.with_number(number),
+                _ => reg,
+            })
+        }));
+    }
+
+    /// Lower a scalar load or store, which moves its value through eax.
+    fn lower_scalar(&mut self, i: usize, instrs: &[Instruction], out: &mut Vec<Instruction>) {
+        let op = &self.program.ops[i].1;
+        let reg = match (op.dst(), operands(op).first()) {
+            (Some(dst), _) => {
+                let reg = self.choose(Class::Scalar, &[], i, out);
+                self.scalar.registers[reg as usize] = Some(Value::Virtual(dst));
+                reg
+            }
+            (None, Some(src)) => {
+                let reg = self.resident(*src, i, &[], out);
+                self.release(op, i);
+                reg
+            }
+            _ => unreachable!(),
+        };
+
+        self.scalar.last_used[reg as usize] = self.clock;
+        let scalar = SCALAR_REGISTERS[reg as usize];
+        out.extend(
+            instrs
+                .iter()
+                .map(|instr| instr.map_registers(|r| if r == Register::EAX { scalar } else { r })),
+        );
+    }
+
+    /// Free the registers of the operands op `i` reads for the last
+    /// time, returning those that were freed.
+    fn release(&mut self, op: &Op, i: usize) -> Vec<u8> {
+        let mut released = vec![];
+        for reg in operands(op) {
+            if self.next_use(reg, i).is_some() {
+                continue;
+            }
+            let bank = self.bank(self.class(reg));
+            if let Some(r) = bank
+                .registers
+                .iter()
+                .position(|value| *value == Some(Value::Virtual(reg)))
+            {
+                bank.registers[r] = None;
+                released.push(r as u8);
+            }
+        }
+        released
+    }
+
+    /// The register `reg` lives in, reloading it from its stack slot
+    /// when it was spilled.
+    fn resident(&mut self, reg: VReg, i: usize, taken: &[u8], out: &mut Vec<Instruction>) -> u8 {
+        let class = self.class(reg);
+        let resident = self
+            .bank(class)
+            .registers
+            .iter()
+            .position(|value| *value == Some(Value::Virtual(reg)));
+        if let Some(r) = resident {
+            return r as u8;
+        }
+
+        let number = self.choose(class, taken, i, out);
+        let dst = Operand::Register(self.register(class, number, reg));
+        out.push(self.spill_move(class, self.slot(reg), dst));
+        self.bank(class).registers[number as usize] = Some(Value::Virtual(reg));
+        number
+    }
+
+    /// Pick a register of `class` that isn't `taken`: the least recently
+    /// used free one, or else the least recently used index vector, or
+    /// else spill the virtual register read again last after op `i`.
+    fn choose(&mut self, class: Class, taken: &[u8], i: usize, out: &mut Vec<Instruction>) -> u8 {
+        let bank = self.bank(class);
+        let candidates: Vec<usize> = (0..bank.registers.len())
+            .filter(|reg| !taken.contains(&(*reg as u8)))
+            .collect();
+
+        // Rotating through the free registers keeps consecutive ops
+        // apart, so that the scheduler can overlap them.
+        if let Some(reg) = candidates
+            .iter()
+            .filter(|reg| bank.registers[**reg].is_none())
+            .min_by_key(|reg| bank.last_used[**reg])
+        {
+            return *reg as u8;
+        }
+
+        if let Some(reg) = candidates
+            .iter()
+            .filter(|reg| matches!(bank.registers[**reg], Some(Value::Constant(_))))
+            .min_by_key(|reg| bank.last_used[**reg])
+        {
+            bank.registers[*reg] = None;
+            return *reg as u8;
+        }
+
+        let live: Vec<(usize, VReg)> = candidates
+            .iter()
+            .filter_map(|reg| match bank.registers[*reg] {
+                Some(Value::Virtual(vreg)) => Some((*reg, vreg)),
+                _ => None,
+            })
+            .collect();
+        let (number, spilled) = live
+            .into_iter()
+            .max_by_key(|(_, vreg)| self.next_use(*vreg, i).unwrap_or(usize::MAX))
+            .expect("an op uses at most 16 registers");
+
+        // Values never change, so one that was spilled before is still
+        // in its slot.
+        if !self.slots.contains_key(&spilled) {
+            self.slots.insert(spilled, self.slots.len());
+            let src = Operand::Register(self.register(class, number as u8, spilled));
+            out.push(self.spill_move(class, src, self.slot(spilled)));
+        }
+        self.bank(class).registers[number] = None;
+        number as u8
+    }
+}
+
+/// The registers an op reads as operands, rather than as index vectors.
+fn operands(op: &Op) -> Vec<VReg> {
+    match *op {
+        Op::Shuffle { first, second, .. } | Op::Blend { first, second, .. } => {
+            vec![first, second]
+        }
+        Op::Store { src, .. } | Op::MaskedStore { src, .. } => vec![src],
+        _ => vec![],
+    }
+}
+
+fn is_vector(reg: &Register) -> bool {
+    reg.is_ymm() || reg.is_xmm()
+}
+
+/// Lower a program of the IR, and clean up after instruction selection
+/// with the peephole rewrites.
+pub fn lower_amd64_program(program: &Program, target: Target) -> Vec<Instruction> {
+    let instrs = RegisterAllocator::new(program, target).lower();
+    peephole_amd64(&instrs, target, program.in_place)
+}
+
+/// Lower a program of blocks, splitting those that don't fit the target.
+pub fn lower_amd64_blocks(blocks: &[InstructionBlock], target: Target) -> Vec<Instruction> {
+    let blocks: Vec<InstructionBlock> = blocks.iter().flat_map(|blk| blk.fit_to(target)).collect();
+    let mut program = Program::from_blocks(&blocks);
+    program.optimize();
+    lower_amd64_program(&program, target)
+}
+
+#[test]
+fn test_constants_stay_resident() {
+    use crate::{args::TargetModel, optimize::ShiftMask};
+
+    // The same permute of each window of eight values.
+    let lanes = [3, 1, 4, 0, 5, 2, 7, 6];
+    let mask = ShiftMask::from(
+        (0..32)
+            .map(|i| 8 * (i / 8) + lanes[i as usize % 8])
+            .collect::<Vec<u32>>(),
+    );
+    let blocks: Vec<InstructionBlock> = mask.optimize_to_window_blocks(255).into();
+    assert!(blocks
+        .iter()
+        .all(|blk| matches!(blk, InstructionBlock::Eight(_))));
+
+    let target = Target::new(TargetModel::X86_64V3, TargetModel::X86_64V3.features());
+    let materialized = lower_amd64_blocks(&blocks, target)
+        .iter()
+        .filter(|instr| matches!(instr, Instruction::VMOVDQU(_, _)))
+        .count();
+    assert_eq!(materialized, 1);
+}
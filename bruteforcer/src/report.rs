@@ -0,0 +1,528 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Samples of one benchmark run, keyed by template, arg count and
+/// pattern number.
+pub struct Run {
+    pub name: String,
+    pub samples: BTreeMap<(String, u32, u32), Vec<f64>>,
+}
+
+/// Parameters of the statistics computed by a report.
+#[derive(Debug, Clone)]
+pub struct ReportConfig {
+    /// The template that speedups are relative to.
+    pub baseline: String,
+    /// Number of bootstrap resamples for the confidence intervals.
+    pub resamples: u32,
+    /// Confidence level of the intervals, eg 0.95.
+    pub confidence: f64,
+    /// Significance level of the comparison between two runs.
+    pub alpha: f64,
+    /// Seed for the bootstrap, so reports are reproducible.
+    pub seed: u64,
+}
+
+/// Statistics of one (template, arg count, pattern) group of a run.
+pub struct GroupStats {
+    pub run: usize,
+    pub template: String,
+    pub arg_count: u32,
+    pub pattern: u32,
+    pub n: usize,
+    pub median: f64,
+    /// Median absolute deviation from the median, unscaled.
+    pub mad: f64,
+    /// Bootstrap confidence interval of the median.
+    pub ci: (f64, f64),
+    /// Median of the baseline template on the same pattern over the
+    /// median of this group, if the run has the baseline.
+    pub speedup: Option<f64>,
+}
+
+/// Comparison of a group between the first and the second run.
+pub struct Comparison {
+    pub template: String,
+    pub arg_count: u32,
+    pub pattern: u32,
+    pub before: f64,
+    pub after: f64,
+    /// Two sided p value of a Mann-Whitney U test.
+    pub p: f64,
+}
+
+impl Comparison {
+    /// Relative change of the median, positive when the second run is slower.
+    pub fn change(&self) -> f64 {
+        self.after / self.before - 1.0
+    }
+
+    pub fn is_significant(&self, alpha: f64) -> bool {
+        self.p < alpha && self.before != self.after
+    }
+
+    pub fn is_regression(&self, alpha: f64) -> bool {
+        self.is_significant(alpha) && self.after > self.before
+    }
+}
+
+pub struct Report {
+    pub config: ReportConfig,
+    pub runs: Vec<String>,
+    pub groups: Vec<GroupStats>,
+    pub comparisons: Vec<Comparison>,
+}
+
+/// Parse a results CSV as written by `bench` or `commands.py runbench`,
+/// columns are found by name so extra columns are ignored.
+pub fn read_results(name: &str, contents: &str) -> Result<Run, String> {
+    let mut lines = contents.lines();
+    let header: Vec<&str> = lines
+        .next()
+        .ok_or(format!("{} is empty", name))?
+        .split(',')
+        .map(|c| c.trim())
+        .collect();
+    let column = |col: &str| {
+        header
+            .iter()
+            .position(|c| *c == col)
+            .ok_or(format!("{} has no {} column", name, col))
+    };
+    let time = column("compute_time")?;
+    let template = column("template_name")?;
+    let arg_count = column("arg_count")?;
+    let pattern = column("pattern_number")?;
+
+    let mut samples: BTreeMap<(String, u32, u32), Vec<f64>> = BTreeMap::new();
+    for (line_number, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        let field = |i: usize| fields.get(i).copied().unwrap_or("");
+        let invalid = || format!("line {} of {} is invalid", line_number + 2, name);
+
+        let key = (
+            field(template).to_string(),
+            field(arg_count).parse().map_err(|_| invalid())?,
+            field(pattern).parse().map_err(|_| invalid())?,
+        );
+        let value: f64 = field(time).parse().map_err(|_| invalid())?;
+        samples.entry(key).or_default().push(value);
+    }
+
+    Ok(Run {
+        name: name.to_string(),
+        samples,
+    })
+}
+
+pub fn median(samples: &[f64]) -> f64 {
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let mid = sorted.len() / 2;
+    if sorted.is_empty() {
+        f64::NAN
+    } else if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+pub fn mad(samples: &[f64]) -> f64 {
+    let center = median(samples);
+    let deviations: Vec<f64> = samples.iter().map(|s| (s - center).abs()).collect();
+
+    median(&deviations)
+}
+
+/// Percentile bootstrap interval of the median.
+pub fn bootstrap_ci<R: Rng>(
+    samples: &[f64],
+    resamples: u32,
+    confidence: f64,
+    rng: &mut R,
+) -> (f64, f64) {
+    if samples.is_empty() || resamples == 0 {
+        return (f64::NAN, f64::NAN);
+    }
+
+    let mut resample = vec![0.0; samples.len()];
+    let mut medians: Vec<f64> = (0..resamples)
+        .map(|_| {
+            for value in resample.iter_mut() {
+                *value = samples[rng.gen_range(0..samples.len())];
+            }
+            median(&resample)
+        })
+        .collect();
+    medians.sort_by(|a, b| a.total_cmp(b));
+
+    let tail = (1.0 - confidence) / 2.0;
+    let at = |q: f64| medians[((q * resamples as f64) as usize).min(medians.len() - 1)];
+    (at(tail), at(1.0 - tail))
+}
+
+/// Two sided Mann-Whitney U test with the normal approximation, which
+/// is corrected for ties and continuity. Returns U of the first sample
+/// and the p value.
+pub fn mann_whitney(first: &[f64], second: &[f64]) -> (f64, f64) {
+    let (n1, n2) = (first.len() as f64, second.len() as f64);
+    let mut all: Vec<(f64, bool)> = first
+        .iter()
+        .map(|v| (*v, true))
+        .chain(second.iter().map(|v| (*v, false)))
+        .collect();
+    all.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // Average the ranks of ties, and keep track of them for the variance.
+    let mut rank_sum = 0.0;
+    let mut ties = 0.0;
+    let mut i = 0;
+    while i < all.len() {
+        let count = all[i..].iter().take_while(|v| v.0 == all[i].0).count();
+        let rank = i as f64 + (count as f64 + 1.0) / 2.0;
+        rank_sum += rank * all[i..i + count].iter().filter(|v| v.1).count() as f64;
+        ties += (count * count * count - count) as f64;
+        i += count;
+    }
+
+    let n = n1 + n2;
+    let u = rank_sum - n1 * (n1 + 1.0) / 2.0;
+    let mean = n1 * n2 / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if variance.is_nan() || variance <= 0.0 {
+        return (u, 1.0);
+    }
+
+    let z = ((u - mean).abs() - 0.5).max(0.0) / variance.sqrt();
+    (u, erfc(z / std::f64::consts::SQRT_2))
+}
+
+/// Complementary error function, with a fractional error below 1.2e-7
+/// (Numerical Recipes' `erfcc`).
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let poly = -z * z - 1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+    let ans = t * poly.exp();
+
+    if x >= 0.0 {
+        ans
+    } else {
+        2.0 - ans
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl Report {
+    /// Compute the statistics of every run, and compare the first two
+    /// runs when there are exactly two.
+    pub fn new(runs: &[Run], config: ReportConfig) -> Self {
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let mut groups = vec![];
+
+        for (i, run) in runs.iter().enumerate() {
+            for ((template, arg_count, pattern), samples) in run.samples.iter() {
+                let median = median(samples);
+                let speedup = run
+                    .samples
+                    .get(&(config.baseline.clone(), *arg_count, *pattern))
+                    .map(|baseline| self::median(baseline) / median);
+
+                groups.push(GroupStats {
+                    run: i,
+                    template: template.clone(),
+                    arg_count: *arg_count,
+                    pattern: *pattern,
+                    n: samples.len(),
+                    median,
+                    mad: mad(samples),
+                    ci: bootstrap_ci(samples, config.resamples, config.confidence, &mut rng),
+                    speedup,
+                });
+            }
+        }
+
+        let mut comparisons = vec![];
+        if let [first, second] = runs {
+            for (key, before) in first.samples.iter() {
+                let Some(after) = second.samples.get(key) else {
+                    continue;
+                };
+
+                comparisons.push(Comparison {
+                    template: key.0.clone(),
+                    arg_count: key.1,
+                    pattern: key.2,
+                    before: median(before),
+                    after: median(after),
+                    p: mann_whitney(before, after).1,
+                });
+            }
+        }
+
+        Self {
+            config,
+            runs: runs.iter().map(|r| r.name.clone()).collect(),
+            groups,
+            comparisons,
+        }
+    }
+
+    pub fn regressions(&self) -> usize {
+        self.comparisons
+            .iter()
+            .filter(|c| c.is_regression(self.config.alpha))
+            .count()
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::new();
+        let confidence = self.config.confidence * 100.0;
+
+        for (i, name) in self.runs.iter().enumerate() {
+            let _ = writeln!(md, "## {}\n", name);
+            let _ = writeln!(
+                md,
+                "| template | arg_count | pattern | n | median | MAD | {}% CI | speedup vs {} |",
+                confidence, self.config.baseline
+            );
+            let _ = writeln!(md, "|---|---:|---:|---:|---:|---:|---|---:|");
+            for group in self.groups.iter().filter(|g| g.run == i) {
+                let speedup = match group.speedup {
+                    Some(speedup) => format!("{:.2}x", speedup),
+                    None => "-".to_string(),
+                };
+                let _ = writeln!(
+                    md,
+                    "| {} | {} | {} | {} | {:.1} | {:.1} | [{:.1}, {:.1}] | {} |",
+                    group.template,
+                    group.arg_count,
+                    group.pattern,
+                    group.n,
+                    group.median,
+                    group.mad,
+                    group.ci.0,
+                    group.ci.1,
+                    speedup
+                );
+            }
+            let _ = writeln!(md);
+        }
+
+        if !self.comparisons.is_empty() {
+            let alpha = self.config.alpha;
+            let _ = writeln!(md, "## {} vs {}\n", self.runs[0], self.runs[1]);
+            let _ = writeln!(
+                md,
+                "| template | arg_count | pattern | before | after | change | p | |"
+            );
+            let _ = writeln!(md, "|---|---:|---:|---:|---:|---:|---:|---|");
+            for cmp in self.comparisons.iter() {
+                let verdict = if cmp.is_regression(alpha) {
+                    "regression"
+                } else if cmp.is_significant(alpha) {
+                    "improvement"
+                } else {
+                    ""
+                };
+                let _ = writeln!(
+                    md,
+                    "| {} | {} | {} | {:.1} | {:.1} | {:+.1}% | {:.4} | {} |",
+                    cmp.template,
+                    cmp.arg_count,
+                    cmp.pattern,
+                    cmp.before,
+                    cmp.after,
+                    cmp.change() * 100.0,
+                    cmp.p,
+                    verdict
+                );
+            }
+
+            let improvements = self
+                .comparisons
+                .iter()
+                .filter(|c| c.is_significant(alpha) && !c.is_regression(alpha))
+                .count();
+            let _ = writeln!(
+                md,
+                "\n{} regressions and {} improvements at alpha = {}.",
+                self.regressions(),
+                improvements,
+                alpha
+            );
+        }
+
+        md
+    }
+
+    /// Render the report as a standalone HTML page, with a chart of the
+    /// medians and their confidence intervals for each arg count.
+    pub fn to_html(&self) -> String {
+        const COLORS: [&str; 8] = [
+            "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#9c755f",
+        ];
+        const ROW: f64 = 18.0;
+        const LABEL: f64 = 260.0;
+        const WIDTH: f64 = 900.0;
+
+        let mut templates: Vec<&str> = self.groups.iter().map(|g| g.template.as_str()).collect();
+        templates.sort();
+        templates.dedup();
+        let color = |template: &str| {
+            let i = templates.iter().position(|t| *t == template).unwrap_or(0);
+            COLORS[i % COLORS.len()]
+        };
+
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Benchmark report</title>\n\
+<style>body { font-family: sans-serif; } table { border-collapse: collapse; } \
+td, th { border: 1px solid #ccc; padding: 2px 6px; text-align: right; }</style>\n</head>\n<body>\n",
+        );
+
+        let mut arg_counts: Vec<u32> = self.groups.iter().map(|g| g.arg_count).collect();
+        arg_counts.sort();
+        arg_counts.dedup();
+        for arg_count in arg_counts.iter() {
+            let rows: Vec<&GroupStats> = self
+                .groups
+                .iter()
+                .filter(|g| g.arg_count == *arg_count)
+                .collect();
+            let max = rows
+                .iter()
+                .map(|g| g.ci.1.max(g.median))
+                .filter(|v| v.is_finite())
+                .fold(1.0, f64::max);
+            let scale = (WIDTH - LABEL - 20.0) / max;
+            let height = ROW * rows.len() as f64 + 30.0;
+
+            let _ = writeln!(html, "<h2>{} arguments</h2>", arg_count);
+            let _ = writeln!(
+                html,
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-size=\"11\">",
+                WIDTH, height
+            );
+            for (i, group) in rows.iter().enumerate() {
+                let y = ROW * i as f64;
+                let _ = writeln!(
+                    html,
+                    "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{} #{} ({})</text>",
+                    LABEL - 6.0,
+                    y + 12.0,
+                    escape(&group.template),
+                    group.pattern,
+                    escape(&self.runs[group.run])
+                );
+                let _ = writeln!(
+                    html,
+                    "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\"><title>median {:.1}</title></rect>",
+                    LABEL,
+                    y + 3.0,
+                    group.median * scale,
+                    ROW - 6.0,
+                    color(&group.template),
+                    group.median
+                );
+                if group.ci.0.is_finite() && group.ci.1.is_finite() {
+                    let _ = writeln!(
+                        html,
+                        "<line x1=\"{:.1}\" x2=\"{:.1}\" y1=\"{:.1}\" y2=\"{:.1}\" stroke=\"black\"/>",
+                        LABEL + group.ci.0 * scale,
+                        LABEL + group.ci.1 * scale,
+                        y + ROW / 2.0,
+                        y + ROW / 2.0
+                    );
+                }
+            }
+            let axis = ROW * rows.len() as f64 + 4.0;
+            let _ = writeln!(
+                html,
+                "<line x1=\"{0}\" x2=\"{1:.1}\" y1=\"{2:.1}\" y2=\"{2:.1}\" stroke=\"black\"/>\n\
+<text x=\"{0}\" y=\"{3:.1}\">0</text>\n<text x=\"{1:.1}\" y=\"{3:.1}\" text-anchor=\"end\">{4:.0}</text>\n</svg>",
+                LABEL,
+                LABEL + max * scale,
+                axis,
+                axis + 14.0,
+                max
+            );
+        }
+
+        // Keep the tables next to the charts, so the page stands alone.
+        let _ = writeln!(
+            html,
+            "<pre>\n{}</pre>\n</body>\n</html>",
+            escape(&self.to_markdown())
+        );
+
+        html
+    }
+}
+
+#[test]
+fn test_statistics() {
+    assert_eq!(median(&[3.0, 1.0, 2.0]), 2.0);
+    assert_eq!(median(&[4.0, 1.0, 2.0, 3.0]), 2.5);
+    assert_eq!(mad(&[1.0, 1.0, 2.0, 2.0, 4.0, 6.0, 9.0]), 1.0);
+
+    let fast: Vec<f64> = (0..50).map(|i| 100.0 + (i % 7) as f64).collect();
+    let slow: Vec<f64> = (0..50).map(|i| 120.0 + (i % 5) as f64).collect();
+    assert!(mann_whitney(&fast, &slow).1 < 1e-6);
+    assert!(mann_whitney(&fast, &fast).1 > 0.9);
+
+    let ci = bootstrap_ci(&fast, 200, 0.95, &mut StdRng::seed_from_u64(1));
+    assert!(ci.0 <= median(&fast) && median(&fast) <= ci.1);
+}
+
+#[test]
+fn test_report() {
+    let before = read_results(
+        "before.csv",
+        "compute_time,template_name,arg_count,pattern_number,iteration_number,blocks\n\
+10,scalar,10,0,0,10\n11,scalar,10,0,1,10\n12,scalar,10,0,2,10\n\
+5,simd,10,0,0,3\n5,simd,10,0,1,3\n6,simd,10,0,2,3\n",
+    )
+    .unwrap();
+    let after = read_results(
+        "after.csv",
+        "compute_time,template_name,arg_count,pattern_number,iteration_number\n\
+10,scalar,10,0,0\n11,scalar,10,0,1\n\
+9,simd,10,0,0\n9,simd,10,0,1\n",
+    )
+    .unwrap();
+
+    let config = ReportConfig {
+        baseline: "scalar".to_string(),
+        resamples: 100,
+        confidence: 0.95,
+        alpha: 0.05,
+        seed: 0,
+    };
+    let report = Report::new(&[before, after], config);
+    assert_eq!(report.groups.len(), 4);
+    assert_eq!(report.groups[1].speedup, Some(11.0 / 5.0));
+    assert_eq!(report.comparisons.len(), 2);
+    assert!(report
+        .to_markdown()
+        .contains("| simd | 10 | 0 | 5.0 | 9.0 | +80.0% |"));
+    assert!(report.to_html().contains("<svg"));
+}
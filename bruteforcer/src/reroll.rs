@@ -0,0 +1,274 @@
+use std::{fmt::Debug, mem::discriminant};
+
+use crate::{
+    abstract_instructions::{single::SingleInstruction, InstructionBlock},
+    ir::{Op, Program},
+};
+
+/// A block of a program after rerolling, either as it was or a loop
+/// standing in for a run of blocks.
+#[derive(Clone)]
+pub enum RolledBlock {
+    Block(InstructionBlock),
+    Loop(BlockLoop),
+}
+
+/// A run of blocks that make the same moves over and over, each time
+/// `stride` values further along the input and output. The loop runs
+/// `body` for `iterations` iterations, where the body is the first
+/// blocks of the run, unrolled as many times as asked for.
+#[derive(Clone)]
+pub struct BlockLoop {
+    pub body: Vec<InstructionBlock>,
+    pub iterations: u32,
+    pub stride: [i64; 2],
+}
+
+impl Debug for RolledBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            RolledBlock::Block(blk) => write!(f, "{:?}", blk),
+            RolledBlock::Loop(l) => write!(
+                f,
+                "loop({} x {:?}, {}, {})",
+                l.iterations, l.body, l.stride[0], l.stride[1]
+            ),
+        }
+    }
+}
+
+impl From<&RolledBlock> for Vec<SingleInstruction> {
+    fn from(val: &RolledBlock) -> Self {
+        match val {
+            RolledBlock::Block(blk) => (*blk).into(),
+            RolledBlock::Loop(l) => {
+                let body: Vec<SingleInstruction> =
+                    l.body.iter().flat_map(|blk| Self::from(*blk)).collect();
+                (0..l.iterations as i64)
+                    .flat_map(|i| {
+                        body.iter().map(move |single| {
+                            SingleInstruction::new(
+                                (single.index as i64 + i * l.stride[0]) as u32,
+                                (single.value as i64 + i * l.stride[1]) as u32,
+                            )
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Roll runs of blocks that repeat the block before them at a constant
+/// stride into loops, with `unroll` blocks of the run in each iteration.
+/// Runs too short for two iterations, and what is left of a run after
+/// the last iteration, stay as they are, so that blocks keep their
+/// order. Single moves are never rolled, and an unroll factor of 0
+/// leaves every block as is.
+pub fn reroll_blocks(blocks: &[InstructionBlock], unroll: u32) -> Vec<RolledBlock> {
+    // The ops of every block on its own, with the lanes of the register
+    // each op writes.
+    let lowered: Vec<Vec<(Op, u32)>> = blocks
+        .iter()
+        .map(|blk| {
+            let program = Program::from_blocks(&[*blk]);
+            program
+                .ops
+                .iter()
+                .map(|(_, op)| (op.clone(), op.dst().map_or(0, |dst| program.lanes(dst))))
+                .collect()
+        })
+        .collect();
+
+    let mut rolled = vec![];
+    let mut i = 0;
+    while i < blocks.len() {
+        let (run, stride) = match blocks[i] {
+            InstructionBlock::Single(_) => (1, [0, 0]),
+            _ => repeats(blocks, &lowered, i),
+        };
+        let iterations = if unroll == 0 {
+            0
+        } else {
+            run / unroll as usize
+        };
+        if iterations < 2 {
+            rolled.push(RolledBlock::Block(blocks[i]));
+            i += 1;
+            continue;
+        }
+
+        let unroll = unroll as usize;
+        rolled.push(RolledBlock::Loop(BlockLoop {
+            body: blocks[i..i + unroll].to_vec(),
+            iterations: iterations as u32,
+            stride: stride.map(|s| s * unroll as i64),
+        }));
+        i += iterations * unroll;
+    }
+
+    rolled
+}
+
+/// The number of blocks from `start` on of the same kind as the one at
+/// `start` that lower to its ops, with the windows they load and store
+/// shifted along by a multiple of the same stride, and that stride.
+/// Comparing the windows rather than just the moves keeps blocks whose
+/// windows were clamped to the ends of the buffers out of the run.
+fn repeats(
+    blocks: &[InstructionBlock],
+    lowered: &[Vec<(Op, u32)>],
+    start: usize,
+) -> (usize, [i64; 2]) {
+    let kind = discriminant(&blocks[start]);
+    let first = &lowered[start];
+    let Some(stride) = lowered
+        .get(start + 1)
+        .and_then(|next| window_stride(first, next))
+    else {
+        return (1, [0, 0]);
+    };
+
+    let run = lowered[start..]
+        .iter()
+        .enumerate()
+        .take_while(|(k, ops)| {
+            let by = [*k as i64 * stride[0], *k as i64 * stride[1]];
+            discriminant(&blocks[start + k]) == kind
+                && ops.len() == first.len()
+                && ops
+                    .iter()
+                    .zip(first.iter())
+                    .all(|((a, a_lanes), (b, b_lanes))| *a == shift(b, by) && a_lanes == b_lanes)
+        })
+        .count();
+
+    (run, stride)
+}
+
+/// The stride between the windows of the first load or copy and store
+/// of two lowered blocks.
+fn window_stride(first: &[(Op, u32)], next: &[(Op, u32)]) -> Option<[i64; 2]> {
+    let windows = |ops: &[(Op, u32)]| {
+        let load = ops.iter().find_map(|(op, _)| match op {
+            Op::Load { first, .. } => Some(*first),
+            Op::Copy { from, .. } => Some(*from),
+            _ => None,
+        })?;
+        let store = ops.iter().find_map(|(op, _)| match op {
+            Op::Store { first, .. } | Op::MaskedStore { first, .. } | Op::Copy { first, .. } => {
+                Some(*first)
+            }
+            _ => None,
+        })?;
+        Some([load as i64, store as i64])
+    };
+
+    let (first, next) = (windows(first)?, windows(next)?);
+    Some([next[0] - first[0], next[1] - first[1]])
+}
+
+/// `op` with the window it loads moved `by[0]` values along the input
+/// and the window it stores moved `by[1]` along the output.
+fn shift(op: &Op, by: [i64; 2]) -> Op {
+    let moved = |first: u32, by: i64| (first as i64 + by) as u32;
+    match op.clone() {
+        Op::Load { dst, first } => Op::Load {
+            dst,
+            first: moved(first, by[0]),
+        },
+        Op::Store { src, first } => Op::Store {
+            src,
+            first: moved(first, by[1]),
+        },
+        Op::MaskedStore { src, first, mask } => Op::MaskedStore {
+            src,
+            first: moved(first, by[1]),
+            mask,
+        },
+        Op::Copy { from, first, len } => Op::Copy {
+            from: moved(from, by[0]),
+            first: moved(first, by[1]),
+            len,
+        },
+        op => op,
+    }
+}
+
+#[test]
+fn test_reroll_blocks() {
+    use crate::optimize::ShiftMask;
+
+    // Every window of 8 is reversed, all but the last of them in four
+    // windows at a time.
+    let mask = ShiftMask::from(
+        (0..104)
+            .map(|i| i / 8 * 8 + 7 - i % 8)
+            .collect::<Vec<u32>>(),
+    );
+    let blocks: Vec<InstructionBlock> = mask.optimize_to_blocks(255).into();
+    let rolled = reroll_blocks(&blocks, 4);
+    assert_eq!(rolled.len(), 2);
+    assert!(matches!(
+        &rolled[0],
+        RolledBlock::Loop(BlockLoop {
+            iterations: 3,
+            stride: [32, 32],
+            ..
+        })
+    ));
+
+    let mut singles: Vec<SingleInstruction> = rolled.iter().flat_map(Vec::from).collect();
+    let mut expected: Vec<SingleInstruction> =
+        blocks.iter().flat_map(|blk| Vec::from(*blk)).collect();
+    singles.sort_by_key(|single| single.index);
+    expected.sort_by_key(|single| single.index);
+    assert_eq!(singles, expected);
+
+    assert_eq!(reroll_blocks(&blocks, 0).len(), blocks.len());
+
+    // The last of the two source blocks of this pattern has its input
+    // window clamped to the end of the buffer, so it can't be rolled
+    // along with the others.
+    let pattern: Vec<u32> = vec![
+        34, 3, 1, 4, 7, 0, 5, 38, 2, 11, 9, 12, 15, 8, 13, 6, 10, 19, 17, 20, 23, 16, 21, 14, 18,
+        27, 25, 28, 31, 24, 29, 22, 26, 35, 33, 36, 39, 32, 37, 30, 41, 40,
+    ];
+    let blocks: Vec<InstructionBlock> = ShiftMask::from(pattern.clone())
+        .optimize_to_blocks(255)
+        .into();
+    for rolled in reroll_blocks(&blocks, 1).iter() {
+        let RolledBlock::Loop(l) = rolled else {
+            continue;
+        };
+        assert!(l
+            .body
+            .iter()
+            .all(|blk| !matches!(blk, InstructionBlock::Single(_))));
+        let program = Program::from_blocks(&l.body);
+        for k in 0..l.iterations as i64 {
+            for (_, op) in program.ops.iter() {
+                let end = match *op {
+                    Op::Load { dst, first } => {
+                        first as i64 + k * l.stride[0] + program.lanes(dst) as i64
+                    }
+                    Op::Store { src, first } => {
+                        first as i64 + k * l.stride[1] + program.lanes(src) as i64
+                    }
+                    _ => continue,
+                };
+                assert!(
+                    end <= pattern.len() as i64,
+                    "{:?} runs past the end",
+                    rolled
+                );
+            }
+        }
+    }
+
+    // Scalar moves are left as they are, however many of them repeat.
+    let singles: Vec<InstructionBlock> = (0..8)
+        .map(|i| InstructionBlock::Single(SingleInstruction::new(i, 7 - i)))
+        .collect();
+    assert_eq!(reroll_blocks(&singles, 1).len(), singles.len());
+}
@@ -0,0 +1,371 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
+
+use crate::{
+    instructions_x86_64::{Instruction, Operand, Register},
+    targets::CostTable,
+};
+
+/// The memory generated programs touch, relative to their base register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Space {
+    Input,
+    Output,
+    Stack,
+}
+
+/// A register, or a 4 byte element of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Location {
+    /// Whether the register is a vector register, and its number.
+    Register(bool, u8),
+    /// An AVX-512 mask register, numbered like the general purpose ones.
+    Mask(u8),
+    Element(Space, i32),
+}
+
+impl Location {
+    pub fn register(reg: Register) -> Self {
+        if reg.is_mask() {
+            return Location::Mask(reg.number());
+        }
+        Location::Register(reg.is_ymm() || reg.is_xmm(), reg.number())
+    }
+}
+
+/// What an instruction reads and writes.
+#[derive(Debug, Default)]
+pub struct Effects {
+    pub reads: Vec<Location>,
+    pub writes: Vec<Location>,
+    /// Spaces read at addresses only known at runtime, by gathers.
+    pub wide_reads: Vec<Space>,
+    /// Nothing may be moved across the instruction.
+    pub barrier: bool,
+}
+
+impl Effects {
+    /// The effects of an instruction. Operands are in AT&T order, so
+    /// everything but the last operand is a source. When permuting in
+    /// place the input and output are the same buffer.
+    pub fn of(instr: &Instruction, in_place: bool) -> Self {
+        let mut effects = Effects::default();
+        if matches!(
+            instr,
+            Instruction::RET | Instruction::VZEROUPPER | Instruction::RDTSC
+        ) {
+            effects.barrier = true;
+            return effects;
+        }
+
+        let mut operands = vec![];
+        instr.map_operands(|op| {
+            operands.push(op);
+            op
+        });
+        let registers: Vec<Register> = operands
+            .iter()
+            .filter_map(|op| match op {
+                Operand::Register(reg) => Some(*reg),
+                _ => None,
+            })
+            .collect();
+        let bytes = if registers.iter().any(|reg| reg.is_ymm()) {
+            32
+        } else if registers.iter().any(|reg| reg.is_xmm()) {
+            16
+        } else if matches!(instr, Instruction::MOVL(_, _)) {
+            4
+        } else {
+            8
+        };
+
+        let space = |base: Register| match base {
+            Register::RDI => Space::Input,
+            Register::RSI if in_place => Space::Input,
+            Register::RSI => Space::Output,
+            Register::RSP => Space::Stack,
+            _ => todo!(),
+        };
+        let elements = |base: Register, displ: i32| {
+            (displ.div_euclid(4)..(displ + bytes).div_euclid(4))
+                .map(move |element| Location::Element(space(base), element))
+        };
+
+        let (destination, sources) = operands.split_last().unwrap();
+        for op in sources.iter() {
+            match *op {
+                // Nothing ever writes the constant pool.
+                Operand::Immediate(_) | Operand::Constant(_) | Operand::RipRelative(_) => {}
+                Operand::Register(reg) => effects.reads.push(Location::register(reg)),
+                Operand::Memory(base) => {
+                    effects.reads.push(Location::register(base));
+                    effects.reads.extend(elements(base, 0));
+                }
+                Operand::Displaced(displ, base) => {
+                    effects.reads.push(Location::register(base));
+                    effects.reads.extend(elements(base, displ));
+                }
+                Operand::ScaledIndex(base, index, _) => {
+                    effects.reads.push(Location::register(base));
+                    effects.reads.push(Location::register(index));
+                    effects.wide_reads.push(space(base));
+                }
+                Operand::Index(_, _) | Operand::ScaledDisplacedIndex(_, _, _) => todo!(),
+            }
+        }
+
+        match *destination {
+            Operand::Register(reg) => {
+                // These merge into their destination.
+                if matches!(
+                    instr,
+                    Instruction::XOR(_, _)
+                        | Instruction::ADD(_, _)
+                        | Instruction::SUB(_, _)
+                        | Instruction::SHUFPS(_, _, _)
+                        | Instruction::UNPCKLPS(_, _)
+                        | Instruction::UNPCKHPS(_, _)
+                        | Instruction::PALIGNR(_, _, _)
+                        | Instruction::BLENDPS(_, _, _)
+                        | Instruction::VGATHERDPS(_, _, _)
+                        | Instruction::VPERMT2PS(_, _, _)
+                ) {
+                    effects.reads.push(Location::register(reg));
+                }
+                effects.writes.push(Location::register(reg));
+            }
+            Operand::Memory(base) => {
+                effects.reads.push(Location::register(base));
+                effects.writes.extend(elements(base, 0));
+            }
+            Operand::Displaced(displ, base) => {
+                effects.reads.push(Location::register(base));
+                effects.writes.extend(elements(base, displ));
+            }
+            _ => todo!(),
+        }
+
+        // Gathers clear their mask as lanes complete.
+        if let Instruction::VGATHERDPS(Operand::Register(mask), _, _) = instr {
+            effects.writes.push(Location::register(*mask));
+        }
+
+        effects
+    }
+}
+
+/// For each instruction, the earlier instructions it has to follow.
+fn dependencies(instrs: &[Instruction], in_place: bool) -> Vec<Vec<usize>> {
+    let mut last_write: HashMap<Location, usize> = HashMap::new();
+    let mut reads: HashMap<Location, Vec<usize>> = HashMap::new();
+    let mut writes_to: HashMap<Space, Vec<usize>> = HashMap::new();
+    let mut wide_reads: HashMap<Space, Vec<usize>> = HashMap::new();
+    let mut since_barrier: Vec<usize> = vec![];
+    let mut barrier: Option<usize> = None;
+
+    let mut preds = vec![];
+    for (i, instr) in instrs.iter().enumerate() {
+        let effects = Effects::of(instr, in_place);
+        let mut deps: Vec<usize> = barrier.into_iter().collect();
+
+        if effects.barrier {
+            deps.append(&mut since_barrier);
+            last_write.clear();
+            reads.clear();
+            writes_to.clear();
+            wide_reads.clear();
+            barrier = Some(i);
+        } else {
+            for loc in effects.reads.iter() {
+                deps.extend(last_write.get(loc));
+                reads.entry(*loc).or_default().push(i);
+            }
+            for space in effects.wide_reads.iter() {
+                deps.extend(writes_to.get(space).into_iter().flatten());
+                wide_reads.entry(*space).or_default().push(i);
+            }
+            for loc in effects.writes.iter() {
+                deps.extend(last_write.insert(*loc, i));
+                deps.extend(reads.remove(loc).into_iter().flatten());
+                if let Location::Element(space, _) = loc {
+                    deps.extend(wide_reads.get(space).into_iter().flatten());
+                    writes_to.entry(*space).or_default().push(i);
+                }
+            }
+            since_barrier.push(i);
+        }
+
+        deps.retain(|dep| *dep != i);
+        deps.sort_unstable();
+        deps.dedup();
+        preds.push(deps);
+    }
+
+    preds
+}
+
+/// Cycles until the result of an instruction can be used.
+fn latency(instr: &Instruction, costs: &CostTable) -> u32 {
+    let is_memory = |op: &Operand| !matches!(op, Operand::Register(_) | Operand::Immediate(_));
+
+    match instr {
+        Instruction::VGATHERDPS(_, _, _) => costs.gather.unwrap_or(costs.load),
+        Instruction::VPERMPS(_, _, _)
+        | Instruction::VPERMD(_, _, _)
+        | Instruction::VPERMT2PS(_, _, _)
+        | Instruction::VPERM2F128(_, _, _, _)
+        | Instruction::VINSERTF128(_, _, _, _) => costs.cross_lane_shuffle,
+        Instruction::VPERMILPS(_, _, _)
+        | Instruction::VPALIGNR(_, _, _, _)
+        | Instruction::VSHUFPS(_, _, _, _)
+        | Instruction::VBLENDPS(_, _, _, _)
+        | Instruction::VUNPCKLPS(_, _, _)
+        | Instruction::VUNPCKHPS(_, _, _)
+        | Instruction::VMOVLHPS(_, _, _)
+        | Instruction::VMOVHLPS(_, _, _)
+        | Instruction::PSHUFD(_, _, _)
+        | Instruction::SHUFPS(_, _, _)
+        | Instruction::UNPCKLPS(_, _)
+        | Instruction::UNPCKHPS(_, _)
+        | Instruction::PALIGNR(_, _, _)
+        | Instruction::BLENDPS(_, _, _) => costs.shuffle,
+        Instruction::MOV(src, dst)
+        | Instruction::MOVQ(src, dst)
+        | Instruction::MOVL(src, dst)
+        | Instruction::VMOVDQA(src, dst)
+        | Instruction::VMOVDQU(src, dst)
+        | Instruction::VMOVUPS(src, dst)
+        | Instruction::MOVUPS(src, dst)
+        | Instruction::KMOVW(src, dst)
+        | Instruction::VMASKMOVPS(src, _, dst)
+        | Instruction::VMOVUPSK(src, _, dst) => {
+            if is_memory(src) {
+                costs.load
+            } else if is_memory(dst) {
+                costs.store
+            } else {
+                1
+            }
+        }
+        _ => 1,
+    }
+}
+
+/// Reorder a lowered program with a list scheduler, so that the loads of
+/// the next blocks overlap the shuffles and stores of the current one.
+/// One instruction is issued a cycle. Of the instructions whose operands
+/// are ready, the one with the longest chain of latencies after it is
+/// issued first, and ties keep program order. How far ahead loads can
+/// move is bounded by the registers the allocator rotates through.
+pub fn schedule_amd64(
+    instrs: &[Instruction],
+    costs: &CostTable,
+    in_place: bool,
+) -> Vec<Instruction> {
+    let preds = dependencies(instrs, in_place);
+    let latencies: Vec<u64> = instrs
+        .iter()
+        .map(|instr| latency(instr, costs) as u64)
+        .collect();
+
+    let mut succs: Vec<Vec<usize>> = vec![vec![]; instrs.len()];
+    let mut waiting: Vec<usize> = preds.iter().map(|deps| deps.len()).collect();
+    for (i, deps) in preds.iter().enumerate() {
+        for dep in deps.iter() {
+            succs[*dep].push(i);
+        }
+    }
+
+    // Dependencies always point backwards, so heights can be computed
+    // in a single pass from the end.
+    let mut height = vec![0; instrs.len()];
+    for i in (0..instrs.len()).rev() {
+        height[i] = latencies[i] + succs[i].iter().map(|succ| height[*succ]).max().unwrap_or(0);
+    }
+
+    let mut available = vec![0; instrs.len()];
+    let mut pending: BinaryHeap<Reverse<(u64, usize)>> = (0..instrs.len())
+        .filter(|i| waiting[*i] == 0)
+        .map(|i| Reverse((0, i)))
+        .collect();
+    let mut ready: BinaryHeap<(u64, Reverse<usize>)> = BinaryHeap::new();
+    let mut scheduled = Vec::with_capacity(instrs.len());
+    let mut cycle = 0;
+
+    while scheduled.len() < instrs.len() {
+        while let Some(Reverse((at, i))) = pending.peek().copied() {
+            if at > cycle {
+                break;
+            }
+            pending.pop();
+            ready.push((height[i], Reverse(i)));
+        }
+
+        let Some((_, Reverse(i))) = ready.pop() else {
+            // Nothing is ready, skip ahead to whatever is first.
+            cycle = pending.peek().map(|Reverse((at, _))| *at).unwrap();
+            continue;
+        };
+
+        scheduled.push(instrs[i]);
+        for succ in succs[i].iter() {
+            available[*succ] = available[*succ].max(cycle + latencies[i]);
+            waiting[*succ] -= 1;
+            if waiting[*succ] == 0 {
+                pending.push(Reverse((available[*succ], *succ)));
+            }
+        }
+        cycle += 1;
+    }
+
+    scheduled
+}
+
+#[test]
+fn test_schedule_overlaps_blocks() {
+    use crate::{
+        abstract_instructions::{reverse::ReverseInstruction, InstructionBlock},
+        args::TargetModel,
+        encodings::Target,
+        regalloc::lower_amd64_blocks,
+    };
+
+    let target = Target::new(TargetModel::X86_64V3, TargetModel::X86_64V3.features());
+    let blocks: Vec<InstructionBlock> = (0..4)
+        .map(|i| InstructionBlock::Reverse(ReverseInstruction::new(8 * i, 8 * i, 8)))
+        .collect();
+    let instrs = lower_amd64_blocks(&blocks, target);
+    let scheduled = schedule_amd64(&instrs, &target.description().costs, false);
+
+    // Every load is hoisted ahead of the first store.
+    let is_load = |instr: &Instruction| {
+        matches!(
+            instr,
+            Instruction::VMOVUPS(Operand::Displaced(_, Register::RDI), _)
+        )
+    };
+    let first_store = scheduled
+        .iter()
+        .position(|instr| matches!(instr, Instruction::VMOVUPS(_, Operand::Displaced(_, _))))
+        .unwrap();
+    assert_eq!(scheduled.len(), instrs.len());
+    assert_eq!(
+        scheduled[..first_store]
+            .iter()
+            .filter(|i| is_load(i))
+            .count(),
+        4
+    );
+
+    // In place, a load can't move above the store to its elements.
+    let blocks = [
+        InstructionBlock::Reverse(ReverseInstruction::new(0, 8, 8)),
+        InstructionBlock::Reverse(ReverseInstruction::new(8, 16, 8)),
+    ];
+    let instrs = lower_amd64_blocks(&blocks, target);
+    let scheduled = schedule_amd64(&instrs, &target.description().costs, true);
+    assert_eq!(scheduled, instrs);
+}
@@ -0,0 +1,357 @@
+use std::fmt::Display;
+
+use clap::ValueEnum;
+
+use crate::{
+    args::{Feature, TargetModel},
+    features::FeatureSet,
+    tiling::TileConfig,
+};
+
+/// The instruction set a target belongs to, which decides the intrinsics
+/// and machine code the encoders emit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsaFamily {
+    X86_64,
+    Aarch64,
+    Riscv64,
+    Wasm32,
+}
+
+impl Display for IsaFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            IsaFamily::X86_64 => write!(f, "x86-64"),
+            IsaFamily::Aarch64 => write!(f, "aarch64"),
+            IsaFamily::Riscv64 => write!(f, "riscv64"),
+            IsaFamily::Wasm32 => write!(f, "wasm32"),
+        }
+    }
+}
+
+/// Width of the vector registers of a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorWidth {
+    Fixed(u32),
+    /// Vector length agnostic, the width is only known at runtime but
+    /// is a multiple of 128 bits within these bounds.
+    Scalable {
+        min: u32,
+        max: u32,
+    },
+}
+
+impl Display for VectorWidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            VectorWidth::Fixed(bits) => write!(f, "{} bits", bits),
+            VectorWidth::Scalable { min, max } => write!(f, "{} to {} bits, scalable", min, max),
+        }
+    }
+}
+
+/// Latencies in cycles of the operations blocks are lowered to, taken
+/// from a representative core of each target.
+#[derive(Debug, Clone, Copy)]
+pub struct CostTable {
+    /// A full vector load that hits L1.
+    pub load: u32,
+    /// A store, until a load of it can be forwarded.
+    pub store: u32,
+    /// A shuffle within 128 bit lanes.
+    pub shuffle: u32,
+    /// A shuffle across 128 bit lanes.
+    pub cross_lane_shuffle: u32,
+    /// A gather of a full vector, on targets that have one.
+    pub gather: Option<u32>,
+    /// A store of only some lanes of a vector, on targets that have one.
+    /// Loads of it can't be forwarded on x86.
+    pub masked_store: Option<u32>,
+}
+
+/// Everything the planner and encoders need to know of a target model.
+pub struct TargetDescription {
+    pub family: IsaFamily,
+    pub vector_width: VectorWidth,
+    /// Extensions every CPU of this model has.
+    pub features: &'static [Feature],
+    /// Shuffle instructions blocks may be lowered to, as listed by
+    /// `--list-targets`. This is for information only, isel picks the
+    /// instructions by the features of the target instead.
+    pub shuffles: &'static [&'static str],
+    pub costs: CostTable,
+    pub tile: TileConfig,
+}
+
+impl TargetModel {
+    pub const fn description(&self) -> &'static TargetDescription {
+        match self {
+            TargetModel::X86_64V2 => &X86_64_V2,
+            TargetModel::X86_64V3 => &X86_64_V3,
+            TargetModel::X86_64V4 => &X86_64_V4,
+            TargetModel::Armv8Neon => &ARMV8_NEON,
+            TargetModel::Armv9Sve => &ARMV9_SVE,
+            TargetModel::Rv64gcv => &RV64GCV,
+            TargetModel::Wasm32Simd128 => &WASM32_SIMD128,
+        }
+    }
+
+    /// The best x86-64 model the host fully implements.
+    #[cfg(target_arch = "x86_64")]
+    pub fn host() -> Option<Self> {
+        let host = FeatureSet::host();
+        Some(if host.has(Feature::Avx512f) {
+            TargetModel::X86_64V4
+        } else if host.has(Feature::Avx2) {
+            TargetModel::X86_64V3
+        } else {
+            TargetModel::X86_64V2
+        })
+    }
+
+    /// Other hosts get the model of their own family, if there is one.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn host() -> Option<Self> {
+        if cfg!(target_arch = "aarch64") {
+            Some(TargetModel::Armv8Neon)
+        } else if cfg!(target_arch = "riscv64") {
+            Some(TargetModel::Rv64gcv)
+        } else {
+            None
+        }
+    }
+
+    pub fn features(&self) -> FeatureSet {
+        FeatureSet::from(self.description().features)
+    }
+
+    /// The extensions of this model the host has. Only amd64 ones are
+    /// detected, a host of another family is taken to have all of them.
+    pub fn host_features(&self) -> FeatureSet {
+        if self.description().family == IsaFamily::X86_64 {
+            FeatureSet::host()
+        } else {
+            self.features()
+        }
+    }
+
+    /// A description of every model, for `--list-targets`.
+    pub fn list() -> String {
+        let host = TargetModel::host();
+        let mut out = String::new();
+
+        for model in TargetModel::value_variants().iter() {
+            let desc = model.description();
+            let costs = &desc.costs;
+            let optional = |cost: Option<u32>| match cost {
+                Some(cost) => cost.to_string(),
+                None => "none".to_string(),
+            };
+
+            out += &format!(
+                "{}{}
+  {}, vectors of {}, features {}
+  shuffles: {}
+  latencies: load {}, store {}, shuffle {}, cross lane shuffle {}, gather {},
+    masked store {}
+",
+                model,
+                if Some(*model) == host { " (host)" } else { "" },
+                desc.family,
+                desc.vector_width,
+                model.features(),
+                desc.shuffles.join(", "),
+                costs.load,
+                costs.store,
+                costs.shuffle,
+                costs.cross_lane_shuffle,
+                optional(costs.gather),
+                optional(costs.masked_store)
+            );
+        }
+
+        out
+    }
+}
+
+/// Nehalem and later, ie SSE up to 4.2 without any VEX encoding.
+const X86_64_V2: TargetDescription = TargetDescription {
+    family: IsaFamily::X86_64,
+    vector_width: VectorWidth::Fixed(128),
+    features: &[Feature::Sse2, Feature::Ssse3, Feature::Sse41],
+    shuffles: &[
+        "pshufd", "pshufb", "palignr", "shufps", "unpcklps", "unpckhps", "blendps",
+    ],
+    costs: CostTable {
+        load: 5,
+        store: 4,
+        shuffle: 1,
+        cross_lane_shuffle: 1,
+        gather: None,
+        masked_store: None,
+    },
+    tile: TileConfig::new(64, 4096),
+};
+
+/// Haswell and later, with AVX2 for lane crossing 256 bit permutes.
+const X86_64_V3: TargetDescription = TargetDescription {
+    family: IsaFamily::X86_64,
+    vector_width: VectorWidth::Fixed(256),
+    features: &[Feature::Avx2],
+    shuffles: &[
+        "vpermilps",
+        "vpermps",
+        "vperm2f128",
+        "vpalignr",
+        "vshufps",
+        "vunpcklps",
+        "vunpckhps",
+        "vgatherdps",
+    ],
+    costs: CostTable {
+        load: 7,
+        store: 5,
+        shuffle: 1,
+        cross_lane_shuffle: 3,
+        gather: Some(22),
+        masked_store: Some(12),
+    },
+    tile: TileConfig::new(64, 4096),
+};
+
+/// Skylake-X and later, with 512 bit registers and two source permutes.
+const X86_64_V4: TargetDescription = TargetDescription {
+    family: IsaFamily::X86_64,
+    vector_width: VectorWidth::Fixed(512),
+    features: &[Feature::Avx512f],
+    shuffles: &[
+        "vpermilps",
+        "vpermps",
+        "vpermt2ps",
+        "vpermi2ps",
+        "vperm2f128",
+        "vpalignr",
+        "vshufps",
+        "vunpcklps",
+        "vunpckhps",
+        "vgatherdps",
+    ],
+    costs: CostTable {
+        load: 8,
+        store: 5,
+        shuffle: 1,
+        cross_lane_shuffle: 3,
+        gather: Some(26),
+        masked_store: Some(12),
+    },
+    tile: TileConfig::new(64, 4096),
+};
+
+// Most of our aarch64 machines run 16K pages, and Apple cores have 128
+// byte cache lines.
+const ARM_TILE: TileConfig = TileConfig::new(128, 16384);
+
+const ARMV8_NEON: TargetDescription = TargetDescription {
+    family: IsaFamily::Aarch64,
+    vector_width: VectorWidth::Fixed(128),
+    features: &[],
+    shuffles: &[
+        "tbl", "ext", "rev64", "zip1", "zip2", "uzp1", "uzp2", "trn1", "trn2",
+    ],
+    costs: CostTable {
+        load: 5,
+        store: 2,
+        shuffle: 2,
+        cross_lane_shuffle: 2,
+        gather: None,
+        masked_store: None,
+    },
+    tile: ARM_TILE,
+};
+
+const ARMV9_SVE: TargetDescription = TargetDescription {
+    family: IsaFamily::Aarch64,
+    vector_width: VectorWidth::Scalable {
+        min: 128,
+        max: 2048,
+    },
+    features: &[],
+    shuffles: &[
+        "tbl", "ext", "rev", "zip1", "zip2", "uzp1", "uzp2", "trn1", "trn2", "compact", "splice",
+    ],
+    costs: CostTable {
+        load: 6,
+        store: 2,
+        shuffle: 3,
+        cross_lane_shuffle: 3,
+        gather: Some(9),
+        masked_store: Some(2),
+    },
+    tile: ARM_TILE,
+};
+
+/// Costs of an in order core with a 256 bit VLEN, where `vrgather` takes
+/// a cycle per lane of the register group.
+const RV64GCV: TargetDescription = TargetDescription {
+    family: IsaFamily::Riscv64,
+    vector_width: VectorWidth::Scalable {
+        min: 128,
+        max: 65536,
+    },
+    features: &[],
+    shuffles: &[
+        "vrgather.vv",
+        "vrgatherei16.vv",
+        "vslideup.vx",
+        "vslidedown.vx",
+        "vcompress.vm",
+    ],
+    costs: CostTable {
+        load: 4,
+        store: 1,
+        shuffle: 8,
+        cross_lane_shuffle: 8,
+        gather: Some(16),
+        masked_store: Some(1),
+    },
+    tile: TileConfig::new(64, 4096),
+};
+
+/// Costs of V8 on an x86-64 host, where constant shuffles of whole lanes
+/// are lowered to a single `pshufd` or `shufps`.
+const WASM32_SIMD128: TargetDescription = TargetDescription {
+    family: IsaFamily::Wasm32,
+    vector_width: VectorWidth::Fixed(128),
+    features: &[],
+    shuffles: &["i8x16.shuffle"],
+    costs: CostTable {
+        load: 6,
+        store: 4,
+        shuffle: 1,
+        cross_lane_shuffle: 1,
+        gather: None,
+        masked_store: None,
+    },
+    tile: TileConfig::new(64, 4096),
+};
+
+#[test]
+fn test_target_models() {
+    for model in TargetModel::value_variants().iter() {
+        let desc = model.description();
+        let widest = match desc.vector_width {
+            VectorWidth::Fixed(bits) => bits,
+            VectorWidth::Scalable { max, .. } => max,
+        };
+
+        // A model can't have a gather without wide enough registers, nor
+        // x86 extensions on another ISA.
+        assert!(desc.costs.gather.is_none() || widest >= 256, "{}", model);
+        if desc.family != IsaFamily::X86_64 {
+            assert_eq!(model.features(), FeatureSet::empty(), "{}", model);
+        }
+    }
+
+    assert!(TargetModel::X86_64V4.features().has(Feature::Avx2));
+    assert!(!TargetModel::X86_64V2.features().has(Feature::Avx));
+}
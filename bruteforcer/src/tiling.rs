@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+
+use crate::abstract_instructions::InstructionBlock;
+
+/// Sizes used to group blocks by the memory regions they touch.
+#[derive(Debug, Clone, Copy)]
+pub struct TileConfig {
+    /// Size of a cache line in bytes.
+    pub line_bytes: u32,
+    /// Size of a tile in bytes, a source tile and a destination tile
+    /// should comfortably fit within L1 at the same time.
+    pub tile_bytes: u32,
+}
+
+impl TileConfig {
+    pub const fn new(line_bytes: u32, tile_bytes: u32) -> Self {
+        Self {
+            line_bytes,
+            tile_bytes,
+        }
+    }
+
+    // Number of array elements within a line and a tile.
+    fn elements(&self) -> (u32, u32) {
+        let size = std::mem::size_of::<f32>() as u32;
+        (
+            (self.line_bytes / size).max(1),
+            (self.tile_bytes / size).max(1),
+        )
+    }
+}
+
+/// Reorder blocks so that generated code walks the destination array one
+/// tile at a time, and within each destination tile visits the source
+/// array one tile at a time, in cache line order within a source tile.
+/// Odd destination tiles walk the source tiles backwards, so that the
+/// last source tile of one destination tile is the first of the next.
+///
+/// Blocks of an out of place permutation write disjoint positions, so
+/// any ordering of them computes the same output, except for partial
+/// windows stored as their whole output window: the blocks fixing the
+/// lanes such a window overwrites have to run after it. That order is
+/// up to `fit_overlapping`, which must run after tiling.
+pub fn tile_blocks(
+    blocks: VecDeque<InstructionBlock>,
+    config: &TileConfig,
+) -> VecDeque<InstructionBlock> {
+    let (line, tile) = config.elements();
+    let mut keyed: Vec<((u32, u32, u32), InstructionBlock)> = blocks
+        .into_iter()
+        .map(|blk| {
+            let src = blk.get_first_input_index();
+            let dst_tile = blk.get_first_output_index() / tile;
+            let src_tile = if dst_tile % 2 == 0 {
+                src / tile
+            } else {
+                u32::MAX - src / tile
+            };
+            ((dst_tile, src_tile, src / line), blk)
+        })
+        .collect();
+
+    // The sort is stable, so blocks sharing a line keep the pattern order.
+    keyed.sort_by_key(|(key, _)| *key);
+    keyed.into_iter().map(|(_, blk)| blk).collect()
+}
+
+#[test]
+fn test_tile_blocks() {
+    use crate::optimize::{RandomConfig, ShiftMask};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mask = ShiftMask::new_random_with(
+        1000,
+        &RandomConfig::default(),
+        &mut StdRng::seed_from_u64(1),
+    );
+    let blocks = mask.optimize_to_blocks(255);
+    let tiled = tile_blocks(blocks.clone(), &TileConfig::new(64, 256));
+
+    assert_eq!(blocks.len(), tiled.len());
+    for blk in blocks.iter() {
+        assert!(tiled.contains(blk));
+    }
+
+    let tiles: Vec<u32> = tiled
+        .iter()
+        .map(|blk| blk.get_first_output_index() / 64)
+        .collect();
+    assert!(tiles.windows(2).all(|pair| pair[0] <= pair[1]));
+}
@@ -0,0 +1,324 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    abstract_instructions::{in_place::InPlaceStep, single::SingleInstruction},
+    args::TargetModel,
+    encodings::{encode_loop_to_c, encode_program_to_c, ConstantPool, Target},
+    ir::Program,
+    optimize::ShiftMask,
+    overlap::fit_overlapping,
+    reroll::{reroll_blocks, BlockLoop, RolledBlock},
+    targets::{IsaFamily, VectorWidth},
+    tiling::{tile_blocks, TileConfig},
+};
+
+/// The C code generated for one block, along with the moves it is
+/// supposed to make so that wrong output can be traced back to it.
+pub struct GeneratedBlock {
+    pub label: String,
+    pub code: String,
+    pub singles: Vec<SingleInstruction>,
+}
+
+/// A value of the output that doesn't match the pattern.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Index of the block that should have stored the value.
+    pub block: usize,
+    pub position: u32,
+    pub expected: u32,
+    pub found: u32,
+}
+
+/// Generate the C code of each block of a pattern, in the order it is
+/// emitted by `simplec`. Out of place, runs of blocks repeating the same
+/// moves are rolled into loops of `unroll` blocks per iteration.
+pub fn generate_c_blocks(
+    mask: &ShiftMask,
+    target: Target,
+    in_place: bool,
+    tile: Option<TileConfig>,
+    unroll: u32,
+) -> Vec<GeneratedBlock> {
+    if target.is_vector_length_agnostic() {
+        return mask
+            .optimize_to_vla_blocks()
+            .iter()
+            .enumerate()
+            .map(|(i, blk)| GeneratedBlock {
+                label: format!("{:?}", blk),
+                code: blk.encode_to_c(i as u32, target),
+                singles: blk.into(),
+            })
+            .collect();
+    }
+
+    let optimize = |program: &mut Program| {
+        program.optimize();
+        if let VectorWidth::Fixed(bits) = target.description().vector_width {
+            program.legalize(bits / 32);
+        }
+    };
+
+    // Loops get programs of their own, as their ops are run once for
+    // every iteration.
+    let mut loops: Vec<(usize, BlockLoop)> = vec![];
+    let (mut program, labels, singles): (Program, Vec<String>, Vec<Vec<SingleInstruction>>) =
+        if in_place {
+            let steps = mask.optimize_to_in_place_blocks(255, target);
+            let singles = steps
+                .iter()
+                .map(|step| match step {
+                    // Saves only load, the matching restore does the stores.
+                    InPlaceStep::Save(_, _, _) => vec![],
+                    _ => step.block().into(),
+                })
+                .collect();
+            (
+                Program::from_in_place_steps(&steps),
+                steps.iter().map(|step| format!("{:?}", step)).collect(),
+                singles,
+            )
+        } else {
+            let mut blocks = mask.optimize_to_blocks(255);
+            if let Some(config) = tile {
+                blocks = tile_blocks(blocks, &config);
+            }
+            let blocks = fit_overlapping(&Vec::from(blocks), target);
+            let rolled = reroll_blocks(&blocks, unroll);
+
+            let mut program = Program::new(false);
+            for (i, blk) in rolled.iter().enumerate() {
+                match blk {
+                    RolledBlock::Block(blk) => program.lower_block(i, blk),
+                    RolledBlock::Loop(l) => loops.push((i, l.clone())),
+                }
+            }
+            (
+                program,
+                rolled.iter().map(|blk| format!("{:?}", blk)).collect(),
+                rolled.iter().map(Vec::from).collect(),
+            )
+        };
+
+    optimize(&mut program);
+    let mut pool = ConstantPool::default();
+    let mut code = encode_program_to_c(&program, labels.len(), target, &mut pool);
+    for (i, l) in loops.iter() {
+        let mut body = Program::from_blocks(&l.body);
+        optimize(&mut body);
+        code[*i] = encode_loop_to_c(
+            &body,
+            l.body.len(),
+            l.iterations,
+            l.stride,
+            target,
+            &mut pool,
+        );
+    }
+
+    let mut generated = vec![];
+    if !pool.lanes.is_empty() {
+        generated.push(GeneratedBlock {
+            label: "pool".to_string(),
+            code: pool.c_declaration(),
+            singles: vec![],
+        });
+    }
+    generated.extend(
+        code.into_iter()
+            .zip(labels)
+            .zip(singles)
+            .map(|((code, label), singles)| GeneratedBlock {
+                label,
+                code,
+                singles,
+            }),
+    );
+    generated
+}
+
+/// Wrap generated blocks into a program that permutes the values
+/// `0, 1, ..., len - 1` and prints the output, one value per line.
+pub fn emit_c_program(
+    blocks: &[GeneratedBlock],
+    len: usize,
+    target: Target,
+    in_place: bool,
+) -> String {
+    let prelude = match target.family() {
+        IsaFamily::X86_64 => {
+            "#include <immintrin.h>
+#include <stdio.h>
+#include <string.h>
+"
+        }
+        IsaFamily::Aarch64 if target.model == TargetModel::Armv9Sve => {
+            "#include <arm_sve.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+"
+        }
+        IsaFamily::Aarch64 => {
+            "#include <arm_neon.h>
+#include <stdio.h>
+#include <string.h>
+"
+        }
+        IsaFamily::Riscv64 => {
+            "#include <riscv_vector.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+"
+        }
+        IsaFamily::Wasm32 => {
+            "#include <wasm_simd128.h>
+#include <stdio.h>
+#include <string.h>
+"
+        }
+    };
+    let body: String = blocks.iter().map(|blk| blk.code.as_str()).collect();
+    let output = if in_place { "buffer_in" } else { "buffer_out" };
+
+    format!(
+        "{}
+static float buffer_in[{}], buffer_out[{}];
+
+void permute(float *in, float *out) {{
+{}}}
+
+int main(void) {{
+  for (int i = 0; i < {}; i++) {{
+    buffer_in[i] = (float) i;
+  }}
+  permute(buffer_in, {});
+  for (int i = 0; i < {}; i++) {{
+    printf(\"%d\\n\", (int) {}[i]);
+  }}
+  return 0;
+}}
+",
+        prelude, len, len, body, len, output, len, output
+    )
+}
+
+/// Compile a C program with the given compiler, run it, and parse the
+/// values it prints.
+pub fn compile_and_run(
+    source: &str,
+    dir: &Path,
+    cc: &str,
+    flags: &[&str],
+) -> Result<Vec<u32>, String> {
+    let source_path = dir.join("permute.c");
+    let binary_path = dir.join("permute");
+    fs::write(&source_path, source)
+        .map_err(|e| format!("unable to write {}: {}", source_path.display(), e))?;
+
+    let compiled = Command::new(cc)
+        .args(flags)
+        .arg("-o")
+        .arg(&binary_path)
+        .arg(&source_path)
+        .output()
+        .map_err(|e| format!("unable to run {}: {}", cc, e))?;
+    if !compiled.status.success() {
+        return Err(format!(
+            "{} failed to compile {}:\n{}",
+            cc,
+            source_path.display(),
+            String::from_utf8_lossy(&compiled.stderr)
+        ));
+    }
+
+    let ran = Command::new(&binary_path)
+        .output()
+        .map_err(|e| format!("unable to run {}: {}", binary_path.display(), e))?;
+    if !ran.status.success() {
+        return Err(format!(
+            "{} exited with {}",
+            binary_path.display(),
+            ran.status
+        ));
+    }
+
+    String::from_utf8_lossy(&ran.stdout)
+        .lines()
+        .map(|line| {
+            line.trim()
+                .parse()
+                .map_err(|_| format!("unexpected program output {:?}", line))
+        })
+        .collect()
+}
+
+/// Compare the output of a program on `0, 1, ..., len - 1` against the
+/// pattern, and attribute every wrong value to the block storing it.
+pub fn check_output(mask: &ShiftMask, blocks: &[GeneratedBlock], output: &[u32]) -> Vec<Mismatch> {
+    let input: Vec<u32> = (0..mask.len() as u32).collect();
+    let expected = mask.permute_array_by_mask(&input);
+
+    let mut mismatches = vec![];
+    for (i, blk) in blocks.iter().enumerate() {
+        for single in blk.singles.iter() {
+            let position = single.value;
+            let found = output.get(position as usize).copied().unwrap_or(u32::MAX);
+            if found != expected[position as usize] {
+                mismatches.push(Mismatch {
+                    block: i,
+                    position,
+                    expected: expected[position as usize],
+                    found,
+                });
+            }
+        }
+    }
+
+    mismatches
+}
+
+/// A scratch directory for the generated program, which is removed
+/// again unless it should be kept around for debugging.
+pub fn scratch_dir() -> Result<PathBuf, String> {
+    let dir = std::env::temp_dir().join(format!("bruteforcer-verify-{}", std::process::id()));
+    fs::create_dir_all(&dir).map_err(|e| format!("unable to create {}: {}", dir.display(), e))?;
+
+    Ok(dir)
+}
+
+#[test]
+fn test_check_output() {
+    let mask = ShiftMask::from(vec![1, 2, 3, 0, 5, 4]);
+    let blocks = generate_c_blocks(&mask, Target::host(), false, None, 1);
+    let source = emit_c_program(&blocks, mask.len(), Target::host(), false);
+    assert!(source.contains("static float buffer_in[6], buffer_out[6];"));
+
+    assert!(check_output(&mask, &blocks, &[3, 0, 1, 2, 5, 4]).is_empty());
+
+    // Swapping two lanes of the rotated window blames it twice.
+    let mismatches = check_output(&mask, &blocks, &[3, 1, 0, 2, 5, 4]);
+    assert_eq!(
+        mismatches,
+        vec![
+            Mismatch {
+                block: 0,
+                position: 1,
+                expected: 0,
+                found: 1
+            },
+            Mismatch {
+                block: 0,
+                position: 2,
+                expected: 1,
+                found: 0
+            },
+        ]
+    );
+}
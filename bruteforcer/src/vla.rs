@@ -0,0 +1,508 @@
+use std::fmt::Debug;
+
+use crate::{
+    abstract_instructions::single::SingleInstruction, args::TargetModel, encodings::Target,
+    optimize::ShiftMask,
+};
+
+/// Granule sizes that vector length agnostic blocks are built out of.
+/// Every vector length is a multiple of 4 lanes, so a granule of 4 never
+/// straddles registers. A granule of 8 does at 128 bit vectors, where
+/// SVE reads from a pair of them with `svtbl2` and RVV code uses register
+/// groups of two.
+pub const VLA_GRANULES: [u32; 2] = [4, 8];
+
+/// Vector lengths in bits an SVE implementation may have. Armv9 only
+/// allows powers of two, which keeps granules aligned to vectors.
+pub const SVE_VECTOR_BITS: [u32; 5] = [128, 256, 512, 1024, 2048];
+
+/// VLEN in bits of RVV implementations, which is a power of two of at
+/// least 128 for the V extension.
+pub const RVV_VLEN_BITS: [u32; 10] = [128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536];
+
+/// A block of vector length agnostic code. Runs of windows that
+/// self permute and all move by the same distance are permuted one
+/// vector at a time, whatever the vector length turns out to be.
+#[derive(Clone, PartialEq, Eq)]
+pub enum VlaBlock {
+    Single(SingleInstruction),
+    Vector {
+        first_in: u32,
+        first_out: u32,
+        /// Number of values within the block, a multiple of `granule`.
+        len: u32,
+        granule: u32,
+        /// For each value of the output, the position within its
+        /// granule of the input it is read from.
+        table: Vec<u32>,
+    },
+}
+
+impl Debug for VlaBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            VlaBlock::Single(s) => write!(f, "{:?}", s),
+            VlaBlock::Vector {
+                first_in,
+                first_out,
+                len,
+                granule,
+                ..
+            } => write!(
+                f,
+                "vla[{}..{} -> {}..{} by {}]",
+                first_in,
+                first_in + len,
+                first_out,
+                first_out + len,
+                granule
+            ),
+        }
+    }
+}
+
+impl ShiftMask {
+    /// Plan vector length agnostic blocks. At each position the longest
+    /// run of self permuting granules moving by the same distance is
+    /// taken, preferring granules of 4 on a tie as they fit within a
+    /// single register at any vector length.
+    pub fn optimize_to_vla_blocks(&self) -> Vec<VlaBlock> {
+        let values = self.values();
+        let mut blocks = vec![];
+        let mut i = 0;
+
+        while i < values.len() {
+            let run = VLA_GRANULES.iter().filter_map(|granule| {
+                let granule = *granule as usize;
+                if !self.window_self_permutes(i, granule) {
+                    return None;
+                }
+
+                let first_out = *values[i..i + granule].iter().min().unwrap();
+                let mut len = granule;
+                while self.window_self_permutes(i + len, granule)
+                    && *values[i + len..i + len + granule].iter().min().unwrap()
+                        == first_out + len as u32
+                {
+                    len += granule;
+                }
+
+                Some((granule, first_out, len))
+            });
+            let run = run.rev().max_by_key(|(_, _, len)| *len);
+
+            let Some((granule, first_out, len)) = run else {
+                blocks.push(VlaBlock::Single(SingleInstruction::new(
+                    i as u32, values[i],
+                )));
+                i += 1;
+                continue;
+            };
+
+            let mut table = vec![0; len];
+            for offset in 0..len {
+                let out = (values[i + offset] - first_out) as usize;
+                table[out] = (offset % granule) as u32;
+            }
+
+            blocks.push(VlaBlock::Vector {
+                first_in: i as u32,
+                first_out,
+                len: len as u32,
+                granule: granule as u32,
+                table,
+            });
+            i += len;
+        }
+
+        blocks
+    }
+}
+
+impl VlaBlock {
+    pub fn encode_to_c(&self, index: u32, target: Target) -> String {
+        if let VlaBlock::Single(s) = self {
+            return format!("  out[{}] = in[{}];\n", s.value, s.index);
+        }
+
+        match target.model {
+            TargetModel::Armv9Sve => self.encode_sve_to_c(index),
+            TargetModel::Rv64gcv => self.encode_rvv_to_c(index),
+            _ => unreachable!("{} isn't vector length agnostic", target.model),
+        }
+    }
+
+    fn encode_table_to_c(&self, index: u32) -> String {
+        let VlaBlock::Vector { len, table, .. } = self else {
+            return String::new();
+        };
+
+        let table: Vec<String> = table.iter().map(|t| t.to_string()).collect();
+        format!(
+            "  static const uint32_t table{}[{}] = {{{}}};\n",
+            index,
+            len,
+            table.join(", ")
+        )
+    }
+
+    fn encode_sve_to_c(&self, index: u32) -> String {
+        let VlaBlock::Vector {
+            first_in,
+            first_out,
+            len,
+            granule,
+            ..
+        } = self
+        else {
+            unreachable!()
+        };
+
+        let mut code = self.encode_table_to_c(index);
+
+        // The index of each lane is the start of its granule within the
+        // vector plus its entry of the table.
+        let vector = format!(
+            "  for (uint64_t i = 0; i < {len}; i += svcntw()) {{
+    svbool_t pg = svwhilelt_b32_u64(i, {len});
+    svfloat32_t valin = svld1_f32(pg, &in[{first_in} + i]);
+    svuint32_t base = svand_n_u32_x(pg, svindex_u32(0, 1), ~{mask}u);
+    svuint32_t idx = svadd_u32_x(pg, base, svld1_u32(pg, &table{index}[i]));
+    svst1_f32(pg, &out[{first_out} + i], svtbl_f32(valin, idx));
+  }}
+",
+            mask = granule - 1,
+        );
+
+        if *granule <= 4 {
+            code += &vector;
+            return code;
+        }
+
+        // Vectors of 4 lanes are the only ones a granule of 8 doesn't fit
+        // in, those permute a pair of vectors at a time.
+        code += &format!(
+            "  if (svcntw() < {granule}) {{
+    svbool_t pg = svptrue_b32();
+    for (uint64_t i = 0; i < {len}; i += 8) {{
+      svfloat32x2_t valin = svcreate2_f32(svld1_f32(pg, &in[{first_in} + i]),
+                                          svld1_f32(pg, &in[{first_in} + i + 4]));
+      svst1_f32(pg, &out[{first_out} + i], svtbl2_f32(valin, svld1_u32(pg, &table{index}[i])));
+      svst1_f32(pg, &out[{first_out} + i + 4], svtbl2_f32(valin, svld1_u32(pg, &table{index}[i + 4])));
+    }}
+  }} else {{
+"
+        );
+        code += &vector.replace("\n  ", "\n    ").replacen("  ", "    ", 1);
+        code += "  }\n";
+        code
+    }
+
+    /// RVV code uses a register group large enough for a granule at the
+    /// smallest VLEN, so `vrgather` never has to reach across groups.
+    fn encode_rvv_to_c(&self, index: u32) -> String {
+        let VlaBlock::Vector {
+            first_in,
+            first_out,
+            len,
+            granule,
+            ..
+        } = self
+        else {
+            unreachable!()
+        };
+
+        let lmul = granule / 4;
+        self.encode_table_to_c(index)
+            + &format!(
+                "  size_t vlmax{index} = __riscv_vsetvlmax_e32m{lmul}();
+  for (size_t i = 0, vl; i < {len}; i += vl) {{
+    // Never ask for more than VLMAX, which could split a granule.
+    vl = __riscv_vsetvl_e32m{lmul}({len} - i < vlmax{index} ? {len} - i : vlmax{index});
+    vfloat32m{lmul}_t valin = __riscv_vle32_v_f32m{lmul}(&in[{first_in} + i], vl);
+    vuint32m{lmul}_t base = __riscv_vand_vx_u32m{lmul}(__riscv_vid_v_u32m{lmul}(vl), ~{mask}u, vl);
+    vuint32m{lmul}_t idx = __riscv_vadd_vv_u32m{lmul}(base, __riscv_vle32_v_u32m{lmul}(&table{index}[i], vl), vl);
+    __riscv_vse32_v_f32m{lmul}(&out[{first_out} + i], __riscv_vrgather_vv_f32m{lmul}(valin, idx, vl), vl);
+  }}
+",
+                mask = granule - 1,
+            )
+    }
+
+    /// Run the code of this block lane by lane, as it would with vectors
+    /// of `vector_bits` on the target, writing out the values it stores.
+    pub fn simulate(&self, target: Target, vector_bits: u32, input: &[u32], output: &mut [u32]) {
+        if let VlaBlock::Single(s) = self {
+            output[s.value as usize] = input[s.index as usize];
+            return;
+        }
+
+        match target.model {
+            TargetModel::Armv9Sve => self.simulate_sve(vector_bits, input, output),
+            TargetModel::Rv64gcv => self.simulate_rvv(vector_bits, input, output),
+            _ => unreachable!("{} isn't vector length agnostic", target.model),
+        }
+    }
+
+    fn simulate_sve(&self, vector_bits: u32, input: &[u32], output: &mut [u32]) {
+        let VlaBlock::Vector {
+            first_in,
+            first_out,
+            len,
+            granule,
+            ref table,
+        } = *self
+        else {
+            unreachable!()
+        };
+        let lanes = vector_bits / 32;
+
+        if lanes < granule {
+            let pg = svptrue(lanes);
+            for i in (0..len).step_by(8) {
+                let valin = [
+                    svld1(&pg, input, first_in + i),
+                    svld1(&pg, input, first_in + i + 4),
+                ];
+                for half in [0, 4] {
+                    let idx = svld1(&pg, table, i + half);
+                    svst1(&pg, output, first_out + i + half, &svtbl2(&valin, &idx));
+                }
+            }
+            return;
+        }
+
+        for i in (0..len).step_by(lanes as usize) {
+            let pg = svwhilelt(lanes, i, len);
+            let valin = svld1(&pg, input, first_in + i);
+            let base: Vec<u32> = (0..lanes).map(|lane| lane & !(granule - 1)).collect();
+            let offsets = svld1(&pg, table, i);
+            let idx: Vec<u32> = base
+                .iter()
+                .zip(offsets.iter())
+                .map(|(b, o)| b + o)
+                .collect();
+            svst1(&pg, output, first_out + i, &svtbl(&valin, &idx));
+        }
+    }
+
+    fn simulate_rvv(&self, vlen: u32, input: &[u32], output: &mut [u32]) {
+        let VlaBlock::Vector {
+            first_in,
+            first_out,
+            len,
+            granule,
+            ref table,
+        } = *self
+        else {
+            unreachable!()
+        };
+        let vlmax = (granule / 4) * vlen / 32;
+
+        let mut i = 0;
+        while i < len {
+            let vl = vsetvl((len - i).min(vlmax), vlmax);
+            let valin = vle32(input, first_in + i, vl);
+            let base: Vec<u32> = vid(vl).iter().map(|lane| lane & !(granule - 1)).collect();
+            let offsets = vle32(table, i, vl);
+            let idx: Vec<u32> = base
+                .iter()
+                .zip(offsets.iter())
+                .map(|(b, o)| b + o)
+                .collect();
+            vse32(output, first_out + i, &vrgather(&valin, &idx, vlmax));
+            i += vl;
+        }
+    }
+}
+
+// The intrinsics used above, over vectors of lanes. Inactive lanes load
+// as zero and are never stored, like the zeroing forms of SVE.
+
+fn svptrue(lanes: u32) -> Vec<bool> {
+    vec![true; lanes as usize]
+}
+
+fn svwhilelt(lanes: u32, start: u32, end: u32) -> Vec<bool> {
+    (0..lanes).map(|lane| start + lane < end).collect()
+}
+
+fn svld1(pg: &[bool], src: &[u32], start: u32) -> Vec<u32> {
+    pg.iter()
+        .enumerate()
+        .map(|(lane, active)| match active {
+            true => src[start as usize + lane],
+            false => 0,
+        })
+        .collect()
+}
+
+fn svst1(pg: &[bool], dst: &mut [u32], start: u32, val: &[u32]) {
+    for (lane, active) in pg.iter().enumerate() {
+        if *active {
+            dst[start as usize + lane] = val[lane];
+        }
+    }
+}
+
+/// Out of range indices select zero.
+fn svtbl(val: &[u32], idx: &[u32]) -> Vec<u32> {
+    idx.iter()
+        .map(|i| val.get(*i as usize).copied().unwrap_or(0))
+        .collect()
+}
+
+/// Looks up within the concatenation of both vectors.
+fn svtbl2(val: &[Vec<u32>; 2], idx: &[u32]) -> Vec<u32> {
+    svtbl(&val.concat(), idx)
+}
+
+/// `vsetvl` given an AVL of at most VLMAX, which sets vl to the AVL.
+fn vsetvl(avl: u32, vlmax: u32) -> u32 {
+    assert!(avl <= vlmax);
+    avl
+}
+
+fn vid(vl: u32) -> Vec<u32> {
+    (0..vl).collect()
+}
+
+fn vle32(src: &[u32], start: u32, vl: u32) -> Vec<u32> {
+    src[start as usize..(start + vl) as usize].to_vec()
+}
+
+fn vse32(dst: &mut [u32], start: u32, val: &[u32]) {
+    dst[start as usize..start as usize + val.len()].copy_from_slice(val);
+}
+
+/// Indices of VLMAX and up select zero.
+fn vrgather(val: &[u32], idx: &[u32], vlmax: u32) -> Vec<u32> {
+    idx.iter()
+        .map(|i| match *i < vlmax {
+            true => val.get(*i as usize).copied().unwrap_or(0),
+            false => 0,
+        })
+        .collect()
+}
+
+impl From<&VlaBlock> for Vec<SingleInstruction> {
+    fn from(val: &VlaBlock) -> Self {
+        match val {
+            VlaBlock::Single(s) => vec![*s],
+            VlaBlock::Vector {
+                first_in,
+                first_out,
+                granule,
+                table,
+                ..
+            } => table
+                .iter()
+                .enumerate()
+                .map(|(out, offset)| {
+                    let out = out as u32;
+                    SingleInstruction::new(first_in + out - out % granule + offset, first_out + out)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Simulate the blocks of a pattern on `0, 1, ..., len - 1` at every
+/// vector length of the target, returning the output of each length.
+pub fn simulate_vla(target: Target, len: usize, blocks: &[VlaBlock]) -> Vec<(u32, Vec<u32>)> {
+    let input: Vec<u32> = (0..len as u32).collect();
+    let vector_bits: &[u32] = match target.model {
+        TargetModel::Rv64gcv => &RVV_VLEN_BITS,
+        _ => &SVE_VECTOR_BITS,
+    };
+
+    vector_bits
+        .iter()
+        .map(|bits| {
+            let mut output = vec![u32::MAX; len];
+            for blk in blocks.iter() {
+                blk.simulate(target, *bits, &input, &mut output);
+            }
+            (*bits, output)
+        })
+        .collect()
+}
+
+#[test]
+fn test_vla_blocks() {
+    use crate::features::FeatureSet;
+
+    let masks = [
+        ShiftMask::new_random(250),
+        ShiftMask::new_block_reverse(96, 4).unwrap(),
+        ShiftMask::new_block_reverse(96, 8).unwrap(),
+        ShiftMask::new_rotate(64, 3).unwrap(),
+        ShiftMask::from((0..40).map(|i| i ^ 5).collect::<Vec<u32>>()),
+        ShiftMask::from(vec![
+            5, 6, 7, 0, 1, 2, 3, 4, 10, 11, 8, 9, 12, 13, 14, 15, 16,
+        ]),
+    ];
+    let targets = [
+        Target::new(TargetModel::Armv9Sve, FeatureSet::empty()),
+        Target::new(TargetModel::Rv64gcv, FeatureSet::empty()),
+    ];
+
+    let is_correct = |target: Target, mask: &ShiftMask, blocks: &[VlaBlock]| {
+        let expected = mask.permute_array_by_mask(&(0..mask.len() as u32).collect::<Vec<u32>>());
+        simulate_vla(target, mask.len(), blocks)
+            .iter()
+            .all(|(_, output)| *output == expected)
+    };
+
+    for mask in masks.iter() {
+        let blocks = mask.optimize_to_vla_blocks();
+        for target in targets.iter() {
+            assert!(
+                is_correct(*target, mask, &blocks),
+                "{} pattern {}",
+                target.model,
+                mask
+            );
+        }
+
+        let moves: usize = blocks
+            .iter()
+            .map(|blk| Vec::<SingleInstruction>::from(blk).len())
+            .sum();
+        assert_eq!(moves, mask.len());
+    }
+
+    // Reversing every 8 values is a single run of granules of 8.
+    let mask = ShiftMask::from((0..96).map(|i| i ^ 7).collect::<Vec<u32>>());
+    let blocks = mask.optimize_to_vla_blocks();
+    assert_eq!(blocks.len(), 1);
+    for target in targets.iter() {
+        assert!(is_correct(*target, &mask, &blocks));
+    }
+}
+
+#[test]
+fn test_rvv_vlens() {
+    use crate::features::FeatureSet;
+
+    let target = Target::new(TargetModel::Rv64gcv, FeatureSet::empty());
+
+    // Reversing every 8 values takes a group of two registers at any
+    // VLEN, and ends on a partial vector from a VLEN of 256 on.
+    let mask = ShiftMask::from((0..40).map(|i| i ^ 7).collect::<Vec<u32>>());
+    let blocks = mask.optimize_to_vla_blocks();
+    assert_eq!(blocks.len(), 1);
+    assert!(blocks[0]
+        .encode_to_c(0, target)
+        .contains("__riscv_vsetvlmax_e32m2()"));
+
+    let expected = mask.permute_array_by_mask(&(0..40).collect::<Vec<u32>>());
+    let outputs = simulate_vla(target, mask.len(), &blocks);
+    assert_eq!(
+        outputs.iter().map(|(bits, _)| *bits).collect::<Vec<u32>>(),
+        RVV_VLEN_BITS
+    );
+    for (bits, output) in outputs.iter() {
+        assert_eq!(*output, expected, "VLEN {}", bits);
+    }
+}
@@ -5,7 +5,6 @@ import sys
 import subprocess
 import os
 import random
-import csv
 import json
 from patterns import patterns
 from templates.base1 import template as tmplbase1
@@ -36,6 +35,7 @@ def new_parser():
 	rgen.add_argument("--bin-output", help="Provide the output location of the generated program executable.", type=str, default="./prog", dest="binout")
 	rgen.add_argument("--output", help="Provide the output location of the generated program source.", type=str, default="", dest="output")
 	rgen.add_argument("--arg_count", help="Provide the number of arguments to build with your random permutation.", type=int, default=1000, dest="arg_count")
+	rgen.add_argument("--seed", help="Provide a seed for the random permutation so it can be regenerated.", type=int, default=None, dest="seed")
 	rgen.set_defaults(func=generate_rand)
 	r = sub.add_parser("run")
 	r.add_argument("--values", help="Provide a comma-separated list of integers to pass to the generated program.", type=str, dest="values")
@@ -46,7 +46,7 @@ def new_parser():
 	rrand.add_argument("--iter", help="Provide the number of iterations you want to execute.", type=int, default=25, dest="iterations")
 	rrand.set_defaults(func=run_rand)
 	rrprt = sub.add_parser("runbench")
-	rrprt.add_argument("--input", help="Provide a path to the generated program to execute.", type=str, default="benchmarks", dest="input")
+	rrprt.add_argument("--iter", help="Provide the number of timed iterations of each program.", type=int, default=500, dest="iterations")
 	rrprt.add_argument("--output", help="Specify the output file location for your benchmark data frame results.", type=str, default="results.csv", dest="output") 
 	rtest = sub.add_parser("runtests")
 
@@ -58,7 +58,7 @@ def runner(args: ArgumentParser):
 		print("generating new output program")
 		return generate(args.pattern, args.template, args.binout, args.output, False, False)
 	elif sys.argv[1] == "genrand":
-		return generate_rand(args.template, args.binout, args.output, args.arg_count, False, False)
+		return generate_rand(args.template, args.binout, args.output, args.arg_count, False, False, args.seed)
 	elif sys.argv[1] == "run":
 		print("running output program")
 		return run(args.input, [int(arg) for arg in args.values.split(",")])
@@ -68,7 +68,7 @@ def runner(args: ArgumentParser):
 		print(outputs)
 	elif sys.argv[1] == "runbench":
 		print("running reporting")
-		bench(args.input, args.output)
+		bench(args.iterations, args.output)
 	elif sys.argv[1] == "runtests":
 		print("running tests")
 		return run_tests([])
@@ -83,12 +83,6 @@ templates = {
 	# "asm1": tmplasm,
 }
 
-benchplates = {
-	"base2": tmplbase2,
-	"simd2": tmplsimd2,
-	"cuda2": tmplcuda2,
-}
-
 def generate(pattern: str, template: str, binout: str, output: str, asm: bool, omp: bool):
 	values: [(int, int)] = [(i, int(arg)) for i, arg in enumerate(pattern.split(","))]
 	arg_count = len(values)
@@ -132,8 +126,11 @@ def rand_pattern(arg_count: int):
 	random.shuffle(nums)
 	return nums
 
-def generate_rand(template: str, binout: str, output: str, arg_count: int, asm: bool, omp: bool):
-	numstr = subprocess.run(["cargo", "run", "--manifest-path=bruteforcer/Cargo.toml", "--", "-l", str(arg_count), "randpat"], capture_output=True).stdout.decode()
+def generate_rand(template: str, binout: str, output: str, arg_count: int, asm: bool, omp: bool, seed: int = None):
+	cmd = ["cargo", "run", "--manifest-path=bruteforcer/Cargo.toml", "--", "-l", str(arg_count), "randpat"]
+	if seed is not None:
+		cmd += ["--seed", str(seed)]
+	numstr = subprocess.run(cmd, capture_output=True).stdout.decode()
 	numstr = numstr.strip("\n")
 	print(f"pattern: {numstr}")
 	generate(numstr, template, binout, output, asm, omp)
@@ -167,24 +164,12 @@ def run_rand(input_prog: str, iterations: int, arg_count: int):
 def run_rand_once(input_prog: str, arg_count: int, args: [int]):
 	return run(input_prog, args)
 
-def bench(outDir: str, outFile: str):
-	file = open(outFile, "w")
-	results = csv.writer(file, delimiter=",")
-	results.writerow(["compute_time", "template_name", "arg_count", "pattern_number", "iteration_number"])
-
-	for name, tmpl in benchplates.items():
-		for arg_count, patternset in patterns.items():
-			for i, pattern in enumerate(patternset):
-				pname = name + "_" + str(arg_count) + "_" + str(i) + "_" + tmpl["program_output"]
-				output = f"{outDir}/{pname}"
-				outbin = f"{outDir}/prog"
-				print(f"running template {name} arg_count {arg_count} pattern {i} at {output}...")
-				generate(pattern, name, outbin, output, False, False)
-				values = run_rand(outbin, 500, arg_count)
-				for ith, value in enumerate(values):
-					results.writerow([value, name, arg_count, i, ith])
-	
-	file.close()
+def bench(iterations: int, outFile: str):
+	# The bruteforcer JITs every pattern of the corpus itself, and writes
+	# the same columns as before (with a few extra ones).
+	proc = subprocess.run(["cargo", "run", "--release", "--manifest-path=bruteforcer/Cargo.toml", "--", "bench", "--corpus", "patterns.py", "--iterations", str(iterations), "--output", outFile])
+	if proc.returncode != 0:
+		exit(1)
 
 def run_tests(tmplversions: [str]):
 	for name, tmpl in templates.items():